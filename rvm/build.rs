@@ -0,0 +1,26 @@
+use std::env;
+use std::fs::{read_to_string, write};
+use std::path::Path;
+
+use rvm_compiler::parsing::{lowercase, parse, print_errors};
+use rvm_compiler::serialize;
+
+/// Assembles the bundled operating system, to be included in the crate.
+fn main() {
+  println!("cargo:rerun-if-changed=os/os.asm");
+
+  let contents = lowercase(&read_to_string("os/os.asm").unwrap());
+
+  let program = match parse(&contents) {
+    Ok(program) => program,
+    Err(errs) => {
+      print_errors(&contents, errs);
+
+      panic!("Failed to assemble the operating system");
+    }
+  };
+
+  let out_file = Path::new(&env::var("OUT_DIR").unwrap()).join("os.obj");
+
+  write(out_file, serialize(&program)).unwrap();
+}
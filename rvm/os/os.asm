@@ -0,0 +1,588 @@
+; the operating system loaded into system space of every machine, unless it
+; runs with native traps. the trap routines print the same as the native ones
+; in `trap.rs`, other than on bad input to in_u16, run in supervisor mode and
+; return with rti, every register preserved.
+
+.orig x0000
+
+; trap vector table, x0000 to x00ff
+.fill bad_trap   ; x00
+.fill bad_trap   ; x01
+.fill bad_trap   ; x02
+.fill bad_trap   ; x03
+.fill bad_trap   ; x04
+.fill bad_trap   ; x05
+.fill bad_trap   ; x06
+.fill bad_trap   ; x07
+.fill bad_trap   ; x08
+.fill bad_trap   ; x09
+.fill bad_trap   ; x0a
+.fill bad_trap   ; x0b
+.fill bad_trap   ; x0c
+.fill bad_trap   ; x0d
+.fill bad_trap   ; x0e
+.fill bad_trap   ; x0f
+.fill bad_trap   ; x10
+.fill bad_trap   ; x11
+.fill bad_trap   ; x12
+.fill bad_trap   ; x13
+.fill bad_trap   ; x14
+.fill bad_trap   ; x15
+.fill bad_trap   ; x16
+.fill bad_trap   ; x17
+.fill bad_trap   ; x18
+.fill bad_trap   ; x19
+.fill bad_trap   ; x1a
+.fill bad_trap   ; x1b
+.fill bad_trap   ; x1c
+.fill bad_trap   ; x1d
+.fill bad_trap   ; x1e
+.fill bad_trap   ; x1f
+.fill getc       ; x20
+.fill out        ; x21
+.fill puts       ; x22
+.fill in         ; x23
+.fill putsp      ; x24
+.fill halt       ; x25
+.fill in_u16     ; x26
+.fill out_u16    ; x27
+.fill bad_trap   ; x28
+.fill bad_trap   ; x29
+.fill bad_trap   ; x2a
+.fill bad_trap   ; x2b
+.fill bad_trap   ; x2c
+.fill bad_trap   ; x2d
+.fill bad_trap   ; x2e
+.fill bad_trap   ; x2f
+.fill bad_trap   ; x30
+.fill bad_trap   ; x31
+.fill bad_trap   ; x32
+.fill bad_trap   ; x33
+.fill bad_trap   ; x34
+.fill bad_trap   ; x35
+.fill bad_trap   ; x36
+.fill bad_trap   ; x37
+.fill bad_trap   ; x38
+.fill bad_trap   ; x39
+.fill bad_trap   ; x3a
+.fill bad_trap   ; x3b
+.fill bad_trap   ; x3c
+.fill bad_trap   ; x3d
+.fill bad_trap   ; x3e
+.fill bad_trap   ; x3f
+.fill bad_trap   ; x40
+.fill bad_trap   ; x41
+.fill bad_trap   ; x42
+.fill bad_trap   ; x43
+.fill bad_trap   ; x44
+.fill bad_trap   ; x45
+.fill bad_trap   ; x46
+.fill bad_trap   ; x47
+.fill bad_trap   ; x48
+.fill bad_trap   ; x49
+.fill bad_trap   ; x4a
+.fill bad_trap   ; x4b
+.fill bad_trap   ; x4c
+.fill bad_trap   ; x4d
+.fill bad_trap   ; x4e
+.fill bad_trap   ; x4f
+.fill bad_trap   ; x50
+.fill bad_trap   ; x51
+.fill bad_trap   ; x52
+.fill bad_trap   ; x53
+.fill bad_trap   ; x54
+.fill bad_trap   ; x55
+.fill bad_trap   ; x56
+.fill bad_trap   ; x57
+.fill bad_trap   ; x58
+.fill bad_trap   ; x59
+.fill bad_trap   ; x5a
+.fill bad_trap   ; x5b
+.fill bad_trap   ; x5c
+.fill bad_trap   ; x5d
+.fill bad_trap   ; x5e
+.fill bad_trap   ; x5f
+.fill bad_trap   ; x60
+.fill bad_trap   ; x61
+.fill bad_trap   ; x62
+.fill bad_trap   ; x63
+.fill bad_trap   ; x64
+.fill bad_trap   ; x65
+.fill bad_trap   ; x66
+.fill bad_trap   ; x67
+.fill bad_trap   ; x68
+.fill bad_trap   ; x69
+.fill bad_trap   ; x6a
+.fill bad_trap   ; x6b
+.fill bad_trap   ; x6c
+.fill bad_trap   ; x6d
+.fill bad_trap   ; x6e
+.fill bad_trap   ; x6f
+.fill bad_trap   ; x70
+.fill bad_trap   ; x71
+.fill bad_trap   ; x72
+.fill bad_trap   ; x73
+.fill bad_trap   ; x74
+.fill bad_trap   ; x75
+.fill bad_trap   ; x76
+.fill bad_trap   ; x77
+.fill bad_trap   ; x78
+.fill bad_trap   ; x79
+.fill bad_trap   ; x7a
+.fill bad_trap   ; x7b
+.fill bad_trap   ; x7c
+.fill bad_trap   ; x7d
+.fill bad_trap   ; x7e
+.fill bad_trap   ; x7f
+.fill bad_trap   ; x80
+.fill bad_trap   ; x81
+.fill bad_trap   ; x82
+.fill bad_trap   ; x83
+.fill bad_trap   ; x84
+.fill bad_trap   ; x85
+.fill bad_trap   ; x86
+.fill bad_trap   ; x87
+.fill bad_trap   ; x88
+.fill bad_trap   ; x89
+.fill bad_trap   ; x8a
+.fill bad_trap   ; x8b
+.fill bad_trap   ; x8c
+.fill bad_trap   ; x8d
+.fill bad_trap   ; x8e
+.fill bad_trap   ; x8f
+.fill bad_trap   ; x90
+.fill bad_trap   ; x91
+.fill bad_trap   ; x92
+.fill bad_trap   ; x93
+.fill bad_trap   ; x94
+.fill bad_trap   ; x95
+.fill bad_trap   ; x96
+.fill bad_trap   ; x97
+.fill bad_trap   ; x98
+.fill bad_trap   ; x99
+.fill bad_trap   ; x9a
+.fill bad_trap   ; x9b
+.fill bad_trap   ; x9c
+.fill bad_trap   ; x9d
+.fill bad_trap   ; x9e
+.fill bad_trap   ; x9f
+.fill bad_trap   ; xa0
+.fill bad_trap   ; xa1
+.fill bad_trap   ; xa2
+.fill bad_trap   ; xa3
+.fill bad_trap   ; xa4
+.fill bad_trap   ; xa5
+.fill bad_trap   ; xa6
+.fill bad_trap   ; xa7
+.fill bad_trap   ; xa8
+.fill bad_trap   ; xa9
+.fill bad_trap   ; xaa
+.fill bad_trap   ; xab
+.fill bad_trap   ; xac
+.fill bad_trap   ; xad
+.fill bad_trap   ; xae
+.fill bad_trap   ; xaf
+.fill bad_trap   ; xb0
+.fill bad_trap   ; xb1
+.fill bad_trap   ; xb2
+.fill bad_trap   ; xb3
+.fill bad_trap   ; xb4
+.fill bad_trap   ; xb5
+.fill bad_trap   ; xb6
+.fill bad_trap   ; xb7
+.fill bad_trap   ; xb8
+.fill bad_trap   ; xb9
+.fill bad_trap   ; xba
+.fill bad_trap   ; xbb
+.fill bad_trap   ; xbc
+.fill bad_trap   ; xbd
+.fill bad_trap   ; xbe
+.fill bad_trap   ; xbf
+.fill bad_trap   ; xc0
+.fill bad_trap   ; xc1
+.fill bad_trap   ; xc2
+.fill bad_trap   ; xc3
+.fill bad_trap   ; xc4
+.fill bad_trap   ; xc5
+.fill bad_trap   ; xc6
+.fill bad_trap   ; xc7
+.fill bad_trap   ; xc8
+.fill bad_trap   ; xc9
+.fill bad_trap   ; xca
+.fill bad_trap   ; xcb
+.fill bad_trap   ; xcc
+.fill bad_trap   ; xcd
+.fill bad_trap   ; xce
+.fill bad_trap   ; xcf
+.fill bad_trap   ; xd0
+.fill bad_trap   ; xd1
+.fill bad_trap   ; xd2
+.fill bad_trap   ; xd3
+.fill bad_trap   ; xd4
+.fill bad_trap   ; xd5
+.fill bad_trap   ; xd6
+.fill bad_trap   ; xd7
+.fill bad_trap   ; xd8
+.fill bad_trap   ; xd9
+.fill bad_trap   ; xda
+.fill bad_trap   ; xdb
+.fill bad_trap   ; xdc
+.fill bad_trap   ; xdd
+.fill bad_trap   ; xde
+.fill bad_trap   ; xdf
+.fill bad_trap   ; xe0
+.fill bad_trap   ; xe1
+.fill bad_trap   ; xe2
+.fill bad_trap   ; xe3
+.fill bad_trap   ; xe4
+.fill bad_trap   ; xe5
+.fill bad_trap   ; xe6
+.fill bad_trap   ; xe7
+.fill bad_trap   ; xe8
+.fill bad_trap   ; xe9
+.fill bad_trap   ; xea
+.fill bad_trap   ; xeb
+.fill bad_trap   ; xec
+.fill bad_trap   ; xed
+.fill bad_trap   ; xee
+.fill bad_trap   ; xef
+.fill bad_trap   ; xf0
+.fill bad_trap   ; xf1
+.fill bad_trap   ; xf2
+.fill bad_trap   ; xf3
+.fill bad_trap   ; xf4
+.fill bad_trap   ; xf5
+.fill bad_trap   ; xf6
+.fill bad_trap   ; xf7
+.fill bad_trap   ; xf8
+.fill bad_trap   ; xf9
+.fill bad_trap   ; xfa
+.fill bad_trap   ; xfb
+.fill bad_trap   ; xfc
+.fill bad_trap   ; xfd
+.fill bad_trap   ; xfe
+.fill bad_trap   ; xff
+
+; interrupt vector table, x0100 to x01ff, left empty for programs to fill
+.blkw #256
+
+; x0200, data shared by the routines
+
+kbsr_addr: .fill xfe00
+kbdr_addr: .fill xfe02
+dsr_addr: .fill xfe04
+ddr_addr: .fill xfe06
+mcr_addr: .fill xfffe
+
+clock_mask: .fill x7fff
+low_byte: .fill x00ff
+low_high_bit: .fill x0100
+neg_ascii_zero: .fill #-48
+ascii_zero: .fill #48
+; the smallest number that can't take another digit, negated
+neg_too_big: .fill #-6554
+
+input_prompt: .stringz "input: "
+output_prompt: .stringz "output: "
+bad_trap_message: .stringz "bad trap\n"
+bad_input_message: .stringz "bad input\n"
+
+; powers of ten negated, ending with 0
+powers:
+  .fill #-10000
+  .fill #-1000
+  .fill #-100
+  .fill #-10
+  .fill #-1
+  .fill #0
+
+; prints the character in r0, once the display is ready
+print_char:
+  st r1, print_char_r1
+print_char_wait:
+  ldi r1, dsr_addr
+  brzp print_char_wait
+  sti r0, ddr_addr
+  ld r1, print_char_r1
+  jmp r7
+
+print_char_r1: .blkw #1
+
+; prints the string of one character per word at r0
+print_string:
+  st r7, print_string_r7
+  st r0, print_string_r0
+  st r1, print_string_r1
+  add r1, r0, #0
+print_string_loop:
+  ldr r0, r1, #0
+  brz print_string_done
+  jsr print_char
+  add r1, r1, #1
+  brnzp print_string_loop
+print_string_done:
+  ld r0, print_string_r0
+  ld r1, print_string_r1
+  ld r7, print_string_r7
+  jmp r7
+
+print_string_r7: .blkw #1
+print_string_r0: .blkw #1
+print_string_r1: .blkw #1
+
+; reads a character into r0, once one is typed
+read_char:
+  ldi r0, kbsr_addr
+  brzp read_char
+  ldi r0, kbdr_addr
+  jmp r7
+
+; x20
+getc:
+  st r7, getc_r7
+  lea r0, input_prompt
+  jsr print_string
+  jsr read_char
+  ld r7, getc_r7
+  rti
+
+getc_r7: .blkw #1
+
+; x21
+out:
+  st r7, out_r7
+  st r0, out_r0
+  lea r0, output_prompt
+  jsr print_string
+  ld r0, out_r0
+  jsr print_char
+  ld r7, out_r7
+  rti
+
+out_r7: .blkw #1
+out_r0: .blkw #1
+
+; x22
+puts:
+  st r7, puts_r7
+  jsr print_string
+  ld r7, puts_r7
+  rti
+
+puts_r7: .blkw #1
+
+; x23
+in:
+  st r7, in_r7
+  lea r0, input_prompt
+  jsr print_string
+  jsr read_char
+  jsr print_char
+  ld r7, in_r7
+  rti
+
+in_r7: .blkw #1
+
+; x24, prints two characters per word, low byte first, until a zero byte
+putsp:
+  st r7, putsp_r7
+  st r0, putsp_r0
+  st r1, putsp_r1
+  st r2, putsp_r2
+  st r3, putsp_r3
+  st r4, putsp_r4
+  st r5, putsp_r5
+  add r1, r0, #0
+putsp_loop:
+  ldr r2, r1, #0
+  ld r3, low_byte
+  and r0, r2, r3
+  brz putsp_done
+  jsr print_char
+
+  ; shift the high byte down one bit at a time
+  and r0, r0, #0
+  and r4, r4, #0
+  add r4, r4, #1
+  ld r5, low_high_bit
+putsp_shift:
+  and r3, r2, r5
+  brz putsp_next_bit
+  add r0, r0, r4
+putsp_next_bit:
+  add r4, r4, r4
+  add r5, r5, r5
+  brnp putsp_shift
+
+  add r0, r0, #0
+  brz putsp_done
+  jsr print_char
+  add r1, r1, #1
+  brnzp putsp_loop
+putsp_done:
+  ld r0, putsp_r0
+  ld r1, putsp_r1
+  ld r2, putsp_r2
+  ld r3, putsp_r3
+  ld r4, putsp_r4
+  ld r5, putsp_r5
+  ld r7, putsp_r7
+  rti
+
+putsp_r7: .blkw #1
+putsp_r0: .blkw #1
+putsp_r1: .blkw #1
+putsp_r2: .blkw #1
+putsp_r3: .blkw #1
+putsp_r4: .blkw #1
+putsp_r5: .blkw #1
+
+; x25, clears the clock enable bit of mcr with only r7 used, as the machine
+; stops right after, and returns if the clock is enabled again
+halt:
+  st r7, halt_r7
+  st r0, halt_r0
+  ldi r0, mcr_addr
+  ld r7, clock_mask
+  and r7, r0, r7
+  ld r0, halt_r0
+  sti r7, mcr_addr
+  ld r7, halt_r7
+  rti
+
+halt_r7: .blkw #1
+halt_r0: .blkw #1
+
+; x26, reads a decimal number ending with a newline into r0. anything but
+; digits, no digits at all or a number past 65535 is bad input, which prints a
+; message and halts where the native routine fails with an error
+in_u16:
+  st r7, in_u16_r7
+  st r1, in_u16_r1
+  st r2, in_u16_r2
+  st r3, in_u16_r3
+  lea r0, input_prompt
+  jsr print_string
+  and r1, r1, #0
+  and r3, r3, #0
+in_u16_loop:
+  jsr read_char
+  add r2, r0, #-10
+  brz in_u16_done
+  ld r2, neg_ascii_zero
+  add r0, r0, r2
+  brn in_u16_bad
+  add r2, r0, #-10
+  brzp in_u16_bad
+
+  ; r1 * 10 + r0 has to fit in 16 bits, r1 being 6553 at most
+  add r1, r1, #0
+  brn in_u16_bad
+  ld r2, neg_too_big
+  add r2, r1, r2
+  brzp in_u16_bad
+  add r2, r2, #1
+  brnp in_u16_digit
+  add r2, r0, #-6
+  brzp in_u16_bad
+in_u16_digit:
+  ; r1 = r1 * 10 + r0
+  add r2, r1, r1
+  add r1, r2, r2
+  add r1, r1, r1
+  add r1, r1, r2
+  add r1, r1, r0
+  add r3, r3, #1
+  brnzp in_u16_loop
+in_u16_done:
+  add r3, r3, #0
+  brz in_u16_bad
+  add r0, r1, #0
+  ld r1, in_u16_r1
+  ld r2, in_u16_r2
+  ld r3, in_u16_r3
+  ld r7, in_u16_r7
+  rti
+in_u16_bad:
+  lea r0, bad_input_message
+  jsr print_string
+  halt
+  brnzp in_u16_bad
+
+in_u16_r7: .blkw #1
+in_u16_r1: .blkw #1
+in_u16_r2: .blkw #1
+in_u16_r3: .blkw #1
+
+; x27, prints r0 as an unsigned decimal number
+out_u16:
+  st r7, out_u16_r7
+  st r0, out_u16_r0
+  st r1, out_u16_r1
+  st r2, out_u16_r2
+  st r3, out_u16_r3
+  st r4, out_u16_r4
+  st r5, out_u16_r5
+  add r1, r0, #0
+  lea r0, output_prompt
+  jsr print_string
+  lea r2, powers
+  and r5, r5, #0
+out_u16_digit:
+  ldr r3, r2, #0
+  brz out_u16_done
+  and r4, r4, #0
+out_u16_subtract:
+  ; anything with the top bit set is above every power of ten
+  add r1, r1, #0
+  brn out_u16_take
+  add r0, r1, r3
+  brn out_u16_print
+out_u16_take:
+  add r1, r1, r3
+  add r4, r4, #1
+  brnzp out_u16_subtract
+out_u16_print:
+  add r2, r2, #1
+
+  ; leading zeros are skipped
+  add r0, r4, r5
+  brz out_u16_digit
+  add r5, r5, #1
+  ld r0, ascii_zero
+  add r0, r0, r4
+  jsr print_char
+  brnzp out_u16_digit
+out_u16_done:
+  add r5, r5, #0
+  brp out_u16_newline
+  ld r0, ascii_zero
+  jsr print_char
+out_u16_newline:
+  and r0, r0, #0
+  add r0, r0, #10
+  jsr print_char
+  ld r0, out_u16_r0
+  ld r1, out_u16_r1
+  ld r2, out_u16_r2
+  ld r3, out_u16_r3
+  ld r4, out_u16_r4
+  ld r5, out_u16_r5
+  ld r7, out_u16_r7
+  rti
+
+out_u16_r7: .blkw #1
+out_u16_r0: .blkw #1
+out_u16_r1: .blkw #1
+out_u16_r2: .blkw #1
+out_u16_r3: .blkw #1
+out_u16_r4: .blkw #1
+out_u16_r5: .blkw #1
+
+bad_trap:
+  lea r0, bad_trap_message
+  jsr print_string
+  halt
+  brnzp bad_trap
+
+.end
@@ -0,0 +1,17 @@
+//! Little-endian fields of the trace and snapshot files.
+
+use std::io::{self, Read};
+
+pub(crate) fn read_u8(input: &mut impl Read) -> io::Result<u8> {
+  let mut byte = [0];
+  input.read_exact(&mut byte)?;
+
+  Ok(byte[0])
+}
+
+pub(crate) fn read_u16(input: &mut impl Read) -> io::Result<u16> {
+  let mut bytes = [0; 2];
+  input.read_exact(&mut bytes)?;
+
+  Ok(u16::from_le_bytes(bytes))
+}
@@ -0,0 +1,192 @@
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+use rvm_compiler::instructions::Instruction;
+
+use crate::memory::MEMORY_SIZE;
+use crate::register::Register;
+use crate::step::Step;
+use crate::Machine;
+
+/// Outcomes of a branch instruction.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Branch {
+  pub taken: u64,
+  pub not_taken: u64,
+}
+
+/// What a run executed, collected between
+/// [`start_coverage`](Machine::start_coverage) and
+/// [`stop_coverage`](Machine::stop_coverage).
+pub struct Coverage {
+  /// Executions by address
+  counts: Box<[u64]>,
+  branches: BTreeMap<u16, Branch>,
+}
+
+impl Coverage {
+  fn new() -> Self {
+    Self {
+      counts: vec![0; MEMORY_SIZE].into_boxed_slice(),
+      branches: BTreeMap::new(),
+    }
+  }
+
+  /// Counts an executed instruction, and which way it went for a BR given the
+  /// condition codes it tested.
+  fn record(&mut self, pc: u16, instruction: u16, cond: u16) {
+    self.counts[usize::from(pc)] += 1;
+
+    if let Instruction::Br(n, z, p, _) = Instruction::decode(instruction) {
+      let branch = self.branches.entry(pc).or_default();
+      let tested = u16::from(n) << 2 | u16::from(z) << 1 | u16::from(p);
+
+      if tested & cond != 0 {
+        branch.taken += 1;
+      } else {
+        branch.not_taken += 1;
+      }
+    }
+  }
+
+  /// Times the instruction at `address` was executed.
+  pub fn executions(&self, address: u16) -> u64 {
+    self.counts[usize::from(address)]
+  }
+
+  /// Outcomes of the BR at `address`, if it was executed.
+  pub fn branch(&self, address: u16) -> Option<Branch> {
+    self.branches.get(&address).copied()
+  }
+
+  /// Writes one line per executed address: the address, executions and, for
+  /// a BR, the times it was taken and not taken.
+  ///
+  /// `x3000 5` or `x3004 5 3 2`, as read by `rvm_compiler --coverage` to map
+  /// back to the source.
+  pub fn write(&self, output: &mut impl Write) -> io::Result<()> {
+    for address in 0..=u16::MAX {
+      let count = self.executions(address);
+
+      if count == 0 {
+        continue;
+      }
+
+      match self.branch(address) {
+        Some(branch) => writeln!(
+          output,
+          "x{address:04x} {count} {} {}",
+          branch.taken, branch.not_taken
+        )?,
+        None => writeln!(output, "x{address:04x} {count}")?,
+      }
+    }
+
+    Ok(())
+  }
+}
+
+impl Machine {
+  /// Records every instruction executed from here on.
+  pub fn start_coverage(&mut self) {
+    self.coverage = Some(Coverage::new());
+  }
+
+  /// Stops recording, returning what was executed.
+  pub fn stop_coverage(&mut self) -> Option<Coverage> {
+    self.coverage.take()
+  }
+
+  /// Called after a step was taken.
+  pub(crate) fn coverage_step(&mut self, step: &Step) {
+    let Some(coverage) = &mut self.coverage else {
+      return;
+    };
+
+    if let Step::Executed { pc, instruction } = *step {
+      coverage.record(pc, instruction, self.registers.get(Register::Cond));
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use rvm_compiler::coverage::{lines, Executed, Line};
+  use rvm_compiler::parsing::{assemble, lowercase};
+  use rvm_compiler::serialize;
+
+  use super::*;
+
+  #[test]
+  fn test_coverage() {
+    // x3000 ADD R0, R0, #2; ADD R0, R0, #-1; BRp x3001; BRn x3000; HALT
+    let mut vm = Machine::with_words(0x3000, &[0x1022, 0x103F, 0x03FE, 0x09FC, 0xF025]);
+
+    vm.start_coverage();
+    vm.resume().unwrap();
+    let coverage = vm.stop_coverage().unwrap();
+
+    assert_eq!(coverage.executions(0x3000), 1);
+    assert_eq!(coverage.executions(0x3001), 2);
+    assert_eq!(
+      coverage.branch(0x3002),
+      Some(Branch {
+        taken: 1,
+        not_taken: 1
+      })
+    );
+    assert_eq!(
+      coverage.branch(0x3003),
+      Some(Branch {
+        taken: 0,
+        not_taken: 1
+      })
+    );
+    assert_eq!(coverage.branch(0x3000), None);
+
+    let mut out = Vec::new();
+    coverage.write(&mut out).unwrap();
+
+    assert_eq!(
+      String::from_utf8(out).unwrap(),
+      "x3000 1\nx3001 2\nx3002 2 1 1\nx3003 1 0 1\nx3004 1\n"
+    );
+  }
+
+  #[test]
+  fn test_source_coverage() {
+    let source = ".orig x3000\nand r0, r0, #0\nbrz skip\nadd r0, r0, #1\nskip: halt\n";
+    let assembly = assemble(&lowercase(source)).unwrap();
+
+    let mut vm = Machine::new();
+    vm.native_traps = true;
+    vm.load_bytes(&serialize(&assembly.program), 0).unwrap();
+    vm.reset_pc(0);
+
+    vm.start_coverage();
+    vm.resume().unwrap();
+
+    let mut out = Vec::new();
+    vm.stop_coverage().unwrap().write(&mut out).unwrap();
+
+    // the assembler reads back what was written
+    let mut executed = Executed::default();
+    executed.parse(&String::from_utf8(out).unwrap()).unwrap();
+
+    assert_eq!(
+      lines(&assembly, &executed)[1..3],
+      [
+        Line {
+          line: 3,
+          count: 1,
+          branch: Some((1, 0))
+        },
+        Line {
+          line: 4,
+          count: 0,
+          branch: None
+        },
+      ]
+    );
+  }
+}
@@ -0,0 +1,745 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{stdout, Write};
+
+use rvm_compiler::instructions::Instruction;
+
+use crate::register::{cc_letter, Register, PSR_PRIORITY};
+use crate::step::StopReason;
+use crate::Machine;
+
+/// Instructions shown before the pc by `list`.
+const LIST_BEFORE: u16 = 4;
+/// Instructions shown by `list`.
+const LIST_LENGTH: u16 = 10;
+
+const HELP: &str = "\
+step [count]          execute instructions, entering subroutines and traps
+next                  execute an instruction, running calls and traps through
+continue              run until a breakpoint or halt
+finish                run until the current subroutine or handler returns
+back [count]          undo instructions
+rewind [step]         go back to a step count, or show the steps recorded
+last-write <address>  go back to before the last write of address
+break <address>       stop before executing the instruction at address
+delete [address]      remove a breakpoint, or all of them
+breakpoints           list breakpoints
+registers             show registers and condition codes
+x <address> [count]   examine memory
+set <target> <value>  write a register (r0-r7, pc, psr) or memory
+list [address]        disassemble around the pc or address
+save <file>           save a snapshot of the machine, to restore with --restore
+quit                  stop debugging
+
+Addresses are numbers (x3000, #12, 12) or labels from the symbol file.
+An empty line repeats the last command.
+";
+
+/// A debugger command, as typed at the prompt.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+  Step(u64),
+  Next,
+  Continue,
+  Finish,
+  /// Undoes steps
+  Back(u64),
+  /// Rewinds to a step count, or shows the history recorded
+  Rewind(Option<u64>),
+  /// Rewinds to before the last write of an address
+  LastWrite(u16),
+  Break(u16),
+  /// Removes the breakpoint at an address, or every breakpoint
+  Delete(Option<u16>),
+  Breakpoints,
+  Registers,
+  /// Shows `count` words from an address
+  Examine(u16, u16),
+  SetRegister(Register, u16),
+  SetMemory(u16, u16),
+  /// Disassembles around an address, the pc by default
+  List(Option<u16>),
+  /// Saves a snapshot to a file
+  Save(String),
+  Help,
+  Quit,
+}
+
+/// Reads a symbol file, one `xADDR label` line per label as written by the
+/// assembler.
+pub fn parse_symbols(text: &str) -> Result<BTreeMap<String, u16>, String> {
+  let mut symbols = BTreeMap::new();
+
+  for (line_number, line) in text.lines().enumerate() {
+    if line.trim().is_empty() {
+      continue;
+    }
+
+    let parsed = match line.split_whitespace().collect::<Vec<_>>().as_slice() {
+      [address, label] => parse_number(address).map(|address| (label.to_string(), address)),
+      _ => None,
+    };
+
+    let Some((label, address)) = parsed else {
+      return Err(format!("bad symbol on line {}: `{line}`", line_number + 1));
+    };
+
+    symbols.insert(label, address);
+  }
+
+  Ok(symbols)
+}
+
+/// The first label of every address with one.
+pub fn labels(symbols: &BTreeMap<String, u16>) -> BTreeMap<u16, String> {
+  let mut labels = BTreeMap::new();
+
+  for (label, &address) in symbols {
+    labels.entry(address).or_insert_with(|| label.clone());
+  }
+
+  labels
+}
+
+/// A number in hex (`x3000`, `0x3000`) or decimal (`#-1`, `12`).
+pub fn parse_number(text: &str) -> Option<u16> {
+  let hex = text
+    .strip_prefix("0x")
+    .or_else(|| text.strip_prefix('x'))
+    .or_else(|| text.strip_prefix('X'));
+
+  if let Some(hex) = hex {
+    return u16::from_str_radix(hex, 16).ok();
+  }
+
+  let decimal: i32 = text.strip_prefix('#').unwrap_or(text).parse().ok()?;
+
+  (-0x8000..=0xFFFF)
+    .contains(&decimal)
+    .then_some(decimal as u16)
+}
+
+/// `r0` to `r7`, `pc` or `psr`.
+pub fn parse_register(name: &str) -> Option<Register> {
+  match name {
+    "pc" => Some(Register::Pc),
+    "psr" => Some(Register::Psr),
+    _ => {
+      let r = name.strip_prefix('r')?.parse().ok()?;
+
+      (r < 8).then(|| Register::general(r))
+    }
+  }
+}
+
+/// `i` as assembly, as if it was at `address`, with pc-relative operands shown
+/// as the address they refer to. A word without a mnemonic is shown as data.
+pub fn disassemble(address: u16, i: u16) -> String {
+  let instruction = Instruction::decode(i);
+  let target = instruction
+    .target(address)
+    .map(|target| format!("x{target:04X}"));
+
+  instruction
+    .to_asm(target.as_deref())
+    .unwrap_or_else(|| format!(".fill x{i:04X}"))
+}
+
+/// An interactive debugger driving a [`Machine`] through
+/// [`step`](Machine::step) and its breakpoints.
+pub struct Debugger {
+  symbols: BTreeMap<String, u16>,
+  /// The first label of every address with one
+  labels: BTreeMap<u16, String>,
+  last: String,
+}
+
+impl Debugger {
+  pub fn new(symbols: BTreeMap<String, u16>) -> Self {
+    Self {
+      labels: labels(&symbols),
+      symbols,
+      last: String::new(),
+    }
+  }
+
+  /// Reads commands from the keyboard until `quit` or the end of input.
+  ///
+  /// The machine reads from the same keyboard, so input typed while it runs
+  /// goes to the program.
+  pub fn run(&mut self, vm: &mut Machine) {
+    println!("{}", self.location(vm));
+
+    loop {
+      print!("(rvm) ");
+      stdout().flush().unwrap();
+
+      let Some(line) = read_line(vm) else {
+        break;
+      };
+
+      let line = if line.trim().is_empty() {
+        self.last.clone()
+      } else {
+        line
+      };
+
+      match self.parse(&line) {
+        Ok(Command::Quit) => break,
+        Ok(command) => {
+          print!("{}", self.execute(vm, command));
+          self.last = line;
+        }
+        Err(err) => println!("{err}"),
+      }
+    }
+  }
+
+  pub fn parse(&self, line: &str) -> Result<Command, String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+
+    let address = |text: &str| -> Result<u16, String> {
+      parse_number(text)
+        .or_else(|| self.symbols.get(text).copied())
+        .ok_or_else(|| format!("unknown address `{text}`"))
+    };
+
+    let value = |text: &str| parse_number(text).ok_or_else(|| format!("bad value `{text}`"));
+    let number = |text: &str| text.parse().map_err(|_| format!("bad count `{text}`"));
+
+    let command = match words.as_slice() {
+      ["s" | "step"] => Command::Step(1),
+      ["s" | "step", count] => Command::Step(number(count)?),
+      ["n" | "next"] => Command::Next,
+      ["c" | "continue"] => Command::Continue,
+      ["f" | "finish"] => Command::Finish,
+      ["bs" | "back"] => Command::Back(1),
+      ["bs" | "back", count] => Command::Back(number(count)?),
+      ["rewind"] => Command::Rewind(None),
+      ["rewind", step] => Command::Rewind(Some(number(step)?)),
+      ["lw" | "last-write", at] => Command::LastWrite(address(at)?),
+      ["b" | "break", at] => Command::Break(address(at)?),
+      ["d" | "delete"] => Command::Delete(None),
+      ["d" | "delete", at] => Command::Delete(Some(address(at)?)),
+      ["bl" | "breakpoints"] => Command::Breakpoints,
+      ["r" | "registers"] => Command::Registers,
+      ["x", at] => Command::Examine(address(at)?, 1),
+      ["x", at, count] => Command::Examine(address(at)?, value(count)?),
+      ["set", target, to] => match parse_register(target) {
+        Some(r) => Command::SetRegister(r, value(to)?),
+        None => Command::SetMemory(address(target)?, value(to)?),
+      },
+      ["l" | "list"] => Command::List(None),
+      ["l" | "list", at] => Command::List(Some(address(at)?)),
+      ["save", file] => Command::Save(file.to_string()),
+      ["h" | "help"] => Command::Help,
+      ["q" | "quit"] => Command::Quit,
+      _ => return Err(format!("unknown command `{}`, try `help`", line.trim())),
+    };
+
+    Ok(command)
+  }
+
+  /// Runs `command`, returning what it printed.
+  pub fn execute(&mut self, vm: &mut Machine, command: Command) -> String {
+    let mut out = String::new();
+
+    match command {
+      Command::Step(count) => {
+        let reason = vm.run_for(count);
+        self.stopped(vm, reason, &mut out);
+      }
+      Command::Next => {
+        let pc = vm.registers.get(Register::Pc);
+        let instruction = Instruction::decode(vm.memory.read(pc));
+
+        // run calls and traps through to the instruction after them
+        let call = matches!(
+          instruction,
+          Instruction::Jsr(_) | Instruction::Jsrr(_) | Instruction::Trap(_)
+        );
+
+        let reason = if call {
+          let next = pc.wrapping_add(1);
+          let mut depth = 0;
+
+          // a recursive call passes through `next` in its own frames first
+          vm.run_with(None, |vm, step| {
+            let pc = vm.registers.get(Register::Pc);
+            depth += step.call_depth(pc);
+
+            depth == 0 && pc == next
+          })
+        } else {
+          vm.run_for(1)
+        };
+
+        self.stopped(vm, reason, &mut out);
+      }
+      Command::Continue => {
+        let reason = vm.run_until(|_| false);
+        self.stopped(vm, reason, &mut out);
+      }
+      Command::Finish => {
+        let reason = finish(vm);
+        self.stopped(vm, reason, &mut out);
+      }
+      Command::Back(count) => {
+        if !(0..count).all(|_| vm.step_back()) {
+          writeln!(out, "reached the start of the history").unwrap();
+        }
+
+        writeln!(out, "{}", self.location(vm)).unwrap();
+      }
+      Command::Rewind(None) => match vm.history_range() {
+        Some(range) => writeln!(
+          out,
+          "at step {}, history from step {}",
+          range.end(),
+          range.start()
+        )
+        .unwrap(),
+        None => writeln!(out, "no history recorded").unwrap(),
+      },
+      Command::Rewind(Some(step)) => {
+        if vm.rewind_to(step) {
+          writeln!(out, "{}", self.location(vm)).unwrap();
+        } else {
+          writeln!(out, "step {step} is not in the history").unwrap();
+        }
+      }
+      Command::LastWrite(address) => {
+        if vm.run_back_to_write(address) {
+          writeln!(out, "{}", self.location(vm)).unwrap();
+        } else {
+          writeln!(out, "no write to {} in the history", self.name(address)).unwrap();
+        }
+      }
+      Command::Break(address) => {
+        vm.breakpoints.insert(address);
+        writeln!(out, "breakpoint at {}", self.name(address)).unwrap();
+      }
+      Command::Delete(Some(address)) => {
+        if !vm.breakpoints.remove(&address) {
+          writeln!(out, "no breakpoint at {}", self.name(address)).unwrap();
+        }
+      }
+      Command::Delete(None) => vm.breakpoints.clear(),
+      Command::Breakpoints => {
+        for &address in &vm.breakpoints {
+          writeln!(out, "{}", self.name(address)).unwrap();
+        }
+      }
+      Command::Registers => {
+        let registers = &vm.registers;
+
+        for r in 0..8 {
+          let separator = if r % 4 == 3 { "\n" } else { "  " };
+          let value = registers.get(Register::general(r));
+
+          write!(out, "r{r} x{value:04X}{separator}").unwrap();
+        }
+
+        writeln!(
+          out,
+          "pc x{:04X}  psr x{:04X}  cc {}  {} priority {}",
+          registers.get(Register::Pc),
+          registers.psr(),
+          cc_letter(registers.get(Register::Cond)),
+          if registers.user_mode() {
+            "user"
+          } else {
+            "supervisor"
+          },
+          (registers.get(Register::Psr) & PSR_PRIORITY) >> 8
+        )
+        .unwrap();
+      }
+      Command::Examine(address, count) => {
+        for row in 0..count.div_ceil(8) {
+          let start = address.wrapping_add(row * 8);
+          write!(out, "x{start:04X}:").unwrap();
+
+          for column in 0..(count - row * 8).min(8) {
+            write!(out, " x{:04X}", vm.memory.read(start.wrapping_add(column))).unwrap();
+          }
+
+          writeln!(out).unwrap();
+        }
+      }
+      Command::SetRegister(Register::Psr, value) => vm.registers.set_psr(value),
+      Command::SetRegister(r, value) => *vm.registers.reg_r(r) = value,
+      Command::SetMemory(address, value) => vm.memory.write(address, value),
+      Command::List(address) => {
+        let pc = vm.registers.get(Register::Pc);
+        let start = address.unwrap_or(pc).wrapping_sub(LIST_BEFORE);
+
+        for address in (0..LIST_LENGTH).map(|i| start.wrapping_add(i)) {
+          if let Some(label) = self.labels.get(&address) {
+            writeln!(out, "{label}:").unwrap();
+          }
+
+          let breakpoint = if vm.breakpoints.contains(&address) {
+            '*'
+          } else {
+            ' '
+          };
+          let current = if address == pc { '>' } else { ' ' };
+          let i = vm.memory.read(address);
+
+          writeln!(
+            out,
+            "{breakpoint}{current} x{address:04X}  x{i:04X}  {}",
+            self.instruction(address, i)
+          )
+          .unwrap();
+        }
+      }
+      Command::Save(file) => {
+        let saved = File::create(&file).and_then(|mut output| vm.save_snapshot(&mut output));
+
+        match saved {
+          Ok(()) => writeln!(out, "saved to `{file}`").unwrap(),
+          Err(err) => writeln!(out, "failed to save `{file}`: {err}").unwrap(),
+        }
+      }
+      Command::Help => out.push_str(HELP),
+      Command::Quit => {}
+    }
+
+    out
+  }
+
+  fn stopped(&self, vm: &mut Machine, reason: StopReason, out: &mut String) {
+    // a call run through by `next` can halt while still meeting its condition
+    if !vm.running() {
+      writeln!(out, "halted").unwrap();
+      return;
+    }
+
+    match reason {
+      StopReason::Breakpoint(address) => {
+        writeln!(out, "breakpoint at {}", self.name(address)).unwrap()
+      }
+      StopReason::Error(err) => writeln!(out, "error: {err}").unwrap(),
+      StopReason::Halted | StopReason::BudgetExhausted | StopReason::Condition => {}
+    }
+
+    writeln!(out, "{}", self.location(vm)).unwrap();
+  }
+
+  /// The instruction at the pc.
+  fn location(&self, vm: &mut Machine) -> String {
+    let pc = vm.registers.get(Register::Pc);
+    let i = vm.memory.read(pc);
+
+    format!("{}: {}", self.name(pc), self.instruction(pc, i))
+  }
+
+  /// An address along with the closest label at or before it.
+  fn name(&self, address: u16) -> String {
+    match self.labels.range(..=address).next_back() {
+      Some((&start, label)) if start == address => format!("x{address:04X} <{label}>"),
+      Some((&start, label)) => format!("x{address:04X} <{label}+{}>", address - start),
+      None => format!("x{address:04X}"),
+    }
+  }
+
+  fn instruction(&self, address: u16, i: u16) -> String {
+    let asm = disassemble(address, i);
+
+    let target = Instruction::decode(i).target(address);
+
+    match target.and_then(|target| self.labels.get(&target)) {
+      Some(label) => format!("{asm} <{label}>"),
+      None => asm,
+    }
+  }
+}
+
+/// Runs until the subroutine, trap routine or handler the pc is in returns to
+/// its caller, counting the calls made along the way.
+fn finish(vm: &mut Machine) -> StopReason {
+  let mut depth = 0;
+
+  vm.run_with(None, |vm, step| {
+    depth += step.call_depth(vm.registers.get(Register::Pc));
+    depth < 0
+  })
+}
+
+/// A line typed on the keyboard, without its newline.
+fn read_line(vm: &mut Machine) -> Option<String> {
+  let mut line = Vec::new();
+
+  loop {
+    match vm.memory.keyboard.wait() {
+      Some(b'\n') => break,
+      Some(c) => line.push(c),
+      None if line.is_empty() => return None,
+      None => break,
+    }
+  }
+
+  Some(
+    String::from_utf8_lossy(&line)
+      .trim_end_matches('\r')
+      .to_string(),
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use rvm_compiler::parsing::{assemble, lowercase};
+  use rvm_compiler::serialize;
+
+  use super::*;
+
+  const SOURCE: &str = r#"
+    .orig x3000
+    start:
+      and r0, r0, #0
+      jsr double
+      add r0, r0, #1
+      jsr double
+      halt
+    double:
+      add r0, r0, r0
+      add r1, r1, #1
+      jmp r7
+    .end
+  "#;
+
+  fn debugger() -> (Debugger, Machine) {
+    debugger_for(SOURCE)
+  }
+
+  fn debugger_for(source: &str) -> (Debugger, Machine) {
+    let assembly = assemble(&lowercase(source)).unwrap();
+
+    let mut vm = Machine::new();
+    vm.native_traps = true;
+    vm.load_bytes(&serialize(&assembly.program), 0).unwrap();
+    vm.reset_pc(0);
+
+    (Debugger::new(assembly.symbols), vm)
+  }
+
+  fn run(debugger: &mut Debugger, vm: &mut Machine, line: &str) -> String {
+    let command = debugger.parse(line).unwrap();
+
+    debugger.execute(vm, command)
+  }
+
+  #[test]
+  fn test_parse_symbols() {
+    let symbols = parse_symbols("x3000 start\n\nx3004 double\n").unwrap();
+    assert_eq!(symbols["start"], 0x3000);
+    assert_eq!(symbols["double"], 0x3004);
+
+    assert!(parse_symbols("start").is_err());
+  }
+
+  #[test]
+  fn test_disassemble() {
+    let cases = [
+      (0x0E02, "brnzp x3003"),
+      (0x0000, ".fill x0000"),
+      (0x107F, "add r0, r1, #-1"),
+      (0x4FFF, "jsr x3000"),
+      (0x6C7E, "ldr r6, r1, #-2"),
+      (0xC1C0, "jmp r7"),
+      (0xE1FF, "lea r0, x3000"),
+      (0xF025, "trap thalt"),
+      (0xF030, "trap x30"),
+    ];
+
+    for (i, asm) in cases {
+      assert_eq!(disassemble(0x3000, i), asm, "x{i:04X}");
+    }
+  }
+
+  #[test]
+  fn test_parse() {
+    let (debugger, _) = debugger();
+
+    assert_eq!(debugger.parse("s 3"), Ok(Command::Step(3)));
+    assert_eq!(debugger.parse("break double"), Ok(Command::Break(0x3005)));
+    assert_eq!(debugger.parse("b #12"), Ok(Command::Break(12)));
+    assert_eq!(
+      debugger.parse("x start x10"),
+      Ok(Command::Examine(0x3000, 0x10))
+    );
+    assert_eq!(
+      debugger.parse("set r3 #-1"),
+      Ok(Command::SetRegister(Register::R3, 0xFFFF))
+    );
+    assert_eq!(
+      debugger.parse("set x4000 0x12"),
+      Ok(Command::SetMemory(0x4000, 0x12))
+    );
+    assert_eq!(debugger.parse("back 2"), Ok(Command::Back(2)));
+    assert_eq!(debugger.parse("rewind 10"), Ok(Command::Rewind(Some(10))));
+    assert_eq!(debugger.parse("lw double"), Ok(Command::LastWrite(0x3005)));
+    assert_eq!(
+      debugger.parse("save run.snap"),
+      Ok(Command::Save("run.snap".to_string()))
+    );
+    assert!(debugger.parse("break nowhere").is_err());
+    assert!(debugger.parse("jump").is_err());
+  }
+
+  #[test]
+  fn test_breakpoints() {
+    let (mut debugger, mut vm) = debugger();
+
+    run(&mut debugger, &mut vm, "break double");
+
+    let out = run(&mut debugger, &mut vm, "continue");
+    assert_eq!(
+      out,
+      "breakpoint at x3005 <double>\nx3005 <double>: add r0, r0, r0\n"
+    );
+
+    let out = run(&mut debugger, &mut vm, "c");
+    assert!(out.starts_with("breakpoint at x3005 <double>"));
+    assert_eq!(vm.registers.get(Register::R0), 1);
+
+    run(&mut debugger, &mut vm, "delete double");
+    assert_eq!(run(&mut debugger, &mut vm, "c"), "halted\n");
+    assert_eq!(vm.registers.get(Register::R0), 2);
+  }
+
+  #[test]
+  fn test_next_and_finish() {
+    let (mut debugger, mut vm) = debugger();
+
+    run(&mut debugger, &mut vm, "step");
+    let out = run(&mut debugger, &mut vm, "next");
+    assert_eq!(out, "x3002 <start+2>: add r0, r0, #1\n");
+    assert_eq!(vm.registers.get(Register::R1), 1);
+
+    run(&mut debugger, &mut vm, "s 2");
+    assert_eq!(vm.registers.get(Register::Pc), 0x3005);
+
+    let out = run(&mut debugger, &mut vm, "finish");
+    assert_eq!(out, "x3004 <start+4>: trap thalt\n");
+    assert_eq!(vm.registers.get(Register::R0), 2);
+
+    assert_eq!(run(&mut debugger, &mut vm, "next"), "halted\n");
+  }
+
+  #[test]
+  fn test_next_over_recursion() {
+    let (mut debugger, mut vm) = debugger_for(
+      r#"
+      .orig x3000
+        and r0, r0, #0
+        add r0, r0, #2
+        jsr count
+        halt
+      count:
+        add r6, r6, #-1
+        str r7, r6, #0
+        add r1, r1, #1
+        add r0, r0, #-1
+        brz done
+        jsr count
+      done:
+        ldr r7, r6, #0
+        add r6, r6, #1
+        jmp r7
+      .end
+    "#,
+    );
+
+    run(&mut debugger, &mut vm, "break x3009");
+    run(&mut debugger, &mut vm, "continue");
+
+    // the nested call reaches `done` first, but `next` waits for it to return
+    let out = run(&mut debugger, &mut vm, "next");
+    assert_eq!(out, "x300A <done>: ldr r7, r6, #0\n");
+    assert_eq!(vm.registers.get(Register::R1), 2);
+    assert_eq!(vm.registers.get(Register::R6), 0x2FFF);
+  }
+
+  #[test]
+  fn test_registers_and_memory() {
+    let (mut debugger, mut vm) = debugger();
+
+    run(&mut debugger, &mut vm, "set r1 xBEEF");
+    run(&mut debugger, &mut vm, "set psr x8004");
+    assert_eq!(
+      run(&mut debugger, &mut vm, "registers"),
+      "r0 x0000  r1 xBEEF  r2 x0000  r3 x0000\n\
+       r4 x0000  r5 x0000  r6 x3000  r7 x0000\n\
+       pc x3000  psr x8004  cc n  user priority 0\n"
+    );
+
+    run(&mut debugger, &mut vm, "set x4000 #7");
+    assert_eq!(
+      run(&mut debugger, &mut vm, "x x3FFF 10"),
+      "x3FFF: x0000 x0007 x0000 x0000 x0000 x0000 x0000 x0000\n\
+       x4007: x0000 x0000\n"
+    );
+  }
+
+  #[test]
+  fn test_list() {
+    let (mut debugger, mut vm) = debugger();
+
+    run(&mut debugger, &mut vm, "step");
+    run(&mut debugger, &mut vm, "break x3002");
+
+    let out = run(&mut debugger, &mut vm, "list");
+    let lines: Vec<&str> = out.lines().collect();
+
+    assert_eq!(lines[3], "start:");
+    assert_eq!(lines[5], " > x3001  x4803  jsr x3005 <double>");
+    assert_eq!(lines[6], "*  x3002  x1021  add r0, r0, #1");
+    assert_eq!(lines[9], "double:");
+  }
+
+  #[test]
+  fn test_back_and_rewind() {
+    let (mut debugger, mut vm) = debugger();
+    vm.record_history(100);
+
+    run(&mut debugger, &mut vm, "s 4");
+    let out = run(&mut debugger, &mut vm, "back 2");
+    assert_eq!(
+      out,
+      "x3005 <double>: add r0, r0, r0
+"
+    );
+    assert_eq!(vm.registers.get(Register::R1), 0);
+
+    assert_eq!(
+      run(&mut debugger, &mut vm, "rewind"),
+      "at step 2, history from step 0
+"
+    );
+    assert_eq!(
+      run(&mut debugger, &mut vm, "rewind 5"),
+      "step 5 is not in the history
+"
+    );
+    assert_eq!(
+      run(&mut debugger, &mut vm, "rewind 0"),
+      "x3000 <start>: and r0, r0, #0
+"
+    );
+    assert_eq!(
+      run(&mut debugger, &mut vm, "back"),
+      "reached the start of the history
+x3000 <start>: and r0, r0, #0
+"
+    );
+    assert_eq!(
+      run(&mut debugger, &mut vm, "lw x2000"),
+      "no write to x2000 in the history
+"
+    );
+  }
+}
@@ -0,0 +1,394 @@
+use std::collections::VecDeque;
+use std::io::{self, stdin, stdout, BufReader, ErrorKind, Read, Write};
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::thread;
+
+/// Keyboard status register, bit 15 is set when a character is ready.
+pub const KBSR: u16 = 0xFE00;
+/// Keyboard data register, the last character typed.
+pub const KBDR: u16 = 0xFE02;
+/// Display status register, bit 15 is set when the display is ready for a
+/// character.
+pub const DSR: u16 = 0xFE04;
+/// Display data register, characters written here are printed.
+pub const DDR: u16 = 0xFE06;
+/// Machine control register, the machine stops when bit 15 is cleared.
+pub const MCR: u16 = 0xFFFE;
+
+const READY: u16 = 1 << 15;
+const INTERRUPT_ENABLE: u16 = 1 << 14;
+
+/// Vector of the keyboard interrupt, raised at [`KEYBOARD_PRIORITY`].
+pub const KEYBOARD_VECTOR: u8 = 0x80;
+pub const KEYBOARD_PRIORITY: u16 = 4;
+
+/// An interrupt request, taken when its priority is above the PSR's.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Interrupt {
+  /// Priority level, 0 to 7
+  pub priority: u16,
+  pub vector: u8,
+}
+
+/// A memory-mapped device, handling reads and writes to its addresses in place
+/// of memory.
+pub trait Device {
+  fn read(&mut self, address: u16) -> u16;
+  fn write(&mut self, address: u16, value: u16);
+
+  /// The interrupt this device is requesting, checked before every instruction.
+  fn interrupt(&mut self) -> Option<Interrupt> {
+    None
+  }
+}
+
+#[derive(Default)]
+pub struct Keyboard {
+  pub(crate) buffer: VecDeque<u8>,
+  source: Option<Receiver<u8>>,
+  pub(crate) data: u16,
+  pub(crate) status: u16,
+  /// Characters read while the undo log records a step
+  pub(crate) taken: Option<Vec<u8>>,
+}
+
+impl Keyboard {
+  /// A keyboard that only receives what is [`push`](Keyboard::push)ed to it.
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// A keyboard fed from the host's stdin by a background thread.
+  pub fn stdin() -> Self {
+    Self::reader(stdin())
+  }
+
+  /// A keyboard fed from `input` by a background thread, whose input ends
+  /// along with it.
+  pub fn reader(input: impl Read + Send + 'static) -> Self {
+    let (sender, receiver) = channel();
+
+    thread::spawn(move || {
+      for byte in BufReader::new(input).bytes() {
+        let Ok(byte) = byte else { break };
+
+        if sender.send(byte).is_err() {
+          break;
+        }
+      }
+    });
+
+    Self {
+      source: Some(receiver),
+      ..Self::default()
+    }
+  }
+
+  /// Queues input as if it was typed.
+  pub fn push(&mut self, input: &[u8]) {
+    self.buffer.extend(input);
+  }
+
+  /// Puts characters back in front of the input, to be read again.
+  pub(crate) fn unread(&mut self, input: &[u8]) {
+    for &c in input.iter().rev() {
+      self.buffer.push_front(c);
+    }
+  }
+
+  /// The next character, noting it for the undo log.
+  fn take(&mut self) -> Option<u8> {
+    let c = self.buffer.pop_front()?;
+
+    if let Some(taken) = &mut self.taken {
+      taken.push(c);
+    }
+
+    Some(c)
+  }
+
+  /// Whether a character is waiting to be read from `KBDR`.
+  pub fn ready(&mut self) -> bool {
+    if let Some(source) = &self.source {
+      self.buffer.extend(source.try_iter());
+    }
+
+    !self.buffer.is_empty()
+  }
+
+  /// Whether the input has ended with nothing left to read. Only input from a
+  /// reader ends, more can always be pushed.
+  pub fn ended(&mut self) -> bool {
+    if self.ready() {
+      return false;
+    }
+
+    let Some(source) = &self.source else {
+      return false;
+    };
+
+    match source.try_recv() {
+      Ok(byte) => {
+        self.buffer.push_back(byte);
+        false
+      }
+      Err(TryRecvError::Empty) => false,
+      Err(TryRecvError::Disconnected) => true,
+    }
+  }
+
+  /// Takes the next character, blocking until one is typed.
+  ///
+  /// Returns `None` once the input has ended.
+  pub fn wait(&mut self) -> Option<u8> {
+    if !self.ready() {
+      let byte = self.source.as_ref()?.recv().ok()?;
+      self.buffer.push_back(byte);
+    }
+
+    self.take()
+  }
+}
+
+/// The error reading past the end of the input fails with, natively or by
+/// polling `KBSR`.
+pub fn end_of_input() -> io::Error {
+  io::Error::new(ErrorKind::UnexpectedEof, "end of input")
+}
+
+impl Device for Keyboard {
+  fn read(&mut self, address: u16) -> u16 {
+    match address {
+      KBSR => {
+        let ready = if self.ready() { READY } else { 0 };
+
+        ready | self.status
+      }
+      KBDR => {
+        if self.ready() {
+          self.data = u16::from(self.take().unwrap());
+        }
+
+        self.data
+      }
+      _ => 0,
+    }
+  }
+
+  fn write(&mut self, address: u16, value: u16) {
+    if address == KBSR {
+      self.status = value & INTERRUPT_ENABLE;
+    }
+  }
+
+  fn interrupt(&mut self) -> Option<Interrupt> {
+    if self.status & INTERRUPT_ENABLE != 0 && self.ready() {
+      Some(Interrupt {
+        priority: KEYBOARD_PRIORITY,
+        vector: KEYBOARD_VECTOR,
+      })
+    } else {
+      None
+    }
+  }
+}
+
+pub enum Output {
+  Stdout,
+  /// Output kept for the host to [`take`](Display::take_output)
+  Buffer(Vec<u8>),
+}
+
+pub struct Display {
+  output: Output,
+  pub(crate) status: u16,
+  /// Why a character written to `DDR` couldn't be printed
+  error: Option<io::Error>,
+}
+
+impl Display {
+  pub fn new(output: Output) -> Self {
+    Self {
+      output,
+      status: 0,
+      error: None,
+    }
+  }
+
+  pub fn put(&mut self, c: u8) -> io::Result<()> {
+    self.print(&[c])
+  }
+
+  pub fn print(&mut self, bytes: &[u8]) -> io::Result<()> {
+    match &mut self.output {
+      Output::Stdout => {
+        let mut lock = stdout().lock();
+        lock.write_all(bytes)?;
+        lock.flush()
+      }
+      Output::Buffer(buffer) => {
+        buffer.extend_from_slice(bytes);
+        Ok(())
+      }
+    }
+  }
+
+  /// Why printing a character written to `DDR` failed, if it has since the
+  /// last call.
+  pub fn take_error(&mut self) -> Option<io::Error> {
+    self.error.take()
+  }
+
+  /// Everything printed since the last call, when output is buffered.
+  pub fn take_output(&mut self) -> Vec<u8> {
+    match &mut self.output {
+      Output::Stdout => Vec::new(),
+      Output::Buffer(buffer) => std::mem::take(buffer),
+    }
+  }
+}
+
+impl Default for Display {
+  fn default() -> Self {
+    Self::new(Output::Stdout)
+  }
+}
+
+impl Device for Display {
+  fn read(&mut self, address: u16) -> u16 {
+    match address {
+      // printing never takes long enough for the display to be busy
+      DSR => READY | self.status,
+      _ => 0,
+    }
+  }
+
+  fn write(&mut self, address: u16, value: u16) {
+    match address {
+      DSR => self.status = value & INTERRUPT_ENABLE,
+      DDR => {
+        if let Err(err) = self.put(value as u8) {
+          self.error = Some(err);
+        }
+      }
+      _ => {}
+    }
+  }
+}
+
+pub struct MachineControl {
+  pub(crate) mcr: u16,
+}
+
+impl MachineControl {
+  pub fn running(&self) -> bool {
+    self.mcr & READY != 0
+  }
+}
+
+impl Default for MachineControl {
+  fn default() -> Self {
+    Self { mcr: READY }
+  }
+}
+
+impl Device for MachineControl {
+  fn read(&mut self, _address: u16) -> u16 {
+    self.mcr
+  }
+
+  fn write(&mut self, _address: u16, value: u16) {
+    self.mcr = value;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_keyboard_input_ends() {
+    let mut keyboard = Keyboard::reader(&b"a"[..]);
+
+    assert_eq!(keyboard.wait(), Some(b'a'));
+    assert_eq!(keyboard.wait(), None);
+    assert!(keyboard.ended());
+
+    // pushed input is read before the end
+    keyboard.push(b"b");
+    assert!(!keyboard.ended());
+    assert_eq!(keyboard.read(KBDR), 'b' as u16);
+    assert!(keyboard.ended());
+
+    assert!(!Keyboard::new().ended());
+  }
+
+  #[test]
+  fn test_keyboard() {
+    let mut keyboard = Keyboard::new();
+    assert_eq!(keyboard.read(KBSR), 0);
+
+    keyboard.push(b"ab");
+    assert_eq!(keyboard.read(KBSR), READY);
+    assert_eq!(keyboard.read(KBDR), 'a' as u16);
+    assert_eq!(keyboard.read(KBSR), READY);
+    assert_eq!(keyboard.read(KBDR), 'b' as u16);
+    assert_eq!(keyboard.read(KBSR), 0);
+
+    // the last character stays in the data register
+    assert_eq!(keyboard.read(KBDR), 'b' as u16);
+
+    keyboard.write(KBSR, 0xFFFF);
+    assert_eq!(keyboard.read(KBSR), INTERRUPT_ENABLE);
+  }
+
+  #[test]
+  fn test_keyboard_interrupt() {
+    let mut keyboard = Keyboard::new();
+    keyboard.push(b"a");
+    assert_eq!(keyboard.interrupt(), None);
+
+    keyboard.write(KBSR, INTERRUPT_ENABLE);
+    assert_eq!(
+      keyboard.interrupt(),
+      Some(Interrupt {
+        priority: 4,
+        vector: 0x80
+      })
+    );
+
+    // reading the character acknowledges the interrupt
+    keyboard.read(KBDR);
+    assert_eq!(keyboard.interrupt(), None);
+  }
+
+  #[test]
+  fn test_keyboard_wait() {
+    let mut keyboard = Keyboard::new();
+    keyboard.push(b"a");
+
+    assert_eq!(keyboard.wait(), Some(b'a'));
+    assert_eq!(keyboard.wait(), None);
+  }
+
+  #[test]
+  fn test_display() {
+    let mut display = Display::new(Output::Buffer(Vec::new()));
+    assert_eq!(display.read(DSR), READY);
+
+    display.write(DDR, 'h' as u16);
+    display.write(DDR, 'i' as u16);
+    assert_eq!(display.take_output(), b"hi");
+    assert_eq!(display.take_output(), b"");
+  }
+
+  #[test]
+  fn test_machine_control() {
+    let mut control = MachineControl::default();
+    assert!(control.running());
+
+    let mcr = control.read(MCR);
+    control.write(MCR, mcr & !READY);
+    assert!(!control.running());
+  }
+}
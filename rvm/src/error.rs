@@ -0,0 +1,68 @@
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::io;
+
+use crate::exception::Exception;
+
+/// Why loading or running a machine failed.
+#[derive(Debug)]
+pub enum VmError {
+  Io(io::Error),
+  /// An image that doesn't fit in memory or isn't made of whole words
+  MalformedImage(String),
+  /// A snapshot file this version can't restore
+  MalformedSnapshot(String),
+  /// TRAP with no host routine and a zero entry in the trap vector table
+  InvalidTrap(u8),
+  /// Input a trap routine couldn't make sense of
+  BadInput(String),
+  /// Running a machine whose clock is disabled
+  Halted,
+  /// An exception without a handler in the vector table
+  Exception(Exception),
+  /// A host trap handler failed
+  Trap {
+    vector: u8,
+    error: Box<dyn Error>,
+  },
+  InvalidRegister(u16),
+}
+
+impl Display for VmError {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    match self {
+      VmError::Io(err) => write!(f, "{err}"),
+      VmError::MalformedImage(reason) => write!(f, "malformed image: {reason}"),
+      VmError::MalformedSnapshot(reason) => write!(f, "malformed snapshot: {reason}"),
+      VmError::InvalidTrap(vector) => write!(f, "no routine for trap x{vector:02X}"),
+      VmError::BadInput(input) => write!(f, "bad input {input:?}"),
+      VmError::Halted => write!(f, "the machine is halted"),
+      VmError::Exception(exception) => write!(f, "unhandled exception: {exception}"),
+      VmError::Trap { vector, error } => write!(f, "trap x{vector:02X} failed: {error}"),
+      VmError::InvalidRegister(index) => write!(f, "invalid register index {index}"),
+    }
+  }
+}
+
+impl Error for VmError {
+  fn source(&self) -> Option<&(dyn Error + 'static)> {
+    match self {
+      VmError::Io(err) => Some(err),
+      VmError::Exception(exception) => Some(exception),
+      VmError::Trap { error, .. } => Some(error.as_ref()),
+      _ => None,
+    }
+  }
+}
+
+impl From<io::Error> for VmError {
+  fn from(err: io::Error) -> Self {
+    VmError::Io(err)
+  }
+}
+
+impl From<Exception> for VmError {
+  fn from(exception: Exception) -> Self {
+    VmError::Exception(exception)
+  }
+}
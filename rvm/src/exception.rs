@@ -0,0 +1,187 @@
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+
+use crate::device::{end_of_input, KBSR};
+use crate::error::VmError;
+use crate::register::Register;
+use crate::trace::Access;
+use crate::{Machine, INTERRUPT_VECTOR_TABLE};
+
+/// Lowest address of user space, everything below is system space.
+pub const USER_SPACE_START: u16 = 0x3000;
+/// Lowest address of the device registers, which are system space too.
+pub const DEVICE_SPACE_START: u16 = 0xFE00;
+
+/// An exception raised by an instruction, vectored through the interrupt vector
+/// table like an interrupt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exception {
+  /// RTI run in user mode
+  PrivilegeViolation,
+  /// The reserved opcode
+  IllegalOpcode,
+  /// User mode access to system space, at the given address
+  AccessViolation(u16),
+}
+
+impl Exception {
+  pub fn vector(&self) -> u8 {
+    match self {
+      Exception::PrivilegeViolation => 0x00,
+      Exception::IllegalOpcode => 0x01,
+      Exception::AccessViolation(_) => 0x02,
+    }
+  }
+}
+
+impl Display for Exception {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    match self {
+      Exception::PrivilegeViolation => write!(f, "privilege mode violation"),
+      Exception::IllegalOpcode => write!(f, "illegal opcode"),
+      Exception::AccessViolation(address) => {
+        write!(f, "access control violation at x{address:04X}")
+      }
+    }
+  }
+}
+
+impl Error for Exception {}
+
+impl Machine {
+  /// Checks that the current privilege allows accessing `address`.
+  pub fn check_access(&self, address: u16) -> Result<(), Exception> {
+    let system = !(USER_SPACE_START..DEVICE_SPACE_START).contains(&address);
+
+    if system && self.registers.user_mode() {
+      Err(Exception::AccessViolation(address))
+    } else {
+      Ok(())
+    }
+  }
+
+  /// Reads memory on behalf of the running program.
+  ///
+  /// Polling `KBSR` once the input has ended fails, as the native trap routines
+  /// do, since nothing will ever be ready.
+  pub fn read(&mut self, address: u16) -> Result<u16, VmError> {
+    self.check_access(address)?;
+
+    if address == KBSR && self.memory.keyboard.ended() {
+      return Err(end_of_input().into());
+    }
+
+    let value = self.memory.read(address);
+    self.trace_access(Access::Read { address, value });
+
+    Ok(value)
+  }
+
+  /// Writes memory on behalf of the running program, failing when a character
+  /// written to `DDR` can't be printed.
+  pub fn write(&mut self, address: u16, value: u16) -> Result<(), VmError> {
+    self.check_access(address)?;
+
+    self.memory.write(address, value);
+    self.trace_access(Access::Write { address, value });
+
+    match self.memory.display.take_error() {
+      Some(err) => Err(err.into()),
+      None => Ok(()),
+    }
+  }
+
+  /// Enters the handler for `exception`, raised by the instruction at `pc`.
+  ///
+  /// Without a handler in the vector table the exception is returned instead,
+  /// with the pc left on the faulting instruction.
+  pub fn raise(&mut self, exception: Exception, pc: u16) -> Result<(), Exception> {
+    let vector = exception.vector();
+    let handler = self
+      .memory
+      .read(INTERRUPT_VECTOR_TABLE.wrapping_add(u16::from(vector)));
+
+    if handler == 0 {
+      *self.registers.reg_r(Register::Pc) = pc;
+      return Err(exception);
+    }
+
+    self.enter_handler(vector, None);
+
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_unhandled_exception() {
+    let mut vm = Machine::new();
+
+    // RES
+    vm.memory.write(0x3000, 0xD000);
+    vm.pc_start = 0x3000;
+
+    assert!(matches!(
+      vm.run(0),
+      Err(VmError::Exception(Exception::IllegalOpcode))
+    ));
+    assert_eq!(vm.registers.get(Register::Pc), 0x3000);
+    assert!(vm.running());
+  }
+
+  #[test]
+  fn test_handled_exception() {
+    let mut vm = Machine::new();
+    vm.native_traps = true;
+
+    // the saved pc is already past the faulting instruction, so the handler
+    // adding one to it skips the instruction after
+    // x1000 LDR R0, R6, #0; ADD R0, R0, #1; STR R0, R6, #0; RTI
+    for (address, word) in [
+      (0x1000, 0x6180),
+      (0x1001, 0x1021),
+      (0x1002, 0x7180),
+      (0x1003, 0x8000),
+      (0x0102, 0x1000),
+      // x3000 LDI R1, #2; ADD R2, R2, #1; HALT; x0000
+      (0x3000, 0xA202),
+      (0x3001, 0x14A1),
+      (0x3002, 0xF025),
+    ] {
+      vm.memory.write(address, word);
+    }
+
+    vm.registers.set_psr(0x8000);
+    *vm.registers.reg_r(Register::R6) = 0xF000;
+    vm.pc_start = 0x3000;
+
+    vm.run(0).unwrap();
+    assert_eq!(vm.registers.get(Register::R1), 0);
+    assert_eq!(vm.registers.get(Register::R2), 0);
+    assert!(vm.registers.user_mode());
+    assert_eq!(vm.registers.get(Register::R6), 0xF000);
+  }
+
+  #[test]
+  fn test_privilege_violation_handler() {
+    let mut vm = Machine::new();
+
+    vm.registers.set_psr(0x8001);
+    *vm.registers.reg_r(Register::R6) = 0xF000;
+    *vm.registers.reg_r(Register::Pc) = 0x3001;
+    vm.memory.write(0x0100, 0x1000);
+
+    assert_eq!(vm.raise(Exception::PrivilegeViolation, 0x3000), Ok(()));
+
+    // entered on the supervisor stack with the user state saved
+    assert_eq!(vm.registers.get(Register::Pc), 0x1000);
+    assert!(!vm.registers.user_mode());
+    assert_eq!(vm.registers.get(Register::R6), 0x2FFE);
+    assert_eq!(vm.registers.get(Register::SavedUsp), 0xF000);
+    assert_eq!(vm.memory.read(0x2FFE), 0x3001);
+    assert_eq!(vm.memory.read(0x2FFF), 0x8001);
+  }
+}
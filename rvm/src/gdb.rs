@@ -0,0 +1,630 @@
+//! A stub for the GDB remote serial protocol, so gdb can drive a machine over
+//! TCP.
+//!
+//! gdb addresses memory in bytes, so word `n` is seen as bytes `2n` (low) and
+//! `2n + 1` (high), and the pc is reported as the byte address of the next
+//! instruction. Registers are sent little-endian, in the order of
+//! [`TARGET_XML`].
+
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use crate::error::VmError;
+use crate::exception::Exception;
+use crate::register::Register;
+use crate::step::StopReason;
+use crate::Machine;
+
+/// Steps taken between checks for an interrupt from gdb while continuing.
+const SLICE: u64 = 10_000;
+
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+
+/// Sent by gdb outside of a packet to interrupt a running target.
+const INTERRUPT: u8 = 0x03;
+
+/// Largest packet, as advertised to gdb. Memory is read at most half of it at
+/// a time, as each byte takes two hex digits.
+const PACKET_SIZE: usize = 0x1000;
+
+/// The registers gdb sees: r0-r7, then the pc as a byte address and the PSR.
+pub const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.rvm.lc3.core">
+    <reg name="r0" bitsize="16" type="int" regnum="0"/>
+    <reg name="r1" bitsize="16" type="int"/>
+    <reg name="r2" bitsize="16" type="int"/>
+    <reg name="r3" bitsize="16" type="int"/>
+    <reg name="r4" bitsize="16" type="int"/>
+    <reg name="r5" bitsize="16" type="int"/>
+    <reg name="r6" bitsize="16" type="data_ptr"/>
+    <reg name="r7" bitsize="16" type="int"/>
+    <reg name="pc" bitsize="32" type="code_ptr"/>
+    <reg name="psr" bitsize="16" type="int"/>
+  </feature>
+</target>
+"#;
+
+/// What the stub does with a packet from gdb.
+#[derive(Debug, PartialEq, Eq)]
+enum Action {
+  Reply(String),
+  Step,
+  Continue,
+  /// Replies and then ends the session
+  Detach,
+  /// Ends the session without a reply
+  Kill,
+}
+
+/// Accepts one gdb connection on `listener` and serves it until gdb detaches
+/// or disconnects.
+pub fn serve(vm: &mut Machine, listener: &TcpListener) -> io::Result<()> {
+  let (stream, _) = listener.accept()?;
+  stream.set_nodelay(true)?;
+
+  Stub {
+    stream,
+    pending: VecDeque::new(),
+  }
+  .run(vm)
+}
+
+struct Stub {
+  stream: TcpStream,
+  /// Bytes received while running that weren't an interrupt
+  pending: VecDeque<u8>,
+}
+
+impl Stub {
+  fn run(&mut self, vm: &mut Machine) -> io::Result<()> {
+    while let Some(packet) = self.read_packet()? {
+      match command(vm, &packet) {
+        Action::Reply(reply) => self.write_packet(&reply)?,
+        Action::Step => {
+          let reason = vm.run_for(1);
+          self.write_packet(&stop_reply(vm, reason))?;
+        }
+        Action::Continue => {
+          let reply = self.resume(vm)?;
+          self.write_packet(&reply)?;
+        }
+        Action::Detach => {
+          self.write_packet("OK")?;
+          break;
+        }
+        Action::Kill => break,
+      }
+    }
+
+    Ok(())
+  }
+
+  /// Runs until the machine stops on its own or gdb interrupts it.
+  fn resume(&mut self, vm: &mut Machine) -> io::Result<String> {
+    loop {
+      match vm.run_for(SLICE) {
+        StopReason::BudgetExhausted => {}
+        reason => return Ok(stop_reply(vm, reason)),
+      }
+
+      if self.interrupted()? {
+        return Ok(format!("S{SIGINT:02x}"));
+      }
+    }
+  }
+
+  /// Whether gdb sent an interrupt, without waiting for one.
+  fn interrupted(&mut self) -> io::Result<bool> {
+    self.stream.set_nonblocking(true)?;
+
+    let mut byte = [0];
+    let result = self.stream.read(&mut byte);
+
+    self.stream.set_nonblocking(false)?;
+
+    match result {
+      Ok(0) => Err(ErrorKind::UnexpectedEof.into()),
+      Ok(_) if byte[0] == INTERRUPT => Ok(true),
+      // the start of a packet, read once the machine stops
+      Ok(_) => {
+        self.pending.push_back(byte[0]);
+        Ok(false)
+      }
+      Err(err) if err.kind() == ErrorKind::WouldBlock => Ok(false),
+      Err(err) => Err(err),
+    }
+  }
+
+  fn read_byte(&mut self) -> io::Result<Option<u8>> {
+    if let Some(byte) = self.pending.pop_front() {
+      return Ok(Some(byte));
+    }
+
+    let mut byte = [0];
+
+    match self.stream.read(&mut byte)? {
+      0 => Ok(None),
+      _ => Ok(Some(byte[0])),
+    }
+  }
+
+  /// The next packet's data, acknowledging it. Returns `None` once gdb has
+  /// disconnected.
+  fn read_packet(&mut self) -> io::Result<Option<String>> {
+    loop {
+      // acknowledgements and interrupts while stopped are skipped
+      loop {
+        match self.read_byte()? {
+          Some(b'$') => break,
+          Some(_) => {}
+          None => return Ok(None),
+        }
+      }
+
+      let mut data = Vec::new();
+
+      loop {
+        match self.read_byte()? {
+          Some(b'#') => break,
+          Some(byte) => data.push(byte),
+          None => return Ok(None),
+        }
+      }
+
+      let (Some(high), Some(low)) = (self.read_byte()?, self.read_byte()?) else {
+        return Ok(None);
+      };
+
+      let expected = std::str::from_utf8(&[high, low])
+        .ok()
+        .and_then(|checksum| u8::from_str_radix(checksum, 16).ok());
+
+      if expected == Some(checksum(&data)) {
+        self.stream.write_all(b"+")?;
+
+        return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+      }
+
+      self.stream.write_all(b"-")?;
+    }
+  }
+
+  fn write_packet(&mut self, data: &str) -> io::Result<()> {
+    let packet = format!("${data}#{:02x}", checksum(data.as_bytes()));
+
+    self.stream.write_all(packet.as_bytes())
+  }
+}
+
+fn checksum(data: &[u8]) -> u8 {
+  data.iter().fold(0, |sum, &byte| sum.wrapping_add(byte))
+}
+
+/// The reply to `s`, `c` or `?`, a halted machine having exited.
+fn stop_reply(vm: &Machine, reason: StopReason) -> String {
+  if !vm.running() {
+    return "W00".to_string();
+  }
+
+  let signal = match reason {
+    StopReason::Error(VmError::Exception(Exception::AccessViolation(_))) => SIGSEGV,
+    StopReason::Error(_) => SIGILL,
+    _ => SIGTRAP,
+  };
+
+  format!("S{signal:02x}")
+}
+
+/// Handles every packet other than the framing, which is left to [`Stub`].
+fn command(vm: &mut Machine, packet: &str) -> Action {
+  let reply = match packet.as_bytes().first() {
+    Some(b'?') => stop_reply(vm, StopReason::Condition),
+    Some(b'g') => (0..REGISTERS).map(|n| read_register(vm, n)).collect(),
+    Some(b'G') => write_registers(vm, &packet[1..]),
+    Some(b'p') => parse_hex(&packet[1..])
+      .filter(|&n| n < REGISTERS)
+      .map(|n| read_register(vm, n))
+      .unwrap_or_else(error),
+    Some(b'P') => write_register(vm, &packet[1..]),
+    Some(b'm') => read_memory(vm, &packet[1..]),
+    Some(b'M') => write_memory(vm, &packet[1..]),
+    Some(b'Z' | b'z') => breakpoint(vm, packet),
+    Some(b's') => return Action::Step,
+    Some(b'c') => return Action::Continue,
+    Some(b'D') => return Action::Detach,
+    Some(b'k') => return Action::Kill,
+    Some(b'H') => "OK".to_string(),
+    _ => query(packet),
+  };
+
+  Action::Reply(reply)
+}
+
+fn query(packet: &str) -> String {
+  if packet.starts_with("qSupported") {
+    return format!("PacketSize={PACKET_SIZE:x};qXfer:features:read+");
+  }
+
+  if packet == "qAttached" {
+    return "1".to_string();
+  }
+
+  if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+    let Some((offset, length)) = parse_range(range) else {
+      return error();
+    };
+
+    let start = (offset as usize).min(TARGET_XML.len());
+    let end = start.saturating_add(length as usize).min(TARGET_XML.len());
+    let more = if end < TARGET_XML.len() { 'm' } else { 'l' };
+
+    return format!("{more}{}", &TARGET_XML[start..end]);
+  }
+
+  // unsupported packets get an empty reply
+  String::new()
+}
+
+const REGISTERS: u32 = 10;
+const PC: u32 = 8;
+const PSR: u32 = 9;
+
+fn error() -> String {
+  "E01".to_string()
+}
+
+fn parse_hex(text: &str) -> Option<u32> {
+  u32::from_str_radix(text, 16).ok()
+}
+
+/// `addr,length`, as used by memory and target description packets.
+fn parse_range(text: &str) -> Option<(u32, u32)> {
+  let (address, length) = text.split_once(',')?;
+
+  Some((parse_hex(address)?, parse_hex(length)?))
+}
+
+/// Little-endian hex of the low `bytes` bytes of `value`.
+fn encode(value: u32, bytes: usize) -> String {
+  value.to_le_bytes()[..bytes]
+    .iter()
+    .map(|byte| format!("{byte:02x}"))
+    .collect()
+}
+
+fn decode(text: &str) -> Option<Vec<u8>> {
+  if !text.len().is_multiple_of(2) {
+    return None;
+  }
+
+  (0..text.len())
+    .step_by(2)
+    .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+    .collect()
+}
+
+fn register_size(n: u32) -> usize {
+  if n == PC {
+    4
+  } else {
+    2
+  }
+}
+
+fn read_register(vm: &Machine, n: u32) -> String {
+  let value = match n {
+    PC => u32::from(vm.registers.get(Register::Pc)) * 2,
+    PSR => u32::from(vm.registers.psr()),
+    r => u32::from(vm.registers.get(Register::general(r as u16))),
+  };
+
+  encode(value, register_size(n))
+}
+
+fn set_register(vm: &mut Machine, n: u32, bytes: &[u8]) {
+  let mut value = [0; 4];
+  value[..bytes.len()].copy_from_slice(bytes);
+  let value = u32::from_le_bytes(value);
+
+  match n {
+    PC => *vm.registers.reg_r(Register::Pc) = (value / 2) as u16,
+    PSR => vm.registers.set_psr(value as u16),
+    r => *vm.registers.reg_r(Register::general(r as u16)) = value as u16,
+  }
+}
+
+fn write_registers(vm: &mut Machine, data: &str) -> String {
+  let Some(mut bytes) = decode(data) else {
+    return error();
+  };
+
+  let size: usize = (0..REGISTERS).map(register_size).sum();
+
+  if bytes.len() != size {
+    return error();
+  }
+
+  for n in 0..REGISTERS {
+    let rest = bytes.split_off(register_size(n));
+    set_register(vm, n, &bytes);
+    bytes = rest;
+  }
+
+  "OK".to_string()
+}
+
+fn write_register(vm: &mut Machine, data: &str) -> String {
+  let register = data.split_once('=').and_then(|(n, value)| {
+    let n = parse_hex(n).filter(|&n| n < REGISTERS)?;
+
+    Some((
+      n,
+      decode(value).filter(|bytes| bytes.len() == register_size(n))?,
+    ))
+  });
+
+  match register {
+    Some((n, bytes)) => {
+      set_register(vm, n, &bytes);
+      "OK".to_string()
+    }
+    None => error(),
+  }
+}
+
+fn read_memory(vm: &mut Machine, data: &str) -> String {
+  let Some((address, length)) = parse_range(data) else {
+    return error();
+  };
+
+  // gdb asks for the rest when the reply is short
+  let length = length.min(PACKET_SIZE as u32 / 2);
+
+  let mut out = String::new();
+  let mut word = None;
+
+  for byte in address..address.saturating_add(length) {
+    let index = (byte / 2) as u16;
+
+    // each word is read once, for devices that change when read
+    let value = match word {
+      Some((cached, value)) if cached == index => value,
+      _ => vm.memory.read(index),
+    };
+    word = Some((index, value));
+
+    out.push_str(&format!("{:02x}", value.to_le_bytes()[byte as usize % 2]));
+  }
+
+  out
+}
+
+fn write_memory(vm: &mut Machine, data: &str) -> String {
+  let Some((range, data)) = data.split_once(':') else {
+    return error();
+  };
+
+  let (Some((address, length)), Some(bytes)) = (parse_range(range), decode(data)) else {
+    return error();
+  };
+
+  if bytes.len() != length as usize {
+    return error();
+  }
+
+  for (byte, value) in (address..).zip(bytes) {
+    let index = (byte / 2) as u16;
+    let mut word = vm.memory.read(index).to_le_bytes();
+    word[byte as usize % 2] = value;
+
+    vm.memory.write(index, u16::from_le_bytes(word));
+  }
+
+  "OK".to_string()
+}
+
+/// `Z0,addr,kind` and `z0,addr,kind`, software breakpoints. Hardware
+/// breakpoints are treated the same.
+fn breakpoint(vm: &mut Machine, packet: &str) -> String {
+  let fields: Vec<&str> = packet[1..].split(',').collect();
+
+  let ["0" | "1", address, _kind] = fields.as_slice() else {
+    return String::new();
+  };
+
+  let Some(address) = parse_hex(address) else {
+    return error();
+  };
+
+  let address = (address / 2) as u16;
+
+  if packet.starts_with('Z') {
+    vm.breakpoints.insert(address);
+  } else {
+    vm.breakpoints.remove(&address);
+  }
+
+  "OK".to_string()
+}
+
+#[cfg(test)]
+mod tests {
+  use std::thread;
+
+  use super::*;
+
+  fn reply(vm: &mut Machine, packet: &str) -> String {
+    match command(vm, packet) {
+      Action::Reply(reply) => reply,
+      action => panic!("expected a reply, got {action:?}"),
+    }
+  }
+
+  #[test]
+  fn test_registers() {
+    let mut vm = Machine::with_words(0x3000, &[]);
+    *vm.registers.reg_r(Register::R1) = 0x1234;
+
+    let registers = format!("00003412{}00300000006000000000", "0000".repeat(4));
+    assert_eq!(reply(&mut vm, "g"), registers);
+    assert_eq!(reply(&mut vm, "p8"), "00600000");
+
+    assert_eq!(reply(&mut vm, "P2=cdab"), "OK");
+    assert_eq!(vm.registers.get(Register::R2), 0xABCD);
+
+    assert_eq!(reply(&mut vm, "P9=0480"), "OK");
+    assert!(vm.registers.user_mode());
+    assert_eq!(vm.registers.get(Register::Cond), 4);
+
+    let registers = "01000200030004000500060007000800026000000200";
+    assert_eq!(reply(&mut vm, &format!("G{registers}")), "OK");
+    assert_eq!(vm.registers.get(Register::R7), 8);
+    assert_eq!(vm.registers.get(Register::Pc), 0x3001);
+    assert_eq!(reply(&mut vm, "g"), registers);
+
+    assert_eq!(reply(&mut vm, "pa"), "E01");
+    assert_eq!(reply(&mut vm, "G00"), "E01");
+  }
+
+  #[test]
+  fn test_memory() {
+    let mut vm = Machine::with_words(0x3000, &[0x1234, 0xABCD]);
+
+    assert_eq!(reply(&mut vm, "m6000,4"), "3412cdab");
+    assert_eq!(reply(&mut vm, "m6001,2"), "12cd");
+
+    assert_eq!(reply(&mut vm, "M6001,2:ffee"), "OK");
+    assert_eq!(vm.memory.read(0x3000), 0xFF34);
+    assert_eq!(vm.memory.read(0x3001), 0xABEE);
+
+    assert_eq!(reply(&mut vm, "M6000,2:ff"), "E01");
+
+    assert_eq!(reply(&mut vm, "m0,ffffffff").len(), PACKET_SIZE);
+  }
+
+  #[test]
+  fn test_breakpoints_and_queries() {
+    let mut vm = Machine::with_words(0x3000, &[]);
+
+    assert_eq!(reply(&mut vm, "Z0,6004,2"), "OK");
+    assert!(vm.breakpoints.contains(&0x3002));
+    assert_eq!(reply(&mut vm, "z0,6004,2"), "OK");
+    assert!(vm.breakpoints.is_empty());
+    assert_eq!(reply(&mut vm, "Z2,6004,2"), "");
+
+    assert!(reply(&mut vm, "qSupported:swbreak+").contains("qXfer:features:read+"));
+    assert_eq!(
+      reply(&mut vm, "qXfer:features:read:target.xml:0,5"),
+      "m<?xml"
+    );
+    assert!(reply(&mut vm, "qXfer:features:read:target.xml:10,1000").starts_with('l'));
+    assert_eq!(reply(&mut vm, "vMustReplyEmpty"), "");
+    assert_eq!(command(&mut vm, "c"), Action::Continue);
+  }
+
+  fn send(stream: &mut TcpStream, packet: &str) {
+    let packet = format!("${packet}#{:02x}", checksum(packet.as_bytes()));
+    stream.write_all(packet.as_bytes()).unwrap();
+  }
+
+  /// Sends `packet` and returns the reply's data, skipping acknowledgements.
+  fn exchange(stream: &mut TcpStream, packet: &str) -> String {
+    send(stream, packet);
+    receive(stream)
+  }
+
+  /// The next reply's data, acknowledging it.
+  fn receive(stream: &mut TcpStream) -> String {
+    let mut reply = Vec::new();
+    let mut byte = [0];
+
+    loop {
+      stream.read_exact(&mut byte).unwrap();
+
+      match byte[0] {
+        b'+' if reply.is_empty() => {}
+        b'#' => break,
+        b'$' => {}
+        c => reply.push(c),
+      }
+    }
+
+    let mut checksum = [0; 2];
+    stream.read_exact(&mut checksum).unwrap();
+    stream.write_all(b"+").unwrap();
+
+    String::from_utf8(reply).unwrap()
+  }
+
+  #[test]
+  fn test_session() {
+    // add r0, r0, #1 three times, then halt
+    let mut vm = Machine::with_words(0x3000, &[0x1021, 0x1021, 0x1021, 0xF025]);
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+
+    let client = thread::spawn(move || {
+      let mut stream = TcpStream::connect(address).unwrap();
+
+      let replies = [
+        exchange(&mut stream, "?"),
+        exchange(&mut stream, "Z0,6004,2"),
+        exchange(&mut stream, "s"),
+        exchange(&mut stream, "c"),
+        exchange(&mut stream, "p0"),
+        exchange(&mut stream, "c"),
+      ];
+
+      // a corrupted packet is asked for again
+      stream.write_all(b"$g#00").unwrap();
+      let mut nack = [0];
+      stream.read_exact(&mut nack).unwrap();
+
+      let detached = exchange(&mut stream, "D");
+
+      (replies, nack[0], detached)
+    });
+
+    serve(&mut vm, &listener).unwrap();
+
+    let (replies, nack, detached) = client.join().unwrap();
+    assert_eq!(replies, ["S05", "OK", "S05", "S05", "0200", "W00"]);
+    assert_eq!(nack, b'-');
+    assert_eq!(detached, "OK");
+    assert_eq!(vm.registers.get(Register::R0), 3);
+  }
+
+  #[test]
+  fn test_interrupt() {
+    // brnzp #-1
+    let mut vm = Machine::with_words(0x3000, &[0x0FFF]);
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+
+    let client = thread::spawn(move || {
+      let mut stream = TcpStream::connect(address).unwrap();
+
+      // a packet sent while running is answered after the interrupt
+      send(&mut stream, "c");
+      send(&mut stream, "p0");
+      stream.write_all(&[INTERRUPT]).unwrap();
+
+      let replies = [receive(&mut stream), receive(&mut stream)];
+      send(&mut stream, "k");
+
+      replies
+    });
+
+    serve(&mut vm, &listener).unwrap();
+
+    assert_eq!(client.join().unwrap(), ["S02", "0000"]);
+  }
+}
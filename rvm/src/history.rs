@@ -0,0 +1,280 @@
+use std::collections::VecDeque;
+use std::ops::RangeInclusive;
+
+use crate::register::Registers;
+use crate::Machine;
+
+/// What a step changed, enough to undo it.
+struct Entry {
+  registers: Registers,
+  /// `KBDR`, `KBSR`, `DSR` and `MCR`
+  devices: [u16; 4],
+  /// Addresses and previous values of the memory cells written, in order
+  writes: Vec<(u16, u16)>,
+  /// Characters the step read from the keyboard
+  input: Vec<u8>,
+}
+
+/// An undo log of the last steps taken.
+///
+/// Only memory cells and the standard devices are restored, devices attached
+/// with [`attach`](crate::memory::Memory::attach) keep their state and output
+/// already printed stays printed.
+pub struct History {
+  entries: VecDeque<Entry>,
+  /// Steps kept at most
+  budget: usize,
+  /// Steps taken since recording started
+  steps: u64,
+  /// The step being taken
+  current: Option<Entry>,
+}
+
+impl Machine {
+  /// Keeps an undo log of the last `budget` steps, to step back through.
+  ///
+  /// Steps are counted from 0 when recording starts.
+  pub fn record_history(&mut self, budget: usize) {
+    self.history = Some(History {
+      entries: VecDeque::new(),
+      budget,
+      steps: 0,
+      current: None,
+    });
+  }
+
+  /// Stops recording, dropping the undo log.
+  pub fn stop_history(&mut self) {
+    self.history = None;
+  }
+
+  /// Forgets the steps logged so far, recording on from step 0.
+  pub(crate) fn clear_history(&mut self) {
+    if let Some(history) = &mut self.history {
+      history.entries.clear();
+      history.steps = 0;
+    }
+  }
+
+  /// The step counts that can be rewound to, the last being the current one.
+  pub fn history_range(&self) -> Option<RangeInclusive<u64>> {
+    let history = self.history.as_ref()?;
+
+    Some(history.steps - history.entries.len() as u64..=history.steps)
+  }
+
+  /// Undoes the last step, returning whether there was one in the log.
+  ///
+  /// Input the step read is put back on the keyboard, so stepping forward again
+  /// reads it again.
+  pub fn step_back(&mut self) -> bool {
+    let Some(history) = &mut self.history else {
+      return false;
+    };
+
+    let Some(entry) = history.entries.pop_back() else {
+      return false;
+    };
+
+    history.steps -= 1;
+
+    for &(address, value) in entry.writes.iter().rev() {
+      self.memory.write(address, value);
+    }
+
+    let [data, status, display, mcr] = entry.devices;
+
+    self.registers = entry.registers;
+    self.memory.keyboard.data = data;
+    self.memory.keyboard.status = status;
+    self.memory.display.status = display;
+    self.memory.control.mcr = mcr;
+    self.memory.keyboard.unread(&entry.input);
+
+    true
+  }
+
+  /// Steps back to the step count `step`, returning whether it is still in
+  /// the log. Nothing is undone when it isn't.
+  pub fn rewind_to(&mut self, step: u64) -> bool {
+    let Some(range) = self.history_range() else {
+      return false;
+    };
+
+    if !range.contains(&step) {
+      return false;
+    }
+
+    for _ in step..*range.end() {
+      self.step_back();
+    }
+
+    true
+  }
+
+  /// Steps back to just before the last step that wrote the memory cell at
+  /// `address`, returning whether one is in the log. Nothing is undone when
+  /// there isn't.
+  pub fn run_back_to_write(&mut self, address: u16) -> bool {
+    let Some(history) = &self.history else {
+      return false;
+    };
+
+    let found = history
+      .entries
+      .iter()
+      .rposition(|entry| entry.writes.iter().any(|&(at, _)| at == address));
+
+    match found {
+      Some(i) => self.rewind_to(history.steps - (history.entries.len() - i) as u64),
+      None => false,
+    }
+  }
+
+  /// Called before taking a step.
+  pub(crate) fn history_begin(&mut self) {
+    let Some(history) = &mut self.history else {
+      return;
+    };
+
+    let memory = &mut self.memory;
+
+    history.current = Some(Entry {
+      registers: self.registers.clone(),
+      devices: [
+        memory.keyboard.data,
+        memory.keyboard.status,
+        memory.display.status,
+        memory.control.mcr,
+      ],
+      writes: Vec::new(),
+      input: Vec::new(),
+    });
+
+    memory.journal = Some(Vec::new());
+    memory.keyboard.taken = Some(Vec::new());
+  }
+
+  /// Called after taking a step, logging it when `taken`.
+  ///
+  /// A step that failed isn't logged, whatever it changed before failing is
+  /// kept.
+  pub(crate) fn history_end(&mut self, taken: bool) {
+    let writes = self.memory.journal.take();
+    let input = self.memory.keyboard.taken.take();
+
+    let Some(history) = &mut self.history else {
+      return;
+    };
+
+    let Some(mut entry) = history.current.take().filter(|_| taken) else {
+      return;
+    };
+
+    entry.writes = writes.unwrap_or_default();
+    entry.input = input.unwrap_or_default();
+
+    history.entries.push_back(entry);
+    history.steps += 1;
+
+    while history.entries.len() > history.budget {
+      history.entries.pop_front();
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::register::Register;
+
+  /// A machine with `words` at `x3000`, recording its history.
+  fn machine(words: &[u16]) -> Machine {
+    let mut vm = Machine::with_words(0x3000, words);
+    vm.record_history(100);
+    vm
+  }
+
+  #[test]
+  fn test_step_back() {
+    // ADD R0, R0, #1; ST R0, #2; BRnzp #-3; x0000
+    let mut vm = machine(&[0x1021, 0x3002, 0x0FFD, 0x0000]);
+
+    vm.run_for(5);
+    assert_eq!(vm.history_range(), Some(0..=5));
+    assert_eq!(vm.registers.get(Register::R0), 2);
+    assert_eq!(vm.memory.read(0x3004), 2);
+
+    assert!(vm.step_back());
+    assert!(vm.step_back());
+    assert_eq!(vm.registers.get(Register::Pc), 0x3000);
+    assert_eq!(vm.memory.read(0x3004), 1);
+    assert_eq!(vm.history_range(), Some(0..=3));
+
+    assert!(!vm.rewind_to(4));
+    assert!(vm.rewind_to(0));
+    assert_eq!(vm.registers.get(Register::Pc), 0x3000);
+    assert_eq!(vm.registers.get(Register::R0), 0);
+    assert_eq!(vm.memory.read(0x3004), 0);
+    assert!(!vm.step_back());
+
+    let mut vm = machine(&[0x1021]);
+    vm.stop_history();
+    vm.step().unwrap();
+    assert_eq!(vm.history_range(), None);
+    assert!(!vm.step_back());
+  }
+
+  #[test]
+  fn test_budget() {
+    // ADD R0, R0, #1; BRnzp #-2
+    let mut vm = machine(&[0x1021, 0x0FFE]);
+    vm.record_history(3);
+
+    vm.run_for(10);
+    assert_eq!(vm.history_range(), Some(7..=10));
+    assert!(!vm.rewind_to(6));
+    assert!(vm.rewind_to(7));
+    assert_eq!(vm.registers.get(Register::R0), 4);
+  }
+
+  #[test]
+  fn test_input_is_replayed() {
+    // GETC; ST R0, #3; GETC; HALT
+    let mut vm = machine(&[0xF020, 0x3003, 0xF020, 0xF025]);
+    vm.memory.keyboard.push(b"ab");
+
+    vm.run_for(10);
+    assert!(!vm.running());
+    assert_eq!(vm.memory.read(0x3005), 'a' as u16);
+    assert!(!vm.memory.keyboard.ready());
+
+    // halting is undone along with the rest
+    assert!(vm.rewind_to(1));
+    assert!(vm.running());
+    assert_eq!(vm.memory.read(0x3005), 0);
+
+    vm.run_for(10);
+    assert_eq!(vm.registers.get(Register::R0), 'b' as u16);
+    assert_eq!(vm.memory.read(0x3005), 'a' as u16);
+    assert_eq!(vm.history_range(), Some(0..=4));
+  }
+
+  #[test]
+  fn test_run_back_to_write() {
+    // ST R0, #5; ADD R0, R0, #1; ST R0, #2; ADD R0, R0, #1; HALT
+    let mut vm = machine(&[0x3005, 0x1021, 0x3002, 0x1021, 0xF025]);
+
+    vm.run_for(10);
+    assert!(!vm.run_back_to_write(0x3010));
+    assert_eq!(vm.history_range(), Some(0..=5));
+
+    assert!(vm.run_back_to_write(0x3005));
+    assert_eq!(vm.registers.get(Register::Pc), 0x3002);
+    assert_eq!(vm.memory.read(0x3005), 0);
+
+    assert!(vm.run_back_to_write(0x3006));
+    assert_eq!(vm.registers.get(Register::Pc), 0x3000);
+    assert_eq!(vm.history_range(), Some(0..=0));
+  }
+}
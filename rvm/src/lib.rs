@@ -1,54 +1,397 @@
-use std::fs::File;
-use std::io::Read;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
 
-use memory::read;
-use ops::op;
-use register::{reg_r, Register};
+use coverage::Coverage;
+use device::Interrupt;
+use error::VmError;
+use history::History;
+use memory::Memory;
+use profile::Profile;
+use register::{Register, Registers, PSR_PRIORITY, PSR_USER, SSP_START, USP_START};
+use trace::{Access, Tracer};
+use trap::TrapHandler;
 
+mod binary;
+pub mod coverage;
+pub mod debugger;
+pub mod device;
+pub mod error;
+pub mod exception;
+pub mod gdb;
+pub mod history;
 pub mod memory;
 pub mod ops;
+pub mod os;
+pub mod profile;
 pub mod register;
+pub mod snapshot;
+pub mod step;
+pub mod trace;
 pub mod trap;
 
-pub static mut RUNNING: bool = true;
-pub static mut PC: u16 = 0;
-pub static mut PC_START: u16 = 0;
-// pub const PC_START: u16 = 0x3000;
+/// Start of the table holding the addresses of interrupt and exception
+/// handlers.
+pub const INTERRUPT_VECTOR_TABLE: u16 = 0x0100;
 
-pub fn load_image(name: &str, offset: u16) {
-  let mut file = File::open(name).unwrap();
-  let mut buffer = Vec::new();
+/// A single LC-3 virtual machine, owning its memory, registers and run state.
+#[derive(Default)]
+pub struct Machine {
+  pub memory: Memory,
+  pub registers: Registers,
+  pub pc_start: u16,
+  /// Runs the trap routines in Rust rather than through the trap vector table,
+  /// for vectors that have one.
+  pub native_traps: bool,
+  trap_handlers: BTreeMap<u8, Box<dyn TrapHandler>>,
+  /// Addresses [`run_for`](Machine::run_for) and
+  /// [`run_until`](Machine::run_until) stop at, before executing them.
+  pub breakpoints: BTreeSet<u16>,
+  tracer: Option<Tracer>,
+  history: Option<History>,
+  profile: Option<Profile>,
+  coverage: Option<Coverage>,
+}
+
+impl Machine {
+  /// A machine with the operating system loaded.
+  pub fn new() -> Self {
+    let mut vm = Self::default();
+    vm.load_os();
+    vm
+  }
+
+  /// Whether the clock is enabled, as set by bit 15 of `MCR`.
+  pub fn running(&self) -> bool {
+    self.memory.control.running()
+  }
+
+  /// Loads an image file, see [`load_bytes`](Machine::load_bytes).
+  pub fn load_image(&mut self, name: &str, offset: u16) -> Result<(), VmError> {
+    let image = fs::read(name)?;
 
-  file.read_to_end(&mut buffer).unwrap();
+    self.load_bytes(&image, offset)
+  }
+
+  /// Loads an image of a big-endian origin followed by its words, moved
+  /// `offset` words past that origin.
+  pub fn load_bytes(&mut self, image: &[u8], offset: u16) -> Result<(), VmError> {
+    let [high, low, words @ ..] = image else {
+      return Err(VmError::MalformedImage("missing origin header".to_string()));
+    };
+
+    let start = u16::from_be_bytes([*high, *low]).wrapping_add(offset);
 
-  let header = u16::from_be_bytes([buffer[0], buffer[1]]);
+    self.memory.load(words, start)?;
+    self.pc_start = start;
 
-  let buffer = &buffer[2..];
+    Ok(())
+  }
+
+  /// Enters the handler at `vector` in the interrupt vector table.
+  ///
+  /// The PSR and PC are pushed on the supervisor stack, switching to it first
+  /// when coming from user mode. The priority is only changed for interrupts.
+  pub fn enter_handler(&mut self, vector: u8, priority: Option<u16>) {
+    self.enter_supervisor(priority);
 
-  // let start = PC_START + offset;
-  let start = unsafe {
-    PC_START = offset + header;
+    *self.registers.reg_r(Register::Pc) = self
+      .memory
+      .read(INTERRUPT_VECTOR_TABLE.wrapping_add(u16::from(vector)));
+  }
 
-    PC_START
-  };
+  /// Switches to supervisor mode, pushing the PSR and PC on the supervisor
+  /// stack for RTI to return with.
+  pub(crate) fn enter_supervisor(&mut self, priority: Option<u16>) {
+    let psr = self.registers.psr();
+
+    if self.registers.user_mode() {
+      *self.registers.reg_r(Register::SavedUsp) = self.registers.get(Register::R6);
+      *self.registers.reg_r(Register::R6) = self.registers.get(Register::SavedSsp);
+    }
+
+    let mut new_psr = psr & !PSR_USER;
+
+    if let Some(priority) = priority {
+      new_psr = (new_psr & !PSR_PRIORITY) | (priority << 8 & PSR_PRIORITY);
+    }
+
+    self.registers.set_psr(new_psr);
+
+    let pc = self.registers.get(Register::Pc);
+    self.push(psr);
+    self.push(pc);
+  }
+
+  /// The highest priority pending interrupt, if it is above the current
+  /// priority.
+  fn pending_interrupt(&mut self) -> Option<Interrupt> {
+    let interrupt = self.memory.interrupt()?;
+    let priority = (self.registers.psr() & PSR_PRIORITY) >> 8;
+
+    (interrupt.priority > priority).then_some(interrupt)
+  }
 
-  memory::load(buffer, start);
+  /// Pushes onto the stack pointed to by R6.
+  pub fn push(&mut self, value: u16) {
+    let sp = self.registers.get(Register::R6).wrapping_sub(1);
+
+    *self.registers.reg_r(Register::R6) = sp;
+    self.memory.write(sp, value);
+    self.trace_access(Access::Write { address: sp, value });
+  }
+
+  /// Pops from the stack pointed to by R6.
+  pub fn pop(&mut self) -> u16 {
+    let sp = self.registers.get(Register::R6);
+
+    *self.registers.reg_r(Register::R6) = sp.wrapping_add(1);
+
+    let value = self.memory.read(sp);
+    self.trace_access(Access::Read { address: sp, value });
+
+    value
+  }
+
+  /// Drops to user mode on the user stack, as a loaded program is started, with
+  /// the supervisor stack left for traps and handlers to switch to.
+  pub fn enter_user_mode(&mut self) {
+    // priority 0, with the z flag set
+    self.registers.set_psr(PSR_USER | 0x0002);
+    *self.registers.reg_r(Register::R6) = USP_START;
+    *self.registers.reg_r(Register::SavedSsp) = SSP_START;
+  }
+
+  /// Points the pc `offset` words past the start of the loaded image.
+  pub fn reset_pc(&mut self, offset: u16) {
+    *self.registers.reg_r(Register::Pc) = self.pc_start.wrapping_add(offset);
+  }
+
+  /// Runs from `offset` words past the start of the loaded image until halted.
+  ///
+  /// The pc wraps around from `xFFFF` to `x0000`, as every address is valid.
+  ///
+  /// Stops early with any exception that has no handler in the vector table,
+  /// or a failing trap handler, leaving the pc on the instruction at fault.
+  pub fn run(&mut self, offset: u16) -> Result<(), VmError> {
+    if !self.running() {
+      return Err(VmError::Halted);
+    }
+
+    self.reset_pc(offset);
+    self.resume()
+  }
+
+  /// Runs from the current pc until halted, as [`run`](Machine::run) does.
+  pub fn resume(&mut self) -> Result<(), VmError> {
+    if !self.running() {
+      return Err(VmError::Halted);
+    }
+
+    while self.running() {
+      self.step()?;
+    }
+
+    Ok(())
+  }
 }
 
-pub fn run(offset: u16) {
-  let start = unsafe { PC_START };
-  assert!(offset < u16::MAX - start);
+#[cfg(test)]
+impl Machine {
+  /// A machine with `words` at `origin`, ready to run them with native traps.
+  pub(crate) fn with_words(origin: u16, words: &[u16]) -> Self {
+    let mut vm = Self::new();
+    vm.native_traps = true;
 
-  *reg_r(Register::Pc) = start + offset;
+    for (i, &word) in words.iter().enumerate() {
+      vm.memory.write(origin.wrapping_add(i as u16), word);
+    }
 
-  unsafe {
-    while RUNNING {
-      PC = *reg_r(Register::Pc);
+    vm.pc_start = origin;
+    vm.reset_pc(0);
+    vm
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_machines_are_independent() {
+    let mut a = Machine::new();
+    let mut b = Machine::new();
+
+    // ADD R0, R0, #5; HALT
+    a.memory.write(0x3000, 0x1025);
+    a.memory.write(0x3001, 0xF025);
+    a.pc_start = 0x3000;
+
+    b.memory.write(0x3000, 0xF025);
+    b.pc_start = 0x3000;
+
+    a.run(0).unwrap();
+    b.run(0).unwrap();
+
+    assert_eq!(a.registers.get(Register::R0), 5);
+    assert_eq!(b.registers.get(Register::R0), 0);
+    assert_eq!(b.memory.read(0x3001), 0);
+    assert!(!a.running());
+    assert!(!b.running());
+  }
+
+  #[test]
+  fn test_clearing_mcr_halts() {
+    let mut vm = Machine::new();
+
+    // LD R0, MASK; STI R0, MCR_ADDR; ADD R1, R1, #1; MASK x7FFF; MCR_ADDR xFFFE
+    vm.memory.write(0x3000, 0x2003);
+    vm.memory.write(0x3001, 0xB003);
+    vm.memory.write(0x3002, 0x1261);
+    vm.memory.write(0x3003, 0x0000);
+    vm.memory.write(0x3004, 0x7FFF);
+    vm.memory.write(0x3005, 0xFFFE);
+    vm.pc_start = 0x3000;
+
+    vm.run(0).unwrap();
+
+    assert!(!vm.running());
+    assert_eq!(vm.registers.get(Register::R1), 0);
+    assert_eq!(vm.registers.get(Register::Pc), 0x3002);
+  }
+
+  #[test]
+  fn test_pc_wraps_around() {
+    let mut vm = Machine::new();
+    vm.native_traps = true;
+
+    // ADD R0, R0, #1 at the last and first addresses, then HALT
+    vm.memory.write(0xFFFF, 0x1021);
+    vm.memory.write(0x0000, 0x1021);
+    vm.memory.write(0x0001, 0xF025);
+    vm.pc_start = 0xFFFF;
+
+    vm.run(0).unwrap();
+
+    assert_eq!(vm.registers.get(Register::R0), 2);
+    assert_eq!(vm.registers.get(Register::Pc), 0x0002);
+  }
+
+  #[test]
+  fn test_run_offset_wraps_around() {
+    let mut vm = Machine::new();
+    vm.native_traps = true;
+
+    vm.memory.write(0x0001, 0xF025);
+    vm.pc_start = 0xFFFF;
+
+    vm.run(2).unwrap();
 
-      let i = read(PC);
-      *reg_r(Register::Pc) = PC + 1;
+    assert_eq!(vm.registers.get(Register::Pc), 0x0002);
+  }
+
+  #[test]
+  fn test_enter_handler_sets_priority() {
+    let mut vm = Machine::new();
+
+    *vm.registers.reg_r(Register::Pc) = 0x3001;
+    *vm.registers.reg_r(Register::R6) = 0x2000;
+    vm.registers.set_psr(0x0102);
+    vm.memory.write(0x0180, 0x1000);
+
+    // already in supervisor mode, so the stack isn't switched
+    vm.enter_handler(0x80, Some(4));
+
+    assert_eq!(vm.registers.get(Register::Pc), 0x1000);
+    assert_eq!(vm.registers.psr(), 0x0402);
+    assert_eq!(vm.registers.get(Register::R6), 0x1FFE);
+    assert_eq!(vm.memory.read(0x1FFE), 0x3001);
+    assert_eq!(vm.memory.read(0x1FFF), 0x0102);
+  }
 
-      op(i);
+  #[test]
+  fn test_keyboard_interrupt() {
+    let mut vm = Machine::new();
+    vm.native_traps = true;
+
+    // spin until the keyboard handler stores a flag
+    // x3000 LDI R1, FLAG_ADDR; BRz x3000; HALT
+    for (address, word) in [
+      (0x3000, 0xA202),
+      (0x3001, 0x05FE),
+      (0x3002, 0xF025),
+      (0x3003, 0x3100),
+      // x1000 LDI R2, KBDR_ADDR; STI R2, FLAG_ADDR; RTI
+      (0x1000, 0xA403),
+      (0x1001, 0xB403),
+      (0x1002, 0x8000),
+      (0x1004, 0xFE02),
+      (0x1005, 0x3100),
+      (0x0180, 0x1000),
+    ] {
+      vm.memory.write(address, word);
     }
+
+    vm.memory.keyboard.push(b"k");
+    vm.memory.write(device::KBSR, 0x4000);
+    *vm.registers.reg_r(Register::R6) = 0x3000;
+    vm.registers.set_psr(0x8002);
+    vm.pc_start = 0x3000;
+
+    vm.run(0).unwrap();
+
+    assert_eq!(vm.memory.read(0x3100), 'k' as u16);
+    assert_eq!(vm.registers.get(Register::R1), 'k' as u16);
+    assert!(vm.registers.user_mode());
+    assert_eq!(vm.registers.get(Register::R6), 0x3000);
+  }
+
+  #[test]
+  fn test_interrupt_masked_by_priority() {
+    let mut vm = Machine::new();
+    vm.native_traps = true;
+
+    vm.memory.keyboard.push(b"k");
+    vm.memory.write(device::KBSR, 0x4000);
+    vm.registers.set_psr(0x0400);
+    vm.memory.write(0x3000, 0xF025);
+    vm.pc_start = 0x3000;
+
+    vm.run(0).unwrap();
+
+    assert_eq!(vm.registers.get(Register::Pc), 0x3001);
+    assert!(vm.memory.keyboard.ready());
+  }
+
+  #[test]
+  fn test_load_bytes() {
+    let mut vm = Machine::new();
+
+    vm.load_bytes(&[0x40, 0x00, 0x12, 0x34], 0x10).unwrap();
+    assert_eq!(vm.pc_start, 0x4010);
+    assert_eq!(vm.memory.read(0x4010), 0x1234);
+
+    assert!(matches!(
+      vm.load_bytes(&[0x40], 0),
+      Err(VmError::MalformedImage(_))
+    ));
+    assert!(matches!(
+      vm.load_bytes(&[0xFF, 0xFF, 0x12, 0x34, 0x56, 0x78], 0),
+      Err(VmError::MalformedImage(_))
+    ));
+    assert!(matches!(
+      vm.load_image("does/not/exist.obj", 0),
+      Err(VmError::Io(_))
+    ));
+  }
+
+  #[test]
+  fn test_run_halted() {
+    let mut vm = Machine::new();
+    vm.native_traps = true;
+    vm.memory.write(0x3000, 0xF025);
+    vm.pc_start = 0x3000;
+
+    vm.run(0).unwrap();
+    assert!(matches!(vm.run(0), Err(VmError::Halted)));
   }
 }
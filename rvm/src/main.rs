@@ -1,12 +1,300 @@
+use std::collections::BTreeMap;
 use std::env::args;
+use std::fs::{read_to_string, File};
+use std::io::{stdout, BufReader, BufWriter};
+use std::net::TcpListener;
+use std::path::Path;
+use std::process::exit;
 
-use rvm::{load_image, run};
+use rvm::debugger::{parse_number, parse_symbols, Debugger};
+use rvm::device::Keyboard;
+use rvm::error::VmError;
+use rvm::register::Register;
+use rvm::trace::{self, TraceFilter};
+use rvm::{gdb, Machine};
+
+/// Steps the debugger can go back through unless `--history` says otherwise.
+const DEBUG_HISTORY: usize = 100_000;
+
+fn usage() -> ! {
+  println!(
+    "Usage: rvm [--native-traps] [--debug | --gdb <port>] [--history <steps>] \
+     [--trace <file>] [--trace-range <from>-<to>] [--trace-ops <op,...>] \
+     [--profile <report>] [--folded <file>] [--coverage <file>] [--restore] \
+     <image or snapshot>"
+  );
+  println!("       rvm --render-trace <trace>");
+  exit(1);
+}
+
+/// Labels written by the assembler next to the image, if there are any.
+///
+/// A symbol file that doesn't parse stops the debugger when `required`, and is
+/// otherwise skipped with a warning.
+fn load_symbols(file: &str, required: bool) -> BTreeMap<String, u16> {
+  let symbol_file = Path::new(file).with_extension("sym");
+
+  let Ok(text) = read_to_string(&symbol_file) else {
+    return BTreeMap::new();
+  };
+
+  parse_symbols(&text).unwrap_or_else(|err| {
+    eprintln!("failed to load `{}`: {err}", symbol_file.display());
+
+    if required {
+      exit(1);
+    }
+
+    BTreeMap::new()
+  })
+}
+
+/// Loads an image to run from its origin in user mode, or a snapshot to carry
+/// on where it was saved.
+fn load(vm: &mut Machine, file: &str, restore: bool) -> Result<(), VmError> {
+  if restore {
+    return vm.restore_snapshot(&mut BufReader::new(File::open(file)?));
+  }
+
+  vm.load_image(file, 0)?;
+  vm.reset_pc(0);
+  vm.enter_user_mode();
+
+  Ok(())
+}
+
+/// Writes out the trace, profile and coverage, if they were asked for.
+fn finish(
+  vm: &mut Machine,
+  symbols: &BTreeMap<String, u16>,
+  report: Option<&String>,
+  folded: Option<&String>,
+  coverage: Option<&String>,
+) {
+  if let Err(err) = vm.stop_trace() {
+    eprintln!("failed to write the trace: {err}");
+  }
+
+  if let (Some(file), Some(executed)) = (coverage, vm.stop_coverage()) {
+    let written = File::create(file).and_then(|output| executed.write(&mut BufWriter::new(output)));
+
+    if let Err(err) = written {
+      eprintln!("failed to write `{file}`: {err}");
+    }
+  }
+
+  let Some(profile) = vm.stop_profile() else {
+    return;
+  };
+
+  for (file, folded) in [(report, false), (folded, true)] {
+    let Some(file) = file else {
+      continue;
+    };
+
+    let written = File::create(file).and_then(|output| {
+      let mut output = BufWriter::new(output);
+
+      if folded {
+        profile.write_folded(symbols, &mut output)
+      } else {
+        profile.write_report(symbols, &mut output)
+      }
+    });
+
+    if let Err(err) = written {
+      eprintln!("failed to write `{file}`: {err}");
+    }
+  }
+}
 
 fn main() {
-  println!("hello awa");
+  let args: Vec<String> = args().skip(1).collect();
+
+  let Some((file, flags)) = args.split_last() else {
+    usage();
+  };
+
+  let mut native_traps = false;
+  let mut debug = false;
+  let mut gdb_port = None;
+  let mut trace_file = None;
+  let mut trace_filter = TraceFilter::default();
+  let mut render = false;
+  let mut history = None;
+  let mut restore = false;
+  let mut report = None;
+  let mut folded = None;
+  let mut coverage = None;
+
+  let mut flags = flags.iter();
+
+  while let Some(flag) = flags.next() {
+    match flag.as_str() {
+      "--native-traps" => native_traps = true,
+      "--debug" => debug = true,
+      "--gdb" => {
+        let port: u16 = flags
+          .next()
+          .and_then(|port| port.parse().ok())
+          .unwrap_or_else(|| usage());
+
+        gdb_port = Some(port);
+      }
+      "--history" => {
+        let steps: usize = flags
+          .next()
+          .and_then(|steps| steps.parse().ok())
+          .unwrap_or_else(|| usage());
+
+        history = Some(steps);
+      }
+      "--trace" => trace_file = Some(flags.next().unwrap_or_else(|| usage())),
+      "--trace-range" => {
+        let range = flags
+          .next()
+          .and_then(|range| range.split_once('-'))
+          .and_then(|(from, to)| Some(parse_number(from)?..=parse_number(to)?))
+          .unwrap_or_else(|| usage());
+
+        trace_filter.addresses = Some(range);
+      }
+      "--trace-ops" => {
+        let names = flags.next().unwrap_or_else(|| usage());
+
+        let opcodes = TraceFilter::parse_opcodes(names).unwrap_or_else(|err| {
+          eprintln!("{err}");
+          exit(1);
+        });
+
+        trace_filter.opcodes = Some(opcodes);
+      }
+      "--render-trace" => render = true,
+      "--restore" => restore = true,
+      "--profile" => report = Some(flags.next().unwrap_or_else(|| usage())),
+      "--folded" => folded = Some(flags.next().unwrap_or_else(|| usage())),
+      "--coverage" => coverage = Some(flags.next().unwrap_or_else(|| usage())),
+      _ => usage(),
+    }
+  }
+
+  if debug && gdb_port.is_some() {
+    usage();
+  }
+
+  if render {
+    let rendered = File::open(file)
+      .and_then(|input| trace::render(&mut BufReader::new(input), &mut stdout().lock()));
+
+    if let Err(err) = rendered {
+      eprintln!("failed to render `{file}`: {err}");
+      exit(1);
+    }
+
+    return;
+  }
+
+  let mut vm = Machine::new();
+  vm.memory.keyboard = Keyboard::stdin();
+
+  if let Err(err) = load(&mut vm, file, restore) {
+    eprintln!("failed to load `{file}`: {err}");
+    exit(1);
+  }
+
+  // a snapshot carries on with the traps it was using
+  vm.native_traps |= native_traps;
+
+  if let Some(steps) = history.or(debug.then_some(DEBUG_HISTORY)) {
+    vm.record_history(steps);
+  }
+
+  if let Some(trace_file) = trace_file {
+    let started = File::create(trace_file)
+      .and_then(|output| vm.start_trace(BufWriter::new(output), trace_filter));
+
+    if let Err(err) = started {
+      eprintln!("failed to create `{trace_file}`: {err}");
+      exit(1);
+    }
+  }
+
+  let profiling = report.is_some() || folded.is_some();
+
+  if profiling {
+    vm.start_profile();
+  }
+
+  if coverage.is_some() {
+    vm.start_coverage();
+  }
+
+  // only the debugger and profiles name addresses
+  let symbols = if debug || profiling {
+    load_symbols(file, debug)
+  } else {
+    BTreeMap::new()
+  };
+
+  if let Some(port) = gdb_port {
+    let listener = TcpListener::bind(("127.0.0.1", port)).unwrap_or_else(|err| {
+      eprintln!("failed to listen on port {port}: {err}");
+      exit(1);
+    });
+
+    println!("waiting for gdb on port {port}");
+
+    if let Err(err) = gdb::serve(&mut vm, &listener) {
+      eprintln!("gdb connection failed: {err}");
+      exit(1);
+    }
+
+    finish(&mut vm, &symbols, report, folded, coverage);
+    return;
+  }
+
+  if debug {
+    Debugger::new(symbols.clone()).run(&mut vm);
+
+    finish(&mut vm, &symbols, report, folded, coverage);
+    return;
+  }
+
+  let result = vm.resume();
+  finish(&mut vm, &symbols, report, folded, coverage);
+
+  if let Err(err) = result {
+    let pc = vm.registers.get(Register::Pc);
+
+    eprintln!("error at x{pc:04X}: {err}");
+    exit(1);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::env::temp_dir;
+  use std::fs::write;
+
+  use rvm::exception::Exception;
+
+  use super::*;
+
+  #[test]
+  fn test_images_run_in_user_mode() {
+    // sti r0, #1; halt; x0000
+    let file = temp_dir().join("rvm_user_mode.obj");
+    write(&file, [0x30, 0x00, 0xB0, 0x01, 0xF0, 0x25, 0x00, 0x00]).unwrap();
 
-  let file = args().nth(1).unwrap();
+    let mut vm = Machine::new();
+    load(&mut vm, file.to_str().unwrap(), false).unwrap();
+    assert_eq!(vm.registers.get(Register::R6), 0xFE00);
 
-  load_image(&file, 0);
-  run(0);
+    // writing the trap vector table is an access violation
+    assert!(matches!(
+      vm.resume(),
+      Err(VmError::Exception(Exception::AccessViolation(0x0000)))
+    ));
+    assert_eq!(vm.registers.get(Register::Pc), 0x3000);
+  }
 }
@@ -1,29 +1,248 @@
-static mut MEM: [u16; u16::MAX as usize] = [0; u16::MAX as usize];
+use std::ops::RangeInclusive;
 
-pub fn read(address: u16) -> u16 {
-  unsafe { MEM[address as usize] }
+use crate::device::{
+  Device, Display, Interrupt, Keyboard, MachineControl, DDR, DSR, KBDR, KBSR, MCR,
+};
+use crate::error::VmError;
+
+/// Number of words in the address space, every `u16` being a valid address.
+pub const MEMORY_SIZE: usize = 1 << 16;
+
+/// The memory bus, sending accesses to device registers to their device and
+/// everything else to plain memory cells.
+pub struct Memory {
+  pub(crate) cells: Box<[u16]>,
+  pub keyboard: Keyboard,
+  pub display: Display,
+  pub control: MachineControl,
+  devices: Vec<(RangeInclusive<u16>, Box<dyn Device>)>,
+  /// Addresses and previous values of cells written while the undo log records
+  /// a step
+  pub(crate) journal: Option<Vec<(u16, u16)>>,
+}
+
+impl Memory {
+  pub fn new() -> Self {
+    Self {
+      cells: vec![0; MEMORY_SIZE].into_boxed_slice(),
+      keyboard: Keyboard::new(),
+      display: Display::default(),
+      control: MachineControl::default(),
+      devices: Vec::new(),
+      journal: None,
+    }
+  }
+
+  /// Maps `device` over `range`, ahead of memory but behind the standard
+  /// devices.
+  pub fn attach(&mut self, range: RangeInclusive<u16>, device: Box<dyn Device>) {
+    self.devices.push((range, device));
+  }
+
+  fn device(&mut self, address: u16) -> Option<&mut dyn Device> {
+    match address {
+      KBSR | KBDR => Some(&mut self.keyboard),
+      DSR | DDR => Some(&mut self.display),
+      MCR => Some(&mut self.control),
+      _ => self
+        .devices
+        .iter_mut()
+        .find(|(range, _)| range.contains(&address))
+        .map(|(_, device)| device.as_mut() as &mut dyn Device),
+    }
+  }
+
+  pub fn read(&mut self, address: u16) -> u16 {
+    match self.device(address) {
+      Some(device) => device.read(address),
+      None => self.cells[address as usize],
+    }
+  }
+
+  pub fn write(&mut self, address: u16, value: u16) {
+    match self.device(address) {
+      Some(device) => device.write(address, value),
+      None => {
+        let cell = &mut self.cells[address as usize];
+
+        if let Some(journal) = &mut self.journal {
+          journal.push((address, *cell));
+        }
+
+        *cell = value;
+      }
+    }
+  }
+
+  /// The highest priority interrupt requested by any device.
+  pub fn interrupt(&mut self) -> Option<Interrupt> {
+    let standard = [
+      self.keyboard.interrupt(),
+      self.display.interrupt(),
+      self.control.interrupt(),
+    ];
+
+    standard
+      .into_iter()
+      .chain(
+        self
+          .devices
+          .iter_mut()
+          .map(|(_, device)| device.interrupt()),
+      )
+      .flatten()
+      .max_by_key(|interrupt| interrupt.priority)
+  }
+
+  /// Copies big-endian words from `buffer` into memory starting at `start`.
+  ///
+  /// The words have to fit between `start` and the end of memory. Devices are
+  /// bypassed, an image only ever fills memory cells.
+  pub fn load(&mut self, buffer: &[u8], start: u16) -> Result<(), VmError> {
+    if !buffer.len().is_multiple_of(2) {
+      return Err(VmError::MalformedImage(format!(
+        "odd length of {} bytes",
+        buffer.len()
+      )));
+    }
+
+    let count = buffer.len() / 2;
+
+    if usize::from(start) + count > MEMORY_SIZE {
+      return Err(VmError::MalformedImage(format!(
+        "{count} words from x{start:04X} run past the end of memory"
+      )));
+    }
+
+    for (i, bytes) in buffer.chunks_exact(2).enumerate() {
+      let value = u16::from_be_bytes([bytes[0], bytes[1]]);
+
+      self.cells[usize::from(start) + i] = value;
+    }
+
+    Ok(())
+  }
 }
 
-pub fn write(address: u16, value: u16) {
-  unsafe { MEM[address as usize] = value }
+impl Default for Memory {
+  fn default() -> Self {
+    Self::new()
+  }
 }
 
-pub fn load(buffer: &[u8], start: u16) {
-  assert!(start + (buffer.len() as u16) < u16::MAX);
-  assert!(buffer.len() % 2 == 0);
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_top_of_memory() {
+    let mut memory = Memory::new();
+
+    memory.write(0xFFFD, 0xBEEF);
+    assert_eq!(memory.read(0xFFFD), 0xBEEF);
+    memory.write(0xFFFF, 0xBEEF);
+    assert_eq!(memory.read(0xFFFF), 0xBEEF);
+  }
+
+  #[test]
+  fn test_device_registers() {
+    let mut memory = Memory::new();
+
+    memory.keyboard.push(b"a");
+    assert_eq!(memory.read(KBSR), 0x8000);
+    assert_eq!(memory.read(KBDR), 'a' as u16);
+    assert_eq!(memory.read(KBSR), 0);
+    assert_eq!(memory.read(DSR), 0x8000);
+
+    memory.write(MCR, 0);
+    assert!(!memory.control.running());
+    assert_eq!(memory.read(MCR), 0);
+  }
+
+  struct Counter(u16);
+
+  impl Device for Counter {
+    fn read(&mut self, _address: u16) -> u16 {
+      self.0 += 1;
+      self.0
+    }
+
+    fn write(&mut self, _address: u16, value: u16) {
+      self.0 = value;
+    }
+
+    fn interrupt(&mut self) -> Option<Interrupt> {
+      (self.0 >= 10).then(|| Interrupt {
+        priority: self.0 - 10,
+        vector: 0x90,
+      })
+    }
+  }
+
+  #[test]
+  fn test_attach() {
+    let mut memory = Memory::new();
+    memory.attach(0xFE10..=0xFE11, Box::new(Counter(0)));
+
+    assert_eq!(memory.read(0xFE10), 1);
+    assert_eq!(memory.read(0xFE11), 2);
+    memory.write(0xFE10, 10);
+    assert_eq!(memory.read(0xFE11), 11);
+    assert_eq!(memory.read(0xFE12), 0);
+  }
+
+  #[test]
+  fn test_load() {
+    let mut memory = Memory::new();
+
+    memory.load(&[0x12, 0x34, 0x56, 0x78], 0x3000).unwrap();
+    assert_eq!(memory.read(0x3000), 0x1234);
+    assert_eq!(memory.read(0x3001), 0x5678);
+  }
+
+  #[test]
+  fn test_load_to_end_of_memory() {
+    let mut memory = Memory::new();
+
+    memory.load(&[0x12, 0x34, 0x56, 0x78], 0xFFFE).unwrap();
+    assert_eq!(memory.read(0xFFFF), 0x5678);
+
+    // the image lands under MCR without reaching the device
+    assert_eq!(memory.read(MCR), 0x8000);
+  }
+
+  #[test]
+  fn test_load_past_end_of_memory() {
+    let mut memory = Memory::new();
+
+    assert!(matches!(
+      memory.load(&[0x12, 0x34, 0x56, 0x78], 0xFFFF),
+      Err(VmError::MalformedImage(_))
+    ));
+    assert!(matches!(
+      memory.load(&[0x12, 0x34, 0x56], 0x3000),
+      Err(VmError::MalformedImage(_))
+    ));
+  }
 
-  // unsafe {
-  //   core::ptr::copy_nonoverlapping(
-  //     buffer.as_ptr(),
-  //     MEM.as_mut_ptr().offset(start as isize) as *mut u8,
-  //     buffer.len(),
-  //   );
-  // }
+  #[test]
+  fn test_highest_priority_interrupt() {
+    let mut memory = Memory::new();
+    memory.attach(0xFE10..=0xFE10, Box::new(Counter(0)));
+    assert_eq!(memory.interrupt(), None);
 
-  for i in 0..buffer.len() / 2 {
-    let bytes = [buffer[i * 2], buffer[i * 2 + 1]];
-    let value = u16::from_be_bytes(bytes);
+    memory.keyboard.push(b"a");
+    memory.write(KBSR, 0x4000);
+    memory.write(0xFE10, 12);
+    assert_eq!(memory.interrupt().unwrap().vector, 0x80);
 
-    write(start + (i as u16), value);
+    memory.write(0xFE10, 15);
+    assert_eq!(
+      memory.interrupt(),
+      Some(Interrupt {
+        priority: 5,
+        vector: 0x90
+      })
+    );
   }
 }
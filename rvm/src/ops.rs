@@ -1,6 +1,7 @@
-use crate::memory::{read, write};
-use crate::register::{reg, reg_r, update_flag, Register};
-use crate::trap::trap;
+use crate::error::VmError;
+use crate::exception::Exception;
+use crate::register::Register;
+use crate::Machine;
 
 const OP_COUNT: usize = 16;
 
@@ -39,143 +40,225 @@ fn base_r(i: u16) -> u16 {
 }
 
 #[inline]
-fn op_br(i: u16) {
-  let cond = *reg_r(Register::Cond);
+fn op_br(vm: &mut Machine, i: u16) -> Result<(), VmError> {
+  let cond = vm.registers.get(Register::Cond);
 
   if (cond & (i >> 9 & 0x7)) != 0 {
-    *reg_r(Register::Pc) += offset_9(i);
+    let pc = vm.registers.get(Register::Pc);
+    *vm.registers.reg_r(Register::Pc) = pc.wrapping_add(offset_9(i));
   }
+
+  Ok(())
 }
 
 #[inline]
-fn op_add(i: u16) {
+fn op_add(vm: &mut Machine, i: u16) -> Result<(), VmError> {
   let dr = dr(i);
   let sr1 = sr1(i);
   let sr2 = (i) & 0x7;
   let bit_5 = (i >> 5) & 0x1 == 1;
 
-  *reg(dr) = {
+  *vm.registers.reg(dr) = {
     if bit_5 {
-      *reg(sr1) + sext(i & 0x1F, 5)
+      vm.registers.reg(sr1).wrapping_add(sext(i & 0x1F, 5))
     } else {
-      *reg(sr1) + *reg(sr2)
+      vm.registers.reg(sr1).wrapping_add(*vm.registers.reg(sr2))
     }
   };
 
-  update_flag(dr.into());
+  vm.registers.update_flag(Register::general(dr));
+
+  Ok(())
 }
 
 #[inline]
-fn op_st(i: u16) {
-  write(*reg_r(Register::Pc) + offset_9(i), *reg(dr(i)));
+fn op_st(vm: &mut Machine, i: u16) -> Result<(), VmError> {
+  let address = vm.registers.get(Register::Pc).wrapping_add(offset_9(i));
+  let value = *vm.registers.reg(dr(i));
+
+  vm.write(address, value)?;
+
+  Ok(())
 }
 
 #[inline]
-fn op_jsr(i: u16) {
-  *reg_r(Register::R7) = *reg_r(Register::Pc);
+fn op_jsr(vm: &mut Machine, i: u16) -> Result<(), VmError> {
+  let pc = vm.registers.get(Register::Pc);
   let bit_11 = (i >> 11) & 0x1 == 1;
 
-  *reg_r(Register::Pc) = if bit_11 {
-    *reg_r(Register::Pc) + offset_11(i)
+  // the target is read before r7 is written, so `JSRR R7` jumps to the old r7
+  let target = if bit_11 {
+    pc.wrapping_add(offset_11(i))
   } else {
-    *reg(base_r(i))
+    *vm.registers.reg(base_r(i))
   };
+
+  *vm.registers.reg_r(Register::R7) = pc;
+  *vm.registers.reg_r(Register::Pc) = target;
+
+  Ok(())
 }
 
 #[inline]
-fn op_and(i: u16) {
+fn op_and(vm: &mut Machine, i: u16) -> Result<(), VmError> {
   let dr = dr(i);
   let sr1 = sr1(i);
   let sr2 = (i) & 0x7;
   let bit_5 = (i >> 5) & 0x1 == 1;
 
-  *reg(dr) = {
+  *vm.registers.reg(dr) = {
     if bit_5 {
-      *reg(sr1) & sext(i & 0x1F, 5)
+      *vm.registers.reg(sr1) & sext(i & 0x1F, 5)
     } else {
-      *reg(sr1) & *reg(sr2)
+      *vm.registers.reg(sr1) & *vm.registers.reg(sr2)
     }
   };
 
-  update_flag(dr.into());
+  vm.registers.update_flag(Register::general(dr));
+
+  Ok(())
 }
 
 #[inline]
-fn op_str(i: u16) {
-  write(*reg(sr1(i)) + offset_6(i), *reg(dr(i)));
+fn op_str(vm: &mut Machine, i: u16) -> Result<(), VmError> {
+  let address = vm.registers.reg(sr1(i)).wrapping_add(offset_6(i));
+  let value = *vm.registers.reg(dr(i));
+
+  vm.write(address, value)?;
+
+  Ok(())
 }
 
-fn op_rti(_i: u16) {}
+fn op_rti(vm: &mut Machine, _i: u16) -> Result<(), VmError> {
+  if vm.registers.user_mode() {
+    return Err(Exception::PrivilegeViolation.into());
+  }
+
+  let pc = vm.pop();
+  let psr = vm.pop();
+
+  *vm.registers.reg_r(Register::Pc) = pc;
+  vm.registers.set_psr(psr);
+
+  // returning to user mode switches back to the user stack
+  if vm.registers.user_mode() {
+    *vm.registers.reg_r(Register::SavedSsp) = vm.registers.get(Register::R6);
+    *vm.registers.reg_r(Register::R6) = vm.registers.get(Register::SavedUsp);
+  }
+
+  Ok(())
+}
 
 #[inline]
-fn op_not(i: u16) {
+fn op_not(vm: &mut Machine, i: u16) -> Result<(), VmError> {
   let dr = dr(i);
   let sr = sr1(i);
 
-  *reg(dr) = !*reg(sr);
+  *vm.registers.reg(dr) = !*vm.registers.reg(sr);
 
-  update_flag(dr.into());
+  vm.registers.update_flag(Register::general(dr));
+
+  Ok(())
 }
 
 #[inline]
-fn op_ld(i: u16) {
+fn op_ld(vm: &mut Machine, i: u16) -> Result<(), VmError> {
   let offset = offset_9(i);
   let dr = dr(i);
 
-  *reg(dr) = read(*reg_r(Register::Pc) + offset);
+  *vm.registers.reg(dr) = vm.read(vm.registers.get(Register::Pc).wrapping_add(offset))?;
+
+  vm.registers.update_flag(Register::general(dr));
 
-  update_flag(dr.into());
+  Ok(())
 }
 
 #[inline]
-fn op_ldi(i: u16) {
+fn op_ldi(vm: &mut Machine, i: u16) -> Result<(), VmError> {
   let offset = offset_9(i);
   let dr = dr(i);
 
-  *reg(dr) = read(read(*reg_r(Register::Pc) + offset));
+  let address = vm.read(vm.registers.get(Register::Pc).wrapping_add(offset))?;
+  *vm.registers.reg(dr) = vm.read(address)?;
 
-  update_flag(dr.into());
+  vm.registers.update_flag(Register::general(dr));
+
+  Ok(())
 }
 
 #[inline]
-fn op_ldr(i: u16) {
+fn op_ldr(vm: &mut Machine, i: u16) -> Result<(), VmError> {
   let offset = offset_6(i);
   let dr = dr(i);
   let sr = sr1(i);
 
-  *reg(dr) = read(*reg(sr) + offset);
+  let address = vm.registers.reg(sr).wrapping_add(offset);
+  *vm.registers.reg(dr) = vm.read(address)?;
+
+  vm.registers.update_flag(Register::general(dr));
 
-  update_flag(dr.into());
+  Ok(())
 }
 
 #[inline]
-fn op_sti(i: u16) {
-  write(read(*reg_r(Register::Pc) + offset_9(i)), *reg(dr(i)));
+fn op_sti(vm: &mut Machine, i: u16) -> Result<(), VmError> {
+  let address = vm.read(vm.registers.get(Register::Pc).wrapping_add(offset_9(i)))?;
+  let value = *vm.registers.reg(dr(i));
+
+  vm.write(address, value)?;
+
+  Ok(())
 }
 
 #[inline]
-fn op_jmp(i: u16) {
+fn op_jmp(vm: &mut Machine, i: u16) -> Result<(), VmError> {
   let base_r = base_r(i);
 
-  *reg_r(Register::Pc) = *reg(base_r);
+  *vm.registers.reg_r(Register::Pc) = *vm.registers.reg(base_r);
+
+  Ok(())
 }
 
-fn op_res(_i: u16) {}
+fn op_res(_vm: &mut Machine, _i: u16) -> Result<(), VmError> {
+  Err(Exception::IllegalOpcode.into())
+}
 
 #[inline]
-fn op_lea(i: u16) {
+fn op_lea(vm: &mut Machine, i: u16) -> Result<(), VmError> {
   let dr = dr(i);
 
-  *reg(dr) = *reg_r(Register::Pc) + sext(i & 0x1FF, 9);
+  *vm.registers.reg(dr) = vm.registers.get(Register::Pc).wrapping_add(offset_9(i));
+
+  vm.registers.update_flag(Register::general(dr));
 
-  update_flag(dr.into());
+  Ok(())
 }
 
-fn op_trap(i: u16) {
-  trap(i);
+/// Runs the host routine for the vector if there is one, otherwise enters the
+/// routine in the trap vector table in supervisor mode with R7 set to the
+/// return address, and the PSR and PC on the supervisor stack for it to return
+/// with RTI. A zero entry in the table is an invalid trap.
+fn op_trap(vm: &mut Machine, i: u16) -> Result<(), VmError> {
+  if vm.trap(i)? {
+    return Ok(());
+  }
+
+  let routine = vm.memory.read(i & 0xFF);
+
+  if routine == 0 {
+    return Err(VmError::InvalidTrap(i as u8));
+  }
+
+  *vm.registers.reg_r(Register::R7) = vm.registers.get(Register::Pc);
+  vm.enter_supervisor(None);
+  *vm.registers.reg_r(Register::Pc) = routine;
+
+  Ok(())
 }
 
-static OPS: [fn(u16); OP_COUNT] = [
+type Op = fn(&mut Machine, u16) -> Result<(), VmError>;
+
+static OPS: [Op; OP_COUNT] = [
   op_br, op_add, op_ld, op_st, op_jsr, op_and, op_ldr, op_str, op_rti, op_not, op_ldi, op_sti,
   op_jmp, op_res, op_lea, op_trap,
 ];
@@ -194,8 +277,11 @@ fn sext(x: u16, bit_count: u16) -> u16 {
   }
 }
 
-pub fn op(i: u16) {
-  OPS[opc(i)](i);
+impl Machine {
+  /// Executes `i`, with the pc already past it.
+  pub fn op(&mut self, i: u16) -> Result<(), VmError> {
+    OPS[opc(i)](self, i)
+  }
 }
 
 #[cfg(test)]
@@ -229,7 +315,7 @@ mod tests {
     assert_eq!(opc(0xF025), 15);
   }
 
-  fn assert_fn_eq(f: fn(u16), g: fn(u16)) {
+  fn assert_fn_eq(f: Op, g: Op) {
     assert_eq!(f as usize, g as usize);
   }
 
@@ -272,4 +358,399 @@ mod tests {
     assert_eq!(OP_NAMES[14], "lea");
     assert_eq!(OP_NAMES[15], "trap");
   }
+
+  const F_P: u16 = 1 << 0;
+  const F_Z: u16 = 1 << 1;
+  const F_N: u16 = 1 << 2;
+
+  /// A machine with its pc at `x3000`, as if it had just fetched an instruction
+  /// from `x2FFF`.
+  fn machine() -> Machine {
+    let mut vm = Machine::new();
+    *vm.registers.reg_r(Register::Pc) = 0x3000;
+    vm
+  }
+
+  fn set(vm: &mut Machine, r: Register, value: u16) {
+    *vm.registers.reg_r(r) = value;
+  }
+
+  fn get(vm: &Machine, r: Register) -> u16 {
+    vm.registers.get(r)
+  }
+
+  #[test]
+  fn test_sext() {
+    assert_eq!(sext(0x1F, 5), 0xFFFF);
+    assert_eq!(sext(0x0F, 5), 0x000F);
+    assert_eq!(sext(0x100, 9), 0xFF00);
+    assert_eq!(sext(0x0FF, 9), 0x00FF);
+    assert_eq!(sext(0x400, 11), 0xFC00);
+    assert_eq!(sext(0x20, 6), 0xFFE0);
+  }
+
+  #[test]
+  fn test_op_add() {
+    let mut vm = machine();
+
+    // ADD R1, R1, #-1
+    set(&mut vm, Register::R1, 0);
+    vm.op(0x127F).unwrap();
+    assert_eq!(get(&vm, Register::R1), 0xFFFF);
+    assert_eq!(get(&vm, Register::Cond), F_N);
+
+    // ADD R1, R1, #1
+    vm.op(0x1261).unwrap();
+    assert_eq!(get(&vm, Register::R1), 0);
+    assert_eq!(get(&vm, Register::Cond), F_Z);
+
+    // ADD R0, R1, R2
+    set(&mut vm, Register::R1, 0x7FFF);
+    set(&mut vm, Register::R2, 1);
+    vm.op(0x1042).unwrap();
+    assert_eq!(get(&vm, Register::R0), 0x8000);
+    assert_eq!(get(&vm, Register::Cond), F_N);
+
+    // ADD R0, R1, R2
+    set(&mut vm, Register::R1, 0xFFFF);
+    set(&mut vm, Register::R2, 0xFFFF);
+    vm.op(0x1042).unwrap();
+    assert_eq!(get(&vm, Register::R0), 0xFFFE);
+
+    // ADD R0, R0, #15
+    set(&mut vm, Register::R0, 0);
+    vm.op(0x102F).unwrap();
+    assert_eq!(get(&vm, Register::R0), 15);
+    assert_eq!(get(&vm, Register::Cond), F_P);
+  }
+
+  #[test]
+  fn test_op_and() {
+    let mut vm = machine();
+
+    // AND R0, R1, #-16
+    set(&mut vm, Register::R1, 0x1234);
+    vm.op(0x5070).unwrap();
+    assert_eq!(get(&vm, Register::R0), 0x1230);
+    assert_eq!(get(&vm, Register::Cond), F_P);
+
+    // AND R0, R0, #0
+    vm.op(0x5020).unwrap();
+    assert_eq!(get(&vm, Register::R0), 0);
+    assert_eq!(get(&vm, Register::Cond), F_Z);
+
+    // AND R0, R1, R2
+    set(&mut vm, Register::R1, 0xF0F0);
+    set(&mut vm, Register::R2, 0xFF00);
+    vm.op(0x5042).unwrap();
+    assert_eq!(get(&vm, Register::R0), 0xF000);
+    assert_eq!(get(&vm, Register::Cond), F_N);
+  }
+
+  #[test]
+  fn test_op_not() {
+    let mut vm = machine();
+
+    // NOT R0, R1
+    set(&mut vm, Register::R1, 0x00FF);
+    vm.op(0x907F).unwrap();
+    assert_eq!(get(&vm, Register::R0), 0xFF00);
+    assert_eq!(get(&vm, Register::Cond), F_N);
+
+    set(&mut vm, Register::R1, 0xFFFF);
+    vm.op(0x907F).unwrap();
+    assert_eq!(get(&vm, Register::R0), 0);
+    assert_eq!(get(&vm, Register::Cond), F_Z);
+  }
+
+  #[test]
+  fn test_op_br() {
+    let mut vm = machine();
+
+    // BRz #-2, not taken
+    set(&mut vm, Register::Cond, F_P);
+    vm.op(0x05FE).unwrap();
+    assert_eq!(get(&vm, Register::Pc), 0x3000);
+
+    // BRp #-2
+    vm.op(0x03FE).unwrap();
+    assert_eq!(get(&vm, Register::Pc), 0x2FFE);
+
+    // BRnzp #255
+    vm.op(0x0EFF).unwrap();
+    assert_eq!(get(&vm, Register::Pc), 0x30FD);
+
+    // BRnzp #-256 from the bottom of memory
+    set(&mut vm, Register::Pc, 0x0010);
+    vm.op(0x0F00).unwrap();
+    assert_eq!(get(&vm, Register::Pc), 0xFF10);
+
+    // BRnzp #1 from the top of memory
+    set(&mut vm, Register::Pc, 0xFFFF);
+    vm.op(0x0E01).unwrap();
+    assert_eq!(get(&vm, Register::Pc), 0x0000);
+  }
+
+  #[test]
+  fn test_op_ld() {
+    let mut vm = machine();
+
+    // LD R0, #-1
+    vm.memory.write(0x2FFF, 0x8000);
+    vm.op(0x21FF).unwrap();
+    assert_eq!(get(&vm, Register::R0), 0x8000);
+    assert_eq!(get(&vm, Register::Cond), F_N);
+
+    // LD R0, #2
+    vm.memory.write(0x3002, 0);
+    vm.op(0x2002).unwrap();
+    assert_eq!(get(&vm, Register::R0), 0);
+    assert_eq!(get(&vm, Register::Cond), F_Z);
+  }
+
+  #[test]
+  fn test_op_ldi() {
+    let mut vm = machine();
+
+    // LDI R0, #-3
+    vm.memory.write(0x2FFD, 0x4000);
+    vm.memory.write(0x4000, 7);
+    vm.op(0xA1FD).unwrap();
+    assert_eq!(get(&vm, Register::R0), 7);
+    assert_eq!(get(&vm, Register::Cond), F_P);
+  }
+
+  #[test]
+  fn test_op_ldr() {
+    let mut vm = machine();
+
+    // LDR R0, R6, #-1
+    set(&mut vm, Register::R6, 0x4000);
+    vm.memory.write(0x3FFF, 0xFFFF);
+    vm.op(0x61BF).unwrap();
+    assert_eq!(get(&vm, Register::R0), 0xFFFF);
+    assert_eq!(get(&vm, Register::Cond), F_N);
+
+    // LDR R0, R6, #-2 wrapping below address zero
+    set(&mut vm, Register::R6, 0x0000);
+    vm.memory.write(0xFFFE, 42);
+    vm.op(0x61BE).unwrap();
+    assert_eq!(get(&vm, Register::R0), 42);
+
+    // LDR R0, R6, #-1 reading the last address
+    vm.memory.write(0xFFFF, 43);
+    vm.op(0x61BF).unwrap();
+    assert_eq!(get(&vm, Register::R0), 43);
+  }
+
+  #[test]
+  fn test_op_st() {
+    let mut vm = machine();
+
+    // ST R1, #-5
+    set(&mut vm, Register::R1, 0xBEEF);
+    vm.op(0x33FB).unwrap();
+    assert_eq!(vm.memory.read(0x2FFB), 0xBEEF);
+  }
+
+  #[test]
+  fn test_op_sti() {
+    let mut vm = machine();
+
+    // STI R1, #-1
+    set(&mut vm, Register::R1, 0xBEEF);
+    vm.memory.write(0x2FFF, 0x4000);
+    vm.op(0xB3FF).unwrap();
+    assert_eq!(vm.memory.read(0x4000), 0xBEEF);
+  }
+
+  #[test]
+  fn test_op_str() {
+    let mut vm = machine();
+
+    // STR R0, R6, #-32
+    set(&mut vm, Register::R0, 0xBEEF);
+    set(&mut vm, Register::R6, 0x4000);
+    vm.op(0x71A0).unwrap();
+    assert_eq!(vm.memory.read(0x3FE0), 0xBEEF);
+  }
+
+  #[test]
+  fn test_op_jsr() {
+    let mut vm = machine();
+
+    // JSR #-1024
+    vm.op(0x4C00).unwrap();
+    assert_eq!(get(&vm, Register::R7), 0x3000);
+    assert_eq!(get(&vm, Register::Pc), 0x2C00);
+
+    // JSRR R7, jumping back to where we came from
+    vm.op(0x41C0).unwrap();
+    assert_eq!(get(&vm, Register::R7), 0x2C00);
+    assert_eq!(get(&vm, Register::Pc), 0x3000);
+  }
+
+  #[test]
+  fn test_op_jmp() {
+    let mut vm = machine();
+
+    // JMP R2
+    set(&mut vm, Register::R2, 0x4000);
+    vm.op(0xC080).unwrap();
+    assert_eq!(get(&vm, Register::Pc), 0x4000);
+  }
+
+  #[test]
+  fn test_op_lea() {
+    let mut vm = machine();
+
+    // LEA R0, #-256
+    vm.op(0xE100).unwrap();
+    assert_eq!(get(&vm, Register::R0), 0x2F00);
+    assert_eq!(get(&vm, Register::Cond), F_P);
+
+    // LEA R0, #-1 from the bottom of memory
+    set(&mut vm, Register::Pc, 0x0000);
+    vm.op(0xE1FF).unwrap();
+    assert_eq!(get(&vm, Register::R0), 0xFFFF);
+    assert_eq!(get(&vm, Register::Cond), F_N);
+  }
+
+  #[test]
+  fn test_op_rti() {
+    let mut vm = machine();
+
+    // return to user mode at x4000 with the Z flag set
+    set(&mut vm, Register::R6, 0x2FFE);
+    set(&mut vm, Register::SavedUsp, 0xFE00);
+    vm.memory.write(0x2FFE, 0x4000);
+    vm.memory.write(0x2FFF, 0x8002);
+
+    vm.op(0x8000).unwrap();
+    assert_eq!(get(&vm, Register::Pc), 0x4000);
+    assert_eq!(vm.registers.psr(), 0x8002);
+    assert_eq!(get(&vm, Register::R6), 0xFE00);
+    assert_eq!(get(&vm, Register::SavedSsp), 0x3000);
+  }
+
+  #[test]
+  fn test_op_rti_in_supervisor_mode_keeps_stack() {
+    let mut vm = machine();
+
+    set(&mut vm, Register::R6, 0x2FFE);
+    vm.memory.write(0x2FFE, 0x4000);
+    vm.memory.write(0x2FFF, 0x0401);
+
+    vm.op(0x8000).unwrap();
+    assert_eq!(get(&vm, Register::Pc), 0x4000);
+    assert_eq!(vm.registers.psr(), 0x0401);
+    assert_eq!(get(&vm, Register::R6), 0x3000);
+  }
+
+  #[test]
+  fn test_op_rti_in_user_mode() {
+    let mut vm = machine();
+
+    vm.registers.set_psr(0x8001);
+    set(&mut vm, Register::R6, 0xF000);
+
+    assert!(matches!(
+      vm.op(0x8000),
+      Err(VmError::Exception(Exception::PrivilegeViolation))
+    ));
+    assert_eq!(get(&vm, Register::Pc), 0x3000);
+    assert_eq!(get(&vm, Register::R6), 0xF000);
+  }
+
+  #[test]
+  fn test_op_res() {
+    let mut vm = machine();
+
+    assert!(matches!(
+      vm.op(0xD000),
+      Err(VmError::Exception(Exception::IllegalOpcode))
+    ));
+  }
+
+  #[test]
+  fn test_access_control() {
+    let mut vm = machine();
+    vm.registers.set_psr(0x8000);
+    set(&mut vm, Register::R1, 0x2FFF);
+
+    // LDR R0, R1, #0 and STR R0, R1, #0 on system space
+    assert!(matches!(
+      vm.op(0x6040),
+      Err(VmError::Exception(Exception::AccessViolation(0x2FFF)))
+    ));
+    assert!(matches!(
+      vm.op(0x7040),
+      Err(VmError::Exception(Exception::AccessViolation(0x2FFF)))
+    ));
+
+    // LDR R0, R1, #1 on user space
+    vm.op(0x6041).unwrap();
+
+    // STR R0, R1, #-1 through the device registers
+    set(&mut vm, Register::R1, 0xFE00);
+    vm.op(0x707F).unwrap();
+    assert!(matches!(
+      vm.op(0x7040),
+      Err(VmError::Exception(Exception::AccessViolation(0xFE00)))
+    ));
+
+    // LDI R0, #-1 through a pointer into system space
+    vm.memory.write(0x2FFF, 0x3000);
+    vm.memory.write(0x3000, 0x0000);
+    set(&mut vm, Register::Pc, 0x3001);
+    assert!(matches!(
+      vm.op(0xA1FF),
+      Err(VmError::Exception(Exception::AccessViolation(0x0000)))
+    ));
+
+    // everything is accessible to the supervisor
+    vm.registers.set_psr(0x0000);
+    vm.op(0xA1FF).unwrap();
+  }
+
+  #[test]
+  fn test_op_trap() {
+    let mut vm = machine();
+    vm.registers.set_psr(0x8001);
+    set(&mut vm, Register::R6, 0xF000);
+
+    // TRAP x30 from user mode, switching to the supervisor stack
+    vm.memory.write(0x0030, 0x4000);
+    vm.op(0xF030).unwrap();
+    assert_eq!(get(&vm, Register::Pc), 0x4000);
+    assert!(!vm.registers.user_mode());
+    assert_eq!(get(&vm, Register::R6), 0x2FFE);
+    assert_eq!(get(&vm, Register::SavedUsp), 0xF000);
+    assert_eq!(vm.memory.read(0x2FFE), 0x3000);
+    assert_eq!(vm.memory.read(0x2FFF), 0x8001);
+    assert_eq!(get(&vm, Register::R7), 0x3000);
+
+    // TRAP x10, below the standard vectors, from supervisor mode
+    vm.native_traps = true;
+    vm.memory.write(0x0010, 0x5000);
+    vm.op(0xF010).unwrap();
+    assert_eq!(get(&vm, Register::Pc), 0x5000);
+    assert_eq!(get(&vm, Register::R6), 0x2FFC);
+    assert_eq!(vm.memory.read(0x2FFC), 0x4000);
+
+    // returning gets back to user mode on the user stack
+    vm.op(0x8000).unwrap();
+    vm.op(0x8000).unwrap();
+    assert_eq!(get(&vm, Register::Pc), 0x3000);
+    assert!(vm.registers.user_mode());
+    assert_eq!(get(&vm, Register::R6), 0xF000);
+  }
+
+  #[test]
+  fn test_op_trap_invalid() {
+    let mut vm = machine();
+
+    vm.memory.write(0x0030, 0x0000);
+    assert!(matches!(vm.op(0xF030), Err(VmError::InvalidTrap(0x30))));
+  }
 }
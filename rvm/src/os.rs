@@ -0,0 +1,17 @@
+use crate::Machine;
+
+/// Image of the operating system in `os/os.asm`, filling the trap vector table
+/// and providing the trap routines from `x0200`.
+pub static OS_IMAGE: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/os.obj"));
+
+impl Machine {
+  /// Loads the operating system into system space.
+  pub fn load_os(&mut self) {
+    let origin = u16::from_be_bytes([OS_IMAGE[0], OS_IMAGE[1]]);
+
+    self
+      .memory
+      .load(&OS_IMAGE[2..], origin)
+      .expect("the operating system fits in memory");
+  }
+}
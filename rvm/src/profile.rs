@@ -0,0 +1,414 @@
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+use crate::debugger::{disassemble, labels};
+use crate::memory::MEMORY_SIZE;
+use crate::ops::OP_NAMES;
+use crate::register::Register;
+use crate::step::Step;
+use crate::Machine;
+
+/// A subroutine, trap routine or handler entered along a particular chain of
+/// calls.
+struct Node {
+  /// Where it was entered
+  address: u16,
+  parent: usize,
+  children: BTreeMap<u16, usize>,
+  calls: u64,
+  /// Instructions executed in it, not counting its calls
+  count: u64,
+}
+
+/// Statistics of one subroutine, over every chain of calls reaching it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Subroutine {
+  pub address: u16,
+  pub calls: u64,
+  /// Instructions executed in it and in what it called
+  pub inclusive: u64,
+  /// Instructions executed in it alone
+  pub exclusive: u64,
+}
+
+/// Where a run spent its time, collected between
+/// [`start_profile`](Machine::start_profile) and
+/// [`stop_profile`](Machine::stop_profile).
+///
+/// Calls and returns are followed as [`call_depth`](Step::call_depth) sees
+/// them, so trap routines and handlers show up as subroutines too.
+pub struct Profile {
+  /// Executions and the last instruction seen, by address
+  addresses: Box<[(u64, u16)]>,
+  opcodes: [u64; 16],
+  /// The tree of calls, the root being where profiling started
+  nodes: Vec<Node>,
+  current: usize,
+}
+
+impl Profile {
+  fn new(entry: u16) -> Self {
+    Self {
+      addresses: vec![(0, 0); MEMORY_SIZE].into_boxed_slice(),
+      opcodes: [0; 16],
+      nodes: vec![Node {
+        address: entry,
+        parent: 0,
+        children: BTreeMap::new(),
+        calls: 1,
+        count: 0,
+      }],
+      current: 0,
+    }
+  }
+
+  fn record(&mut self, step: &Step, pc: u16) {
+    if let Step::Executed {
+      pc: from,
+      instruction,
+    } = *step
+    {
+      let (count, word) = &mut self.addresses[usize::from(from)];
+      *count += 1;
+      *word = instruction;
+
+      self.opcodes[usize::from(instruction >> 12)] += 1;
+      self.nodes[self.current].count += 1;
+    }
+
+    match step.call_depth(pc) {
+      1 => self.enter(pc),
+      -1 => self.current = self.nodes[self.current].parent,
+      _ => {}
+    }
+  }
+
+  fn enter(&mut self, address: u16) {
+    let next = self.nodes.len();
+    let node = &mut self.nodes[self.current];
+
+    let child = *node.children.entry(address).or_insert(next);
+
+    if child == next {
+      self.nodes.push(Node {
+        address,
+        parent: self.current,
+        children: BTreeMap::new(),
+        calls: 0,
+        count: 0,
+      });
+    }
+
+    self.nodes[child].calls += 1;
+    self.current = child;
+  }
+
+  /// Instructions executed.
+  pub fn instructions(&self) -> u64 {
+    self.opcodes.iter().sum()
+  }
+
+  /// Times the instruction at `address` was executed.
+  pub fn executions(&self, address: u16) -> u64 {
+    self.addresses[usize::from(address)].0
+  }
+
+  /// Instructions executed with `opcode`, the top 4 bits.
+  pub fn opcode(&self, opcode: u16) -> u64 {
+    self.opcodes[usize::from(opcode & 0xF)]
+  }
+
+  /// Every subroutine entered, by address.
+  ///
+  /// A recursive subroutine only counts its outermost call towards its
+  /// inclusive count.
+  pub fn subroutines(&self) -> Vec<Subroutine> {
+    // children always come after their parent
+    let mut totals: Vec<u64> = self.nodes.iter().map(|node| node.count).collect();
+
+    for i in (1..self.nodes.len()).rev() {
+      totals[self.nodes[i].parent] += totals[i];
+    }
+
+    let mut subroutines = BTreeMap::new();
+
+    for (i, node) in self.nodes.iter().enumerate() {
+      let subroutine = subroutines
+        .entry(node.address)
+        .or_insert_with(|| Subroutine {
+          address: node.address,
+          calls: 0,
+          inclusive: 0,
+          exclusive: 0,
+        });
+
+      subroutine.calls += node.calls;
+      subroutine.exclusive += node.count;
+
+      if !self
+        .ancestors(i)
+        .any(|a| self.nodes[a].address == node.address)
+      {
+        subroutine.inclusive += totals[i];
+      }
+    }
+
+    subroutines.into_values().collect()
+  }
+
+  /// The nodes above `i`, up to the root.
+  fn ancestors(&self, mut i: usize) -> impl Iterator<Item = usize> + '_ {
+    std::iter::from_fn(move || {
+      (i != 0).then(|| {
+        i = self.nodes[i].parent;
+        i
+      })
+    })
+  }
+
+  /// Writes the subroutines, opcodes and addresses, each busiest first, naming
+  /// addresses after the labels in `symbols`.
+  pub fn write_report(
+    &self,
+    symbols: &BTreeMap<String, u16>,
+    output: &mut impl Write,
+  ) -> io::Result<()> {
+    let labels = labels(symbols);
+    let name = |address: u16| match labels.get(&address) {
+      Some(label) => format!("x{address:04X} <{label}>"),
+      None => format!("x{address:04X}"),
+    };
+
+    writeln!(output, "{} instructions", self.instructions())?;
+
+    let mut subroutines = self.subroutines();
+    subroutines.sort_by_key(|subroutine| Reverse(subroutine.inclusive));
+
+    writeln!(output)?;
+    writeln!(
+      output,
+      "{:<28}{:>8}{:>12}{:>12}",
+      "subroutine", "calls", "inclusive", "exclusive"
+    )?;
+
+    for subroutine in &subroutines {
+      writeln!(
+        output,
+        "{:<28}{:>8}{:>12}{:>12}",
+        name(subroutine.address),
+        subroutine.calls,
+        subroutine.inclusive,
+        subroutine.exclusive
+      )?;
+    }
+
+    let mut opcodes: Vec<_> = (0..16).filter(|&op| self.opcodes[op] > 0).collect();
+    opcodes.sort_by_key(|&op| Reverse(self.opcodes[op]));
+
+    writeln!(output)?;
+    writeln!(output, "{:<28}{:>8}", "opcode", "count")?;
+
+    for op in opcodes {
+      writeln!(output, "{:<28}{:>8}", OP_NAMES[op], self.opcodes[op])?;
+    }
+
+    let mut addresses: Vec<u16> = (0..=u16::MAX)
+      .filter(|&address| self.executions(address) > 0)
+      .collect();
+    addresses.sort_by_key(|&address| Reverse(self.executions(address)));
+
+    writeln!(output)?;
+    writeln!(output, "{:<28}{:>8}  instruction", "address", "count")?;
+
+    for address in addresses {
+      let (count, word) = self.addresses[usize::from(address)];
+
+      writeln!(
+        output,
+        "{:<28}{count:>8}  {}",
+        name(address),
+        disassemble(address, word)
+      )?;
+    }
+
+    Ok(())
+  }
+
+  /// Writes one `caller;callee count` line per chain of calls that executed
+  /// anything, the folded stack format flame graph tools read.
+  pub fn write_folded(
+    &self,
+    symbols: &BTreeMap<String, u16>,
+    output: &mut impl Write,
+  ) -> io::Result<()> {
+    let labels = labels(symbols);
+    let name = |i: usize| {
+      let address = self.nodes[i].address;
+
+      match labels.get(&address) {
+        Some(label) => label.clone(),
+        None => format!("x{address:04X}"),
+      }
+    };
+
+    for (i, node) in self.nodes.iter().enumerate() {
+      if node.count == 0 {
+        continue;
+      }
+
+      let mut stack: Vec<String> = self.ancestors(i).map(name).collect();
+      stack.reverse();
+      stack.push(name(i));
+
+      writeln!(output, "{} {}", stack.join(";"), node.count)?;
+    }
+
+    Ok(())
+  }
+}
+
+impl Machine {
+  /// Counts every step from here on, the root of the calls being the current
+  /// pc.
+  pub fn start_profile(&mut self) {
+    self.profile = Some(Profile::new(self.registers.get(Register::Pc)));
+  }
+
+  /// Stops profiling, returning what was counted.
+  pub fn stop_profile(&mut self) -> Option<Profile> {
+    self.profile.take()
+  }
+
+  /// Called after a step was taken.
+  pub(crate) fn profile_step(&mut self, step: &Step) {
+    if let Some(profile) = &mut self.profile {
+      profile.record(step, self.registers.get(Register::Pc));
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use rvm_compiler::parsing::{assemble, lowercase};
+  use rvm_compiler::serialize;
+
+  use super::*;
+
+  const SOURCE: &str = r#"
+    .orig x3000
+    start:
+      jsr twice
+      jsr twice
+      halt
+    twice:
+      add r0, r0, #1
+      add r6, r7, #0
+      jsr once
+      add r7, r6, #0
+      jmp r7
+    once:
+      add r1, r1, #1
+      jmp r7
+    .end
+  "#;
+
+  fn profile() -> (Profile, BTreeMap<String, u16>) {
+    let assembly = assemble(&lowercase(SOURCE)).unwrap();
+
+    let mut vm = Machine::new();
+    vm.native_traps = true;
+    vm.load_bytes(&serialize(&assembly.program), 0).unwrap();
+    vm.reset_pc(0);
+
+    vm.start_profile();
+    vm.resume().unwrap();
+
+    (vm.stop_profile().unwrap(), assembly.symbols)
+  }
+
+  #[test]
+  fn test_counts() {
+    let (profile, _) = profile();
+
+    assert_eq!(profile.instructions(), 17);
+    assert_eq!(profile.executions(0x3000), 1);
+    assert_eq!(profile.executions(0x3003), 2);
+    assert_eq!(profile.executions(0x3008), 2);
+    assert_eq!(profile.opcode(4), 4);
+    assert_eq!(profile.opcode(12), 4);
+    assert_eq!(profile.opcode(15), 1);
+
+    assert_eq!(
+      profile.subroutines(),
+      [
+        Subroutine {
+          address: 0x3000,
+          calls: 1,
+          inclusive: 17,
+          exclusive: 3
+        },
+        Subroutine {
+          address: 0x3003,
+          calls: 2,
+          inclusive: 14,
+          exclusive: 10
+        },
+        Subroutine {
+          address: 0x3008,
+          calls: 2,
+          inclusive: 4,
+          exclusive: 4
+        },
+      ]
+    );
+  }
+
+  #[test]
+  fn test_recursion() {
+    let mut profile = Profile::new(0x3000);
+    let executed = |pc, instruction| Step::Executed { pc, instruction };
+
+    // x3000 calls x4000, which calls itself once
+    profile.record(&executed(0x3000, 0x4FFF), 0x4000);
+    profile.record(&executed(0x4000, 0x4FFF), 0x4000);
+    profile.record(&executed(0x4000, 0x1021), 0x4001);
+    profile.record(&executed(0x4001, 0xC1C0), 0x4001);
+    profile.record(&executed(0x4001, 0xC1C0), 0x3001);
+
+    let subroutine = &profile.subroutines()[1];
+    assert_eq!(subroutine.calls, 2);
+    assert_eq!(subroutine.inclusive, 4);
+    assert_eq!(subroutine.exclusive, 4);
+
+    // returning past the root stays there
+    profile.record(&executed(0x3001, 0xC1C0), 0x5000);
+    assert_eq!(profile.subroutines()[0].exclusive, 2);
+  }
+
+  #[test]
+  fn test_report() {
+    let (profile, symbols) = profile();
+
+    let mut report = Vec::new();
+    profile.write_report(&symbols, &mut report).unwrap();
+    let report = String::from_utf8(report).unwrap();
+    let lines: Vec<&str> = report.lines().collect();
+
+    assert_eq!(lines[0], "17 instructions");
+    assert_eq!(
+      lines[3],
+      "x3000 <start>                      1          17           3"
+    );
+    assert_eq!(lines[8], "add                                8");
+    assert!(report.contains("x3008 <once>                       2  add r1, r1, #1\n"));
+
+    let mut folded = Vec::new();
+    profile.write_folded(&symbols, &mut folded).unwrap();
+
+    assert_eq!(
+      String::from_utf8(folded).unwrap(),
+      "start 3\nstart;twice 10\nstart;twice;once 4\n"
+    );
+  }
+}
@@ -1,4 +1,6 @@
-#[derive(Debug, Clone, Copy)]
+use crate::error::VmError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Register {
   R0 = 0,
   R1,
@@ -10,12 +12,18 @@ pub enum Register {
   R7,
   Pc,
   Cond,
+  /// Privilege and priority bits of the PSR, the condition codes are kept in
+  /// `Cond`
+  Psr,
+  SavedSsp,
+  SavedUsp,
   Count,
 }
 
-impl From<u16> for Register {
-  fn from(r: u16) -> Self {
-    match r {
+impl Register {
+  /// The general purpose register in the low 3 bits of an instruction field.
+  pub fn general(bits: u16) -> Self {
+    match bits & 0x7 {
       0 => Register::R0,
       1 => Register::R1,
       2 => Register::R2,
@@ -23,10 +31,23 @@ impl From<u16> for Register {
       4 => Register::R4,
       5 => Register::R5,
       6 => Register::R6,
-      7 => Register::R7,
-      8 => Register::Pc,
-      9 => Register::Cond,
-      _ => panic!("Invalid register"),
+      _ => Register::R7,
+    }
+  }
+}
+
+impl TryFrom<u16> for Register {
+  type Error = VmError;
+
+  fn try_from(r: u16) -> Result<Self, VmError> {
+    match r {
+      0..=7 => Ok(Register::general(r)),
+      8 => Ok(Register::Pc),
+      9 => Ok(Register::Cond),
+      10 => Ok(Register::Psr),
+      11 => Ok(Register::SavedSsp),
+      12 => Ok(Register::SavedUsp),
+      _ => Err(VmError::InvalidRegister(r)),
     }
   }
 }
@@ -37,31 +58,121 @@ impl From<Register> for usize {
   }
 }
 
-static mut REG: [u16; Register::Count as usize] = [0; Register::Count as usize];
+#[derive(Clone)]
+pub struct Registers([u16; Register::Count as usize]);
 
-#[inline]
-pub fn reg_r(reg: Register) -> &'static mut u16 {
-  unsafe { &mut REG[reg as usize] }
+const F_P: u16 = 1 << 0;
+const F_Z: u16 = 1 << 1;
+const F_N: u16 = 1 << 2;
+
+/// PSR bit set while running in user mode.
+pub const PSR_USER: u16 = 1 << 15;
+/// PSR bits holding the priority level, 0 to 7.
+pub const PSR_PRIORITY: u16 = 0x0700;
+const PSR_CONDITION: u16 = F_N | F_Z | F_P;
+
+/// Letter of the condition code set in `cond`, as kept in the `Cond` register.
+pub fn cc_letter(cond: u16) -> char {
+  match cond {
+    F_N => 'n',
+    F_Z => 'z',
+    F_P => 'p',
+    _ => '-',
+  }
 }
 
-#[inline]
-pub fn reg(reg: u16) -> &'static mut u16 {
-  unsafe { &mut REG[reg as usize] }
+/// Where the supervisor stack starts, growing down below the user program.
+pub const SSP_START: u16 = 0x3000;
+/// Where the user stack of a loaded program starts, growing down from the
+/// device registers.
+pub const USP_START: u16 = 0xFE00;
+
+impl Default for Registers {
+  fn default() -> Self {
+    let mut registers = Self([0; Register::Count as usize]);
+    // the machine starts in supervisor mode, on the supervisor stack
+    *registers.reg_r(Register::R6) = SSP_START;
+    *registers.reg_r(Register::SavedSsp) = SSP_START;
+    registers
+  }
 }
 
-const F_P: u16 = 1 << 0;
-const F_Z: u16 = 1 << 1;
-const F_N: u16 = 1 << 2;
+impl Registers {
+  #[inline]
+  pub fn reg_r(&mut self, reg: Register) -> &mut u16 {
+    &mut self.0[reg as usize]
+  }
 
-#[inline]
-pub fn update_flag(r: Register) {
-  let r = *reg_r(r);
-
-  *reg_r(Register::Cond) = if r == 0 {
-    F_Z
-  } else if r >> 15 == 1 {
-    F_N
-  } else {
-    F_P
-  };
+  #[inline]
+  pub fn reg(&mut self, reg: u16) -> &mut u16 {
+    &mut self.0[reg as usize]
+  }
+
+  #[inline]
+  pub fn get(&self, reg: Register) -> u16 {
+    self.0[reg as usize]
+  }
+
+  #[inline]
+  pub fn update_flag(&mut self, r: Register) {
+    let r = self.get(r);
+
+    *self.reg_r(Register::Cond) = if r == 0 {
+      F_Z
+    } else if r >> 15 == 1 {
+      F_N
+    } else {
+      F_P
+    };
+  }
+
+  /// The full processor status register, privilege, priority and condition
+  /// codes.
+  pub fn psr(&self) -> u16 {
+    self.get(Register::Psr) | self.get(Register::Cond)
+  }
+
+  pub fn set_psr(&mut self, psr: u16) {
+    *self.reg_r(Register::Psr) = psr & (PSR_USER | PSR_PRIORITY);
+    *self.reg_r(Register::Cond) = psr & PSR_CONDITION;
+  }
+
+  pub fn user_mode(&self) -> bool {
+    self.get(Register::Psr) & PSR_USER != 0
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_psr() {
+    let mut registers = Registers::default();
+    assert_eq!(registers.psr(), 0);
+    assert!(!registers.user_mode());
+
+    registers.set_psr(0x8302);
+    assert!(registers.user_mode());
+    assert_eq!(registers.get(Register::Cond), F_Z);
+    assert_eq!(registers.psr(), 0x8302);
+
+    *registers.reg_r(Register::R0) = 0xFFFF;
+    registers.update_flag(Register::R0);
+    assert_eq!(registers.psr(), 0x8304);
+
+    // unused bits are dropped
+    registers.set_psr(0x78F8);
+    assert_eq!(registers.psr(), 0x0000);
+  }
+
+  #[test]
+  fn test_try_from() {
+    assert_eq!(Register::try_from(3).unwrap(), Register::R3);
+    assert_eq!(Register::try_from(12).unwrap(), Register::SavedUsp);
+    assert!(matches!(
+      Register::try_from(13),
+      Err(VmError::InvalidRegister(13))
+    ));
+  }
 }
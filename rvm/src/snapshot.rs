@@ -0,0 +1,280 @@
+use std::io::{self, Read, Write};
+
+use crate::binary::{read_u16, read_u8};
+use crate::error::VmError;
+use crate::memory::MEMORY_SIZE;
+use crate::register::Register;
+use crate::Machine;
+
+/// Start of every snapshot file.
+const MAGIC: &[u8; 4] = b"RVMS";
+/// Format version, bumped whenever the layout changes.
+const VERSION: u8 = 1;
+
+const NATIVE_TRAPS: u8 = 1 << 0;
+
+impl Machine {
+  /// Writes the whole state of the machine, to continue from with
+  /// [`restore_snapshot`](Machine::restore_snapshot).
+  ///
+  /// Little-endian after the magic and version: the image start, flags, the
+  /// registers, `KBDR`, `KBSR`, `DSR` and `MCR`, the input not read yet, then
+  /// every memory cell. Devices attached with
+  /// [`attach`](crate::memory::Memory::attach), trap handlers, breakpoints and
+  /// the undo log aren't saved.
+  pub fn save_snapshot(&mut self, output: &mut impl Write) -> io::Result<()> {
+    // take in whatever was typed so far, so it is saved too
+    self.memory.keyboard.ready();
+
+    let memory = &self.memory;
+    let mut bytes = Vec::with_capacity(MEMORY_SIZE * 2 + 64);
+
+    bytes.extend(MAGIC);
+    bytes.push(VERSION);
+    bytes.extend(self.pc_start.to_le_bytes());
+    bytes.push(if self.native_traps { NATIVE_TRAPS } else { 0 });
+
+    bytes.push(Register::Count as u8);
+
+    for r in 0..Register::Count as u16 {
+      bytes.extend(
+        self
+          .registers
+          .get(Register::try_from(r).unwrap())
+          .to_le_bytes(),
+      );
+    }
+
+    for word in [
+      memory.keyboard.data,
+      memory.keyboard.status,
+      memory.display.status,
+      memory.control.mcr,
+    ] {
+      bytes.extend(word.to_le_bytes());
+    }
+
+    bytes.extend((memory.keyboard.buffer.len() as u32).to_le_bytes());
+    bytes.extend(&memory.keyboard.buffer);
+
+    for cell in memory.cells.iter() {
+      bytes.extend(cell.to_le_bytes());
+    }
+
+    output.write_all(&bytes)
+  }
+
+  /// Replaces the state of the machine with a snapshot written by
+  /// [`save_snapshot`](Machine::save_snapshot), leaving it untouched when the
+  /// snapshot can't be read.
+  ///
+  /// The saved input goes ahead of anything already queued on the keyboard,
+  /// where it keeps its source. Output goes wherever the display already sends
+  /// it, and the undo log starts over.
+  pub fn restore_snapshot(&mut self, input: &mut impl Read) -> Result<(), VmError> {
+    let mut header = [0; 5];
+    input.read_exact(&mut header)?;
+
+    if header[..4] != MAGIC[..] {
+      return Err(VmError::MalformedSnapshot(
+        "not a snapshot file".to_string(),
+      ));
+    }
+
+    if header[4] != VERSION {
+      return Err(VmError::MalformedSnapshot(format!(
+        "version {} isn't supported",
+        header[4]
+      )));
+    }
+
+    let pc_start = read_u16(input)?;
+    let flags = read_u8(input)?;
+
+    let count = read_u8(input)?;
+
+    if count != Register::Count as u8 {
+      return Err(VmError::MalformedSnapshot(format!(
+        "{count} registers instead of {}",
+        Register::Count as u8
+      )));
+    }
+
+    let mut registers = [0; Register::Count as usize];
+
+    for value in &mut registers {
+      *value = read_u16(input)?;
+    }
+
+    let mut devices = [0; 4];
+
+    for value in &mut devices {
+      *value = read_u16(input)?;
+    }
+
+    let mut buffer = [0; 4];
+    input.read_exact(&mut buffer)?;
+    let length = u32::from_le_bytes(buffer);
+
+    // read rather than allocated up front, as a corrupt length can be anything
+    let mut pending = Vec::new();
+    input
+      .by_ref()
+      .take(u64::from(length))
+      .read_to_end(&mut pending)?;
+
+    if pending.len() as u64 != u64::from(length) {
+      return Err(VmError::MalformedSnapshot(format!(
+        "{} bytes of input instead of {length}",
+        pending.len()
+      )));
+    }
+
+    let mut bytes = vec![0; MEMORY_SIZE * 2];
+    input.read_exact(&mut bytes)?;
+
+    self.pc_start = pc_start;
+    self.native_traps = flags & NATIVE_TRAPS != 0;
+
+    for (r, value) in registers.into_iter().enumerate() {
+      *self.registers.reg(r as u16) = value;
+    }
+
+    let memory = &mut self.memory;
+    let [data, status, display, mcr] = devices;
+
+    memory.keyboard.data = data;
+    memory.keyboard.status = status;
+    memory.display.status = display;
+    memory.control.mcr = mcr;
+    memory.keyboard.unread(&pending);
+
+    for (cell, word) in memory.cells.iter_mut().zip(bytes.chunks_exact(2)) {
+      *cell = u16::from_le_bytes([word[0], word[1]]);
+    }
+
+    self.clear_history();
+
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::device::{Display, Output, DSR};
+  use crate::step::StopReason;
+
+  /// Echoes input until a `q`, counting the characters in R1.
+  ///
+  /// x3000 GETC; ADD R1, R1, #1; LD R2, MINUS_Q; ADD R2, R2, R0; BRz #2; OUT;
+  /// BRnzp x3000; HALT; MINUS_Q x-71
+  fn machine() -> Machine {
+    let mut vm = Machine::with_words(
+      0x3000,
+      &[
+        0xF020, 0x1261, 0x2405, 0x1480, 0x0402, 0xF021, 0x0FF9, 0xF025, 0xFF8F,
+      ],
+    );
+    vm.memory.display = Display::new(Output::Buffer(Vec::new()));
+    vm
+  }
+
+  #[test]
+  fn test_round_trip() {
+    let mut vm = machine();
+    vm.memory.keyboard.push(b"abcq");
+    vm.memory.write(DSR, 0x4000);
+
+    assert!(matches!(vm.run_for(14), StopReason::BudgetExhausted));
+
+    let mut snapshot = Vec::new();
+    vm.save_snapshot(&mut snapshot).unwrap();
+
+    let mut restored = Machine::new();
+    restored.memory.display = Display::new(Output::Buffer(Vec::new()));
+    restored.restore_snapshot(&mut snapshot.as_slice()).unwrap();
+
+    assert_eq!(restored.pc_start, 0x3000);
+    assert!(restored.native_traps);
+    assert_eq!(restored.memory.read(DSR), 0xC000);
+
+    vm.resume().unwrap();
+    restored.resume().unwrap();
+
+    assert_eq!(vm.registers.get(Register::R1), 4);
+    assert_eq!(restored.registers.get(Register::R1), 4);
+    assert_eq!(
+      restored.registers.get(Register::Pc),
+      vm.registers.get(Register::Pc)
+    );
+    assert_eq!(
+      vm.memory.display.take_output(),
+      b"input: output: ainput: output: binput: output: cinput: "
+    );
+    assert_eq!(
+      restored.memory.display.take_output(),
+      b"input: output: cinput: "
+    );
+    assert!(!restored.running());
+  }
+
+  #[test]
+  fn test_restore_halted() {
+    let mut vm = machine();
+    vm.memory.keyboard.push(b"q");
+    vm.run(0).unwrap();
+
+    let mut snapshot = Vec::new();
+    vm.save_snapshot(&mut snapshot).unwrap();
+
+    let mut restored = Machine::new();
+    restored.restore_snapshot(&mut snapshot.as_slice()).unwrap();
+
+    assert!(!restored.running());
+    assert!(matches!(restored.resume(), Err(VmError::Halted)));
+  }
+
+  #[test]
+  fn test_malformed() {
+    let mut vm = machine();
+
+    let mut snapshot = Vec::new();
+    vm.save_snapshot(&mut snapshot).unwrap();
+
+    let mut restored = Machine::new();
+
+    assert!(matches!(
+      restored.restore_snapshot(&mut &b"RVMT\x01"[..]),
+      Err(VmError::MalformedSnapshot(_))
+    ));
+
+    snapshot[4] = 2;
+    assert!(matches!(
+      restored.restore_snapshot(&mut snapshot.as_slice()),
+      Err(VmError::MalformedSnapshot(_))
+    ));
+
+    snapshot[4] = VERSION;
+
+    // a length past the end of the file, rather than a 4 GiB buffer
+    let length = 9 + 2 * Register::Count as usize + 8;
+    let mut huge = snapshot.clone();
+    assert_eq!(huge.len(), length + 4 + MEMORY_SIZE * 2);
+    huge[length..length + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+    assert!(matches!(
+      restored.restore_snapshot(&mut huge.as_slice()),
+      Err(VmError::MalformedSnapshot(_))
+    ));
+
+    snapshot.pop();
+    assert!(matches!(
+      restored.restore_snapshot(&mut snapshot.as_slice()),
+      Err(VmError::Io(_))
+    ));
+
+    // nothing was restored
+    assert_eq!(restored.pc_start, 0);
+    assert_eq!(restored.memory.read(0x3000), 0);
+  }
+}
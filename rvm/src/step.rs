@@ -0,0 +1,293 @@
+use rvm_compiler::instructions::Instruction;
+use rvm_compiler::registers;
+
+use crate::device::Interrupt;
+use crate::error::VmError;
+use crate::exception::Exception;
+use crate::register::Register;
+use crate::Machine;
+
+/// What a single [`step`](Machine::step) did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+  /// Executed `instruction`, fetched from `pc`
+  Executed { pc: u16, instruction: u16 },
+  /// Entered the handler of an interrupt, without executing anything
+  Interrupt(Interrupt),
+  /// Entered the handler of `exception`, raised by the instruction at `pc`
+  Exception { pc: u16, exception: Exception },
+}
+
+impl Step {
+  /// How the step changed the depth of calls, given the pc after it: 1 for
+  /// entering a subroutine, trap routine or handler, -1 for returning from one.
+  pub fn call_depth(&self, pc: u16) -> i32 {
+    match *self {
+      Step::Executed {
+        pc: from,
+        instruction,
+      } => match Instruction::decode(instruction) {
+        Instruction::Jsr(_) | Instruction::Jsrr(_) => 1,
+        // traps run on the host don't leave the caller
+        Instruction::Trap(_) if pc != from.wrapping_add(1) => 1,
+        Instruction::Rti | Instruction::Jmp(registers::Register::R7) => -1,
+        _ => 0,
+      },
+      Step::Interrupt(_) | Step::Exception { .. } => 1,
+    }
+  }
+}
+
+/// Why [`run_for`](Machine::run_for) or [`run_until`](Machine::run_until)
+/// returned.
+#[derive(Debug)]
+pub enum StopReason {
+  /// The clock enable bit of `MCR` was cleared
+  Halted,
+  /// Every step allowed was taken
+  BudgetExhausted,
+  /// The pc reached a breakpoint, at this address
+  Breakpoint(u16),
+  /// The predicate held after a step
+  Condition,
+  Error(VmError),
+}
+
+impl Machine {
+  /// Takes a pending interrupt, or executes the instruction at the pc.
+  ///
+  /// An exception with a handler is entered, anything else is returned with
+  /// the pc left on the instruction at fault.
+  pub fn step(&mut self) -> Result<Step, VmError> {
+    if !self.running() {
+      return Err(VmError::Halted);
+    }
+
+    self.history_begin();
+
+    let step = self.advance();
+    self.history_end(step.is_ok());
+
+    if let Ok(step) = &step {
+      self.profile_step(step);
+      self.coverage_step(step);
+    }
+
+    step
+  }
+
+  fn advance(&mut self) -> Result<Step, VmError> {
+    if let Some(interrupt) = self.pending_interrupt() {
+      self.enter_handler(interrupt.vector, Some(interrupt.priority));
+
+      return Ok(Step::Interrupt(interrupt));
+    }
+
+    let pc = self.registers.get(Register::Pc);
+
+    let result = self.read(pc).and_then(|i| {
+      *self.registers.reg_r(Register::Pc) = pc.wrapping_add(1);
+
+      self.trace_begin();
+      self.op(i).map(|()| i)
+    });
+
+    match result {
+      Ok(instruction) => {
+        self.trace_end(pc, instruction);
+
+        Ok(Step::Executed { pc, instruction })
+      }
+      Err(VmError::Exception(exception)) => {
+        self.raise(exception, pc)?;
+
+        Ok(Step::Exception { pc, exception })
+      }
+      Err(err) => {
+        *self.registers.reg_r(Register::Pc) = pc;
+
+        Err(err)
+      }
+    }
+  }
+
+  /// Takes at most `max_steps` steps from the current pc.
+  pub fn run_for(&mut self, max_steps: u64) -> StopReason {
+    self.run_with(Some(max_steps), |_, _| false)
+  }
+
+  /// Steps from the current pc until `predicate` holds after a step.
+  pub fn run_until(&mut self, mut predicate: impl FnMut(&Machine) -> bool) -> StopReason {
+    self.run_with(None, |vm, _| predicate(vm))
+  }
+
+  /// Steps until halted, out of steps, at a breakpoint or `predicate` holds of
+  /// the machine and the step just taken.
+  ///
+  /// Breakpoints are checked after each step, so running again from a
+  /// breakpoint gets past it, and a run split into several still stops at one.
+  pub fn run_with(
+    &mut self,
+    max_steps: Option<u64>,
+    mut predicate: impl FnMut(&Machine, Step) -> bool,
+  ) -> StopReason {
+    let mut steps = 0;
+
+    loop {
+      if !self.running() {
+        return StopReason::Halted;
+      }
+
+      if max_steps == Some(steps) {
+        return StopReason::BudgetExhausted;
+      }
+
+      let step = match self.step() {
+        Ok(step) => step,
+        Err(err) => return StopReason::Error(err),
+      };
+
+      steps += 1;
+
+      if predicate(self, step) {
+        return StopReason::Condition;
+      }
+
+      let pc = self.registers.get(Register::Pc);
+
+      if self.running() && self.breakpoints.contains(&pc) {
+        return StopReason::Breakpoint(pc);
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_step() {
+    // ADD R0, R0, #1; RES; HALT
+    let mut vm = Machine::with_words(0x3000, &[0x1021, 0xD000, 0xF025]);
+    vm.memory.write(0x0101, 0x3002);
+    *vm.registers.reg_r(Register::R6) = 0x3000;
+
+    assert_eq!(
+      vm.step().unwrap(),
+      Step::Executed {
+        pc: 0x3000,
+        instruction: 0x1021
+      }
+    );
+    assert_eq!(
+      vm.step().unwrap(),
+      Step::Exception {
+        pc: 0x3001,
+        exception: Exception::IllegalOpcode
+      }
+    );
+    assert_eq!(vm.registers.get(Register::Pc), 0x3002);
+
+    vm.step().unwrap();
+    assert!(matches!(vm.step(), Err(VmError::Halted)));
+  }
+
+  #[test]
+  fn test_call_depth() {
+    let executed = |instruction| Step::Executed {
+      pc: 0x3000,
+      instruction,
+    };
+
+    // JSR, TRAP through the table and natively, RET, RTI, ADD
+    assert_eq!(executed(0x4802).call_depth(0x3003), 1);
+    assert_eq!(executed(0xF020).call_depth(0x0400), 1);
+    assert_eq!(executed(0xF020).call_depth(0x3001), 0);
+    assert_eq!(executed(0xC1C0).call_depth(0x3010), -1);
+    assert_eq!(executed(0x8000).call_depth(0x3010), -1);
+    assert_eq!(executed(0x1021).call_depth(0x3001), 0);
+
+    let exception = Step::Exception {
+      pc: 0x3000,
+      exception: Exception::IllegalOpcode,
+    };
+    assert_eq!(exception.call_depth(0x1000), 1);
+  }
+
+  #[test]
+  fn test_step_interrupt() {
+    let mut vm = Machine::with_words(0x3000, &[0x0000]);
+    vm.memory.keyboard.push(b"k");
+    vm.memory.write(crate::device::KBSR, 0x4000);
+    vm.memory.write(0x0180, 0x1000);
+
+    assert_eq!(
+      vm.step().unwrap(),
+      Step::Interrupt(Interrupt {
+        priority: 4,
+        vector: 0x80
+      })
+    );
+    assert_eq!(vm.registers.get(Register::Pc), 0x1000);
+  }
+
+  #[test]
+  fn test_run_for() {
+    // ADD R0, R0, #1; BRnzp #-2
+    let mut vm = Machine::with_words(0x3000, &[0x1021, 0x0FFE]);
+
+    assert!(matches!(vm.run_for(10), StopReason::BudgetExhausted));
+    assert_eq!(vm.registers.get(Register::R0), 5);
+
+    assert!(matches!(vm.run_for(0), StopReason::BudgetExhausted));
+    assert_eq!(vm.registers.get(Register::R0), 5);
+  }
+
+  #[test]
+  fn test_run_until() {
+    // ADD R0, R0, #1; BRnzp #-2
+    let mut vm = Machine::with_words(0x3000, &[0x1021, 0x0FFE]);
+
+    let reason = vm.run_until(|vm| vm.registers.get(Register::R0) == 3);
+    assert!(matches!(reason, StopReason::Condition));
+    assert_eq!(vm.registers.get(Register::Pc), 0x3001);
+  }
+
+  #[test]
+  fn test_breakpoints() {
+    // ADD R0, R0, #1; ADD R0, R0, #1; BRnzp #-3
+    let mut vm = Machine::with_words(0x3000, &[0x1021, 0x1021, 0x0FFD]);
+    vm.breakpoints.insert(0x3001);
+
+    assert!(matches!(
+      vm.run_until(|_| false),
+      StopReason::Breakpoint(0x3001)
+    ));
+    assert_eq!(vm.registers.get(Register::R0), 1);
+
+    // continuing gets past the breakpoint and around the loop to it again
+    assert!(matches!(vm.run_for(100), StopReason::Breakpoint(0x3001)));
+    assert_eq!(vm.registers.get(Register::R0), 3);
+
+    // a run ending on a breakpoint stops at it rather than running out of steps
+    vm.reset_pc(0);
+    assert!(matches!(vm.run_for(1), StopReason::Breakpoint(0x3001)));
+  }
+
+  #[test]
+  fn test_run_until_halted_or_error() {
+    // HALT
+    let mut vm = Machine::with_words(0x3000, &[0xF025]);
+    assert!(matches!(vm.run_for(10), StopReason::Halted));
+    assert!(matches!(vm.run_for(10), StopReason::Halted));
+
+    // RES
+    let mut vm = Machine::with_words(0x3000, &[0xD000]);
+    assert!(matches!(
+      vm.run_for(10),
+      StopReason::Error(VmError::Exception(Exception::IllegalOpcode))
+    ));
+    assert_eq!(vm.registers.get(Register::Pc), 0x3000);
+  }
+}
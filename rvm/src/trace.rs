@@ -0,0 +1,472 @@
+use std::fmt::{self, Display, Formatter};
+use std::io::{self, ErrorKind, Read, Write};
+use std::ops::RangeInclusive;
+
+use rvm_compiler::instructions::Instruction;
+
+use crate::binary::{read_u16, read_u8};
+use crate::debugger::disassemble;
+use crate::ops::OP_NAMES;
+use crate::register::{cc_letter, Register, Registers};
+use crate::Machine;
+
+/// Start of every trace file, followed by its version.
+const MAGIC: &[u8; 4] = b"RVMT";
+const VERSION: u8 = 1;
+
+/// Registers a trap can write, compared before and after it.
+const GENERAL: [Register; 8] = [
+  Register::R0,
+  Register::R1,
+  Register::R2,
+  Register::R3,
+  Register::R4,
+  Register::R5,
+  Register::R6,
+  Register::R7,
+];
+
+/// A memory access made by an instruction, with the value read or written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+  Read { address: u16, value: u16 },
+  Write { address: u16, value: u16 },
+}
+
+/// What one executed instruction did.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+  pub pc: u16,
+  pub instruction: u16,
+  /// Registers written by the instruction, with their new values
+  pub registers: Vec<(Register, u16)>,
+  /// Memory accessed by the instruction itself, not by host trap routines
+  pub accesses: Vec<Access>,
+  /// Condition codes after the instruction, as in the `Cond` register
+  pub cond: u16,
+}
+
+/// Which instructions are recorded, every one by default.
+#[derive(Debug, Clone, Default)]
+pub struct TraceFilter {
+  /// Only instructions fetched from these addresses
+  pub addresses: Option<RangeInclusive<u16>>,
+  /// Only these opcodes, bit `n` set for opcode `n`
+  pub opcodes: Option<u16>,
+}
+
+impl TraceFilter {
+  pub fn matches(&self, pc: u16, instruction: u16) -> bool {
+    let address = self
+      .addresses
+      .as_ref()
+      .is_none_or(|range| range.contains(&pc));
+    let opcode = self
+      .opcodes
+      .is_none_or(|opcodes| opcodes & 1 << (instruction >> 12) != 0);
+
+    address && opcode
+  }
+
+  /// Opcode bits for a comma separated list of names from
+  /// [`OP_NAMES`](crate::ops::OP_NAMES).
+  pub fn parse_opcodes(names: &str) -> Result<u16, String> {
+    names.split(',').try_fold(0, |opcodes, name| {
+      let name = name.trim();
+
+      OP_NAMES
+        .iter()
+        .position(|&op| op == name)
+        .map(|opcode| opcodes | 1 << opcode)
+        .ok_or_else(|| format!("unknown opcode `{name}`"))
+    })
+  }
+}
+
+/// Records executed instructions to a trace file.
+pub struct Tracer {
+  output: Box<dyn Write>,
+  filter: TraceFilter,
+  /// Registers before the current instruction
+  before: Registers,
+  accesses: Vec<Access>,
+  /// The first write that failed, after which nothing more is written
+  error: Option<io::Error>,
+}
+
+impl Tracer {
+  fn begin(&mut self, registers: &Registers) {
+    self.before = registers.clone();
+    self.accesses.clear();
+  }
+
+  fn end(&mut self, pc: u16, instruction: u16, registers: &Registers) {
+    if self.error.is_some() || !self.filter.matches(pc, instruction) {
+      return;
+    }
+
+    let changed = |r: Register| registers.get(r) != self.before.get(r);
+
+    let written = match Instruction::decode(instruction) {
+      // whatever the host routine or switching to the supervisor stack changed
+      Instruction::Trap(_) => GENERAL.into_iter().filter(|&r| changed(r)).collect(),
+      decoded => destination(decoded).into_iter().collect::<Vec<_>>(),
+    };
+
+    let psr = changed(Register::Psr).then_some(Register::Psr);
+
+    let written = written
+      .into_iter()
+      .chain(psr)
+      .map(|r| (r, registers.get(r)))
+      .collect();
+
+    let record = Record {
+      pc,
+      instruction,
+      registers: written,
+      accesses: std::mem::take(&mut self.accesses),
+      cond: registers.get(Register::Cond),
+    };
+
+    if let Err(err) = record.write(&mut self.output) {
+      self.error = Some(err);
+    }
+  }
+}
+
+/// The register an instruction other than a trap writes, if any.
+fn destination(instruction: Instruction) -> Option<Register> {
+  match instruction {
+    Instruction::Add1(dr, ..)
+    | Instruction::Add2(dr, ..)
+    | Instruction::And1(dr, ..)
+    | Instruction::And2(dr, ..)
+    | Instruction::Not(dr, _)
+    | Instruction::Ld(dr, _)
+    | Instruction::Ldi(dr, _)
+    | Instruction::Ldr(dr, ..)
+    | Instruction::Lea(dr, _) => Some(Register::general(dr.bytecode())),
+    Instruction::Jsr(_) | Instruction::Jsrr(_) => Some(Register::R7),
+    // popping the PC and PSR moves the stack pointer
+    Instruction::Rti => Some(Register::R6),
+    _ => None,
+  }
+}
+
+impl Record {
+  /// Writes the record little-endian: pc, instruction, condition codes, then a
+  /// count and entries for registers and for accesses.
+  pub fn write(&self, output: &mut impl Write) -> io::Result<()> {
+    let mut bytes = Vec::with_capacity(16);
+
+    bytes.extend(self.pc.to_le_bytes());
+    bytes.extend(self.instruction.to_le_bytes());
+    bytes.push(self.cond as u8);
+
+    bytes.push(self.registers.len() as u8);
+
+    for &(r, value) in &self.registers {
+      bytes.push(r as u8);
+      bytes.extend(value.to_le_bytes());
+    }
+
+    bytes.push(self.accesses.len() as u8);
+
+    for access in &self.accesses {
+      let (kind, address, value) = match *access {
+        Access::Read { address, value } => (0, address, value),
+        Access::Write { address, value } => (1, address, value),
+      };
+
+      bytes.push(kind);
+      bytes.extend(address.to_le_bytes());
+      bytes.extend(value.to_le_bytes());
+    }
+
+    output.write_all(&bytes)
+  }
+
+  /// Reads the next record, or `None` at the end of the trace.
+  pub fn read(input: &mut impl Read) -> io::Result<Option<Record>> {
+    let mut header = [0; 5];
+
+    match input.read_exact(&mut header) {
+      Ok(()) => {}
+      Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+      Err(err) => return Err(err),
+    }
+
+    let pc = u16::from_le_bytes([header[0], header[1]]);
+    let instruction = u16::from_le_bytes([header[2], header[3]]);
+    let cond = u16::from(header[4]);
+
+    let mut registers = Vec::new();
+
+    for _ in 0..read_u8(input)? {
+      let r = Register::try_from(u16::from(read_u8(input)?))
+        .map_err(|err| io::Error::new(ErrorKind::InvalidData, err.to_string()))?;
+
+      registers.push((r, read_u16(input)?));
+    }
+
+    let mut accesses = Vec::new();
+
+    for _ in 0..read_u8(input)? {
+      let kind = read_u8(input)?;
+      let address = read_u16(input)?;
+      let value = read_u16(input)?;
+
+      accesses.push(match kind {
+        0 => Access::Read { address, value },
+        1 => Access::Write { address, value },
+        _ => {
+          return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("unknown access kind {kind}"),
+          ))
+        }
+      });
+    }
+
+    Ok(Some(Record {
+      pc,
+      instruction,
+      registers,
+      accesses,
+      cond,
+    }))
+  }
+}
+
+fn register_name(r: Register) -> String {
+  match r {
+    Register::Psr => "psr".to_string(),
+    r => format!("r{}", r as u16),
+  }
+}
+
+/// One line: address, word, disassembly, then what the instruction wrote and
+/// accessed.
+impl Display for Record {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    let asm = disassemble(self.pc, self.instruction);
+
+    write!(f, "x{:04X}  x{:04X}  {asm:<22}", self.pc, self.instruction)?;
+
+    for &(r, value) in &self.registers {
+      write!(f, "  {}=x{value:04X}", register_name(r))?;
+    }
+
+    for access in &self.accesses {
+      match access {
+        Access::Read { address, value } => write!(f, "  [x{address:04X}]->x{value:04X}")?,
+        Access::Write { address, value } => write!(f, "  [x{address:04X}]<-x{value:04X}")?,
+      }
+    }
+
+    write!(f, "  cc={}", cc_letter(self.cond))
+  }
+}
+
+/// Writes a trace file as text, one line per instruction.
+pub fn render(input: &mut impl Read, output: &mut impl Write) -> io::Result<()> {
+  let mut header = [0; 5];
+  input.read_exact(&mut header)?;
+
+  if header[..4] != MAGIC[..] || header[4] != VERSION {
+    return Err(io::Error::new(ErrorKind::InvalidData, "not a trace file"));
+  }
+
+  while let Some(record) = Record::read(input)? {
+    writeln!(output, "{record}")?;
+  }
+
+  Ok(())
+}
+
+impl Machine {
+  /// Records every executed instruction that passes `filter` to `output`,
+  /// until [`stop_trace`](Machine::stop_trace).
+  pub fn start_trace(
+    &mut self,
+    mut output: impl Write + 'static,
+    filter: TraceFilter,
+  ) -> io::Result<()> {
+    output.write_all(MAGIC)?;
+    output.write_all(&[VERSION])?;
+
+    self.tracer = Some(Tracer {
+      output: Box::new(output),
+      filter,
+      before: Registers::default(),
+      accesses: Vec::new(),
+      error: None,
+    });
+
+    Ok(())
+  }
+
+  /// Stops tracing, flushing the trace and returning the first error writing
+  /// it.
+  pub fn stop_trace(&mut self) -> io::Result<()> {
+    let Some(mut tracer) = self.tracer.take() else {
+      return Ok(());
+    };
+
+    match tracer.error {
+      Some(err) => Err(err),
+      None => tracer.output.flush(),
+    }
+  }
+
+  /// Called before executing an instruction.
+  pub(crate) fn trace_begin(&mut self) {
+    if let Some(tracer) = &mut self.tracer {
+      tracer.begin(&self.registers);
+    }
+  }
+
+  /// Called after executing the instruction `instruction` fetched from `pc`.
+  pub(crate) fn trace_end(&mut self, pc: u16, instruction: u16) {
+    if let Some(tracer) = &mut self.tracer {
+      tracer.end(pc, instruction, &self.registers);
+    }
+  }
+
+  pub(crate) fn trace_access(&mut self, access: Access) {
+    if let Some(tracer) = &mut self.tracer {
+      tracer.accesses.push(access);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::cell::RefCell;
+  use std::rc::Rc;
+
+  use super::*;
+
+  /// A writer that can still be read after the machine owns it.
+  #[derive(Clone, Default)]
+  struct Shared(Rc<RefCell<Vec<u8>>>);
+
+  impl Write for Shared {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+      self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+      Ok(())
+    }
+  }
+
+  /// Runs `words` from `x3000` until halted, tracing with `filter`.
+  fn trace(words: &[u16], filter: TraceFilter) -> Vec<u8> {
+    let mut vm = Machine::with_words(0x3000, words);
+
+    let output = Shared::default();
+    vm.start_trace(output.clone(), filter).unwrap();
+    vm.run(0).unwrap();
+    vm.stop_trace().unwrap();
+
+    let trace = output.0.borrow().clone();
+    trace
+  }
+
+  // LD R1, x3004; ADD R1, R1, #-1; STI R1, x3005; HALT; x0005; x4000
+  const PROGRAM: [u16; 6] = [0x2203, 0x127F, 0xB202, 0xF025, 0x0005, 0x4000];
+
+  #[test]
+  fn test_records() {
+    let trace = trace(&PROGRAM, TraceFilter::default());
+    assert_eq!(&trace[..5], b"RVMT\x01");
+
+    let mut input = &trace[5..];
+    let mut records = Vec::new();
+
+    while let Some(record) = Record::read(&mut input).unwrap() {
+      records.push(record);
+    }
+
+    assert_eq!(records.len(), 4);
+    assert_eq!(
+      records[0],
+      Record {
+        pc: 0x3000,
+        instruction: 0x2203,
+        registers: vec![(Register::R1, 5)],
+        accesses: vec![Access::Read {
+          address: 0x3004,
+          value: 5
+        }],
+        cond: 1,
+      }
+    );
+    assert_eq!(
+      records[2].accesses,
+      [
+        Access::Read {
+          address: 0x3005,
+          value: 0x4000
+        },
+        Access::Write {
+          address: 0x4000,
+          value: 4
+        }
+      ]
+    );
+    assert_eq!(records[3].instruction, 0xF025);
+  }
+
+  #[test]
+  fn test_registers_written() {
+    // ADD R0, R0, #0; JSR #0; HALT
+    let trace = trace(&[0x1020, 0x4800, 0xF025], TraceFilter::default());
+
+    let mut input = &trace[5..];
+    let mut records = Vec::new();
+
+    while let Some(record) = Record::read(&mut input).unwrap() {
+      records.push(record);
+    }
+
+    // written even when the value stays the same
+    assert_eq!(records[0].registers, [(Register::R0, 0)]);
+    assert_eq!(records[1].registers, [(Register::R7, 0x3002)]);
+    assert_eq!(records[2].registers, []);
+  }
+
+  #[test]
+  fn test_filter() {
+    let opcodes = TraceFilter::parse_opcodes("ld, sti").unwrap();
+    assert_eq!(opcodes, 1 << 2 | 1 << 11);
+    assert!(TraceFilter::parse_opcodes("mov").is_err());
+
+    let filter = TraceFilter {
+      addresses: Some(0x3000..=0x3002),
+      opcodes: Some(opcodes),
+    };
+    assert!(filter.matches(0x3000, 0x2203));
+    assert!(!filter.matches(0x3001, 0x127F));
+    assert!(!filter.matches(0x3003, 0xB202));
+
+    let trace = trace(&PROGRAM, filter);
+    let mut text = Vec::new();
+    render(&mut trace.as_slice(), &mut text).unwrap();
+
+    assert_eq!(
+      String::from_utf8(text).unwrap(),
+      "x3000  x2203  ld r1, x3004            r1=x0005  [x3004]->x0005  cc=p\n\
+       x3002  xB202  sti r1, x3005           [x3005]->x4000  [x4000]<-x0004  cc=p\n"
+    );
+  }
+
+  #[test]
+  fn test_render_rejects_other_files() {
+    let mut output = Vec::new();
+
+    assert!(render(&mut &b"\x30\x00\x12\x34"[..], &mut output).is_err());
+  }
+}
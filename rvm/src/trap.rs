@@ -1,77 +1,146 @@
-use std::io::{stdin, stdout, Read, Write};
+use std::error::Error;
+use std::io;
 
-use crate::memory::read;
-use crate::register::{reg_r, Register};
-use crate::RUNNING;
+use crate::device::{end_of_input, MCR};
+use crate::error::VmError;
+use crate::memory::Memory;
+use crate::register::{Register, Registers};
+use crate::Machine;
 
-fn read_char() -> char {
-  let mut buffer = String::with_capacity(1);
+/// What the machine does once a host trap handler returns.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrapAction {
+  Continue,
+  /// Clears the clock enable bit of `MCR`, like the HALT trap
+  Halt,
+}
+
+pub type TrapResult = Result<TrapAction, Box<dyn Error>>;
+
+/// A trap routine run by the host, in place of the one in the trap vector
+/// table.
+///
+/// Closures taking the registers and memory are handlers too.
+pub trait TrapHandler {
+  fn trap(&mut self, registers: &mut Registers, memory: &mut Memory) -> TrapResult;
+}
 
-  unsafe {
-    stdin().read_exact(buffer.as_mut_vec()).unwrap();
+impl<F> TrapHandler for F
+where F: FnMut(&mut Registers, &mut Memory) -> TrapResult
+{
+  fn trap(&mut self, registers: &mut Registers, memory: &mut Memory) -> TrapResult {
+    self(registers, memory)
   }
+}
 
-  buffer.as_bytes()[0] as char
+fn read_char(memory: &mut Memory) -> Result<u8, VmError> {
+  memory.keyboard.wait().ok_or_else(|| end_of_input().into())
 }
 
-fn trap_get_char() {
-  let mut lock = stdout().lock();
-  write!(lock, "input: ").unwrap();
-  lock.flush().unwrap();
+fn trap_get_char(registers: &mut Registers, memory: &mut Memory) -> TrapResult {
+  memory.display.print(b"input: ")?;
+
+  *registers.reg_r(Register::R0) = read_char(memory)? as u16;
 
-  *reg_r(Register::R0) = read_char() as u16;
+  Ok(TrapAction::Continue)
 }
 
-fn trap_out() {
-  print!("output: {}", *reg_r(Register::R0) as u8 as char);
+fn trap_out(registers: &mut Registers, memory: &mut Memory) -> TrapResult {
+  let c = registers.get(Register::R0) as u8;
+
+  memory.display.print(b"output: ")?;
+  memory.display.put(c)?;
+
+  Ok(TrapAction::Continue)
 }
 
-fn trap_puts() {
-  let mut address = *reg_r(Register::R0);
+fn trap_puts(registers: &mut Registers, memory: &mut Memory) -> TrapResult {
+  let mut address = registers.get(Register::R0);
 
   loop {
-    let c = read(address) as u8;
+    let c = memory.read(address) as u8;
 
     if c == 0 {
       break;
     }
 
-    print!("{}", c as char);
+    memory.display.put(c)?;
 
-    address += 1;
+    address = address.wrapping_add(1);
   }
+
+  Ok(TrapAction::Continue)
 }
 
-fn trap_in() {
-  trap_get_char();
+fn trap_in(registers: &mut Registers, memory: &mut Memory) -> TrapResult {
+  trap_get_char(registers, memory)?;
 
-  print!("{}", *reg_r(Register::R0) as u8 as char);
+  let c = registers.get(Register::R0) as u8;
+  memory.display.put(c)?;
+
+  Ok(TrapAction::Continue)
 }
 
-fn trap_putsp() {}
+/// Prints two characters per word, low byte first, until a zero byte.
+fn trap_putsp(registers: &mut Registers, memory: &mut Memory) -> TrapResult {
+  let mut address = registers.get(Register::R0);
+
+  'words: loop {
+    let [low, high] = memory.read(address).to_le_bytes();
+
+    for c in [low, high] {
+      if c == 0 {
+        break 'words;
+      }
+
+      memory.display.put(c)?;
+    }
 
-fn trap_halt() {
-  unsafe {
-    RUNNING = false;
+    address = address.wrapping_add(1);
   }
+
+  Ok(TrapAction::Continue)
+}
+
+fn trap_halt(_registers: &mut Registers, _memory: &mut Memory) -> TrapResult {
+  Ok(TrapAction::Halt)
 }
 
-fn trap_in_u16() {
+fn trap_in_u16(registers: &mut Registers, memory: &mut Memory) -> TrapResult {
   let mut buffer = String::new();
 
-  let mut lock = stdout().lock();
-  write!(lock, "input: ").unwrap();
-  lock.flush().unwrap();
-  stdin().read_line(&mut buffer).unwrap();
+  memory.display.print(b"input: ")?;
+
+  loop {
+    let c = read_char(memory)?;
+
+    if c == b'\n' {
+      break;
+    }
+
+    buffer.push(c as char);
+  }
+
+  let Ok(value) = buffer.trim().parse() else {
+    return Err(VmError::BadInput(buffer).into());
+  };
 
-  *reg_r(Register::R0) = buffer.trim().parse().unwrap();
+  *registers.reg_r(Register::R0) = value;
+
+  Ok(TrapAction::Continue)
 }
 
-fn trap_out_u16() {
-  println!("output: {}", *reg_r(Register::R0));
+fn trap_out_u16(registers: &mut Registers, memory: &mut Memory) -> TrapResult {
+  let output = format!("output: {}\n", registers.get(Register::R0));
+
+  memory.display.print(output.as_bytes())?;
+
+  Ok(TrapAction::Continue)
 }
 
-static mut TRAPS: [fn(); 8] = [
+type NativeTrap = fn(&mut Registers, &mut Memory) -> TrapResult;
+
+static TRAPS: [NativeTrap; 8] = [
   trap_get_char,
   trap_out,
   trap_puts,
@@ -82,6 +151,346 @@ static mut TRAPS: [fn(); 8] = [
   trap_out_u16,
 ];
 
-pub fn trap(i: u16) {
-  unsafe { TRAPS[((i & 0xFF) - 0x20) as usize]() }
+impl Machine {
+  /// Runs `handler` for TRAP `vector`, ahead of the native routines and the
+  /// trap vector table.
+  pub fn set_trap_handler(&mut self, vector: u8, handler: impl TrapHandler + 'static) {
+    self.trap_handlers.insert(vector, Box::new(handler));
+  }
+
+  pub fn remove_trap_handler(&mut self, vector: u8) -> Option<Box<dyn TrapHandler>> {
+    self.trap_handlers.remove(&vector)
+  }
+
+  /// Runs the host routine for the vector of `i`, returning whether there was
+  /// one.
+  pub fn trap(&mut self, i: u16) -> Result<bool, VmError> {
+    let vector = (i & 0xFF) as u8;
+
+    let native = vector
+      .checked_sub(0x20)
+      .and_then(|i| TRAPS.get(usize::from(i)))
+      .filter(|_| self.native_traps);
+
+    let result = if let Some(handler) = self.trap_handlers.get_mut(&vector) {
+      handler.trap(&mut self.registers, &mut self.memory)
+    } else if let Some(trap) = native {
+      trap(&mut self.registers, &mut self.memory)
+    } else {
+      return Ok(false);
+    };
+
+    // handlers can fail with a `VmError` of their own, like bad input, or
+    // with the display failing to print
+    let result = result.map_err(|error| match error.downcast::<VmError>() {
+      Ok(err) => *err,
+      Err(error) => match error.downcast::<io::Error>() {
+        Ok(err) => VmError::Io(*err),
+        Err(error) => VmError::Trap { vector, error },
+      },
+    });
+
+    match result? {
+      TrapAction::Continue => {}
+      TrapAction::Halt => {
+        let mcr = self.memory.read(MCR);
+
+        self.memory.write(MCR, mcr & 0x7FFF);
+      }
+    }
+
+    Ok(true)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use rvm_compiler::parsing::{lowercase, parse};
+  use rvm_compiler::serialize;
+
+  use super::*;
+  use crate::device::{Display, Keyboard, Output};
+
+  fn machine(input: &[u8]) -> Machine {
+    let mut vm = Machine::new();
+    vm.native_traps = true;
+    vm.memory.keyboard.push(input);
+    vm.memory.display = Display::new(Output::Buffer(Vec::new()));
+    vm
+  }
+
+  #[test]
+  fn test_io_goes_through_devices() {
+    let mut vm = machine(b"a42\n");
+
+    vm.trap(0xF023).unwrap();
+    assert_eq!(vm.registers.get(Register::R0), 'a' as u16);
+
+    vm.trap(0xF026).unwrap();
+    assert_eq!(vm.registers.get(Register::R0), 42);
+
+    vm.trap(0xF027).unwrap();
+    assert_eq!(
+      vm.memory.display.take_output(),
+      b"input: ainput: output: 42\n"
+    );
+  }
+
+  #[test]
+  fn test_halt_clears_mcr() {
+    let mut vm = machine(b"");
+
+    vm.trap(0xF025).unwrap();
+    assert!(!vm.running());
+    assert_eq!(vm.memory.read(MCR), 0);
+  }
+
+  /// Assembles and runs `source` until it halts, returning what it printed.
+  fn run(source: &str, input: &[u8], native_traps: bool) -> (Machine, Vec<u8>) {
+    let image = serialize(&parse(&lowercase(source)).unwrap());
+
+    let mut vm = machine(input);
+    vm.native_traps = native_traps;
+    vm.load_bytes(&image, 0).unwrap();
+    vm.run(0).unwrap();
+
+    let output = vm.memory.display.take_output();
+    (vm, output)
+  }
+
+  #[test]
+  fn test_os_matches_native_traps() {
+    let source = r#"
+      .orig x3000
+        add r1, r1, #1
+        add r2, r2, #2
+        add r3, r3, #3
+        add r4, r4, #4
+        add r5, r5, #5
+        lea r0, hello
+        trap tputs
+        trap tgetc
+        trap toutc
+        trap tin
+        trap tinu16
+        trap toutu16
+        ld r0, big
+        trap toutu16
+        ld r0, tens
+        trap toutu16
+        and r0, r0, #0
+        trap toutu16
+        halt
+      hello: .stringz "hi\n"
+      big: .fill #65535
+      tens: .fill #40010
+      .end
+    "#;
+
+    let (native, native_output) = run(source, b"ab123\n", true);
+    let (os, os_output) = run(source, b"ab123\n", false);
+
+    let expected =
+      "hi\ninput: output: ainput: binput: output: 123\noutput: 65535\noutput: 40010\noutput: 0\n";
+    assert_eq!(String::from_utf8(os_output).unwrap(), expected);
+    assert_eq!(String::from_utf8(native_output).unwrap(), expected);
+
+    // halting stops inside the OS routine, with r6 on the supervisor stack
+    for r in [
+      Register::R0,
+      Register::R1,
+      Register::R2,
+      Register::R3,
+      Register::R4,
+      Register::R5,
+    ] {
+      assert_eq!(os.registers.get(r), native.registers.get(r));
+    }
+  }
+
+  #[test]
+  fn test_os_traps_from_user_mode() {
+    let source = r#"
+      .orig x3000
+        lea r0, hello
+        trap tputs
+        ld r0, bang
+        trap toutc
+        add r1, r6, #0
+        halt
+      hello: .stringz "hi\n"
+      bang: .fill x21
+      .end
+    "#;
+
+    let image = serialize(&parse(&lowercase(source)).unwrap());
+
+    let mut vm = machine(b"");
+    vm.native_traps = false;
+    vm.load_bytes(&image, 0).unwrap();
+    vm.registers.set_psr(0x8002);
+    *vm.registers.reg_r(Register::R6) = 0xFE00;
+
+    vm.run(0).unwrap();
+
+    assert_eq!(vm.memory.display.take_output(), b"hi\noutput: !");
+    assert!(!vm.running());
+
+    // each routine returned to user mode on the user stack
+    assert_eq!(vm.registers.get(Register::R1), 0xFE00);
+    assert_eq!(vm.registers.get(Register::SavedUsp), 0xFE00);
+  }
+
+  #[test]
+  fn test_end_of_input() {
+    let source = ".orig x3000\n trap tgetc\n add r1, r0, #0\n trap tgetc\n halt\n .end\n";
+    let image = serialize(&parse(&lowercase(source)).unwrap());
+
+    // the OS polling KBSR stops just like the native routine
+    for native_traps in [true, false] {
+      let mut vm = machine(b"");
+      vm.native_traps = native_traps;
+      vm.memory.keyboard = Keyboard::reader(&b"a"[..]);
+      vm.load_bytes(&image, 0).unwrap();
+
+      let err = vm.run(0).unwrap_err();
+      assert!(
+        matches!(&err, VmError::Io(err) if err.kind() == io::ErrorKind::UnexpectedEof),
+        "{err}"
+      );
+      assert_eq!(vm.registers.get(Register::R1), 'a' as u16);
+      assert!(vm.running());
+    }
+  }
+
+  #[test]
+  fn test_in_u16_bad_input() {
+    let source = ".orig x3000\n trap tinu16\n halt\n .end\n";
+    let image = serialize(&parse(&lowercase(source)).unwrap());
+
+    for input in [&b"12a\n"[..], b"65536\n", b"100000\n", b"\n"] {
+      let mut native = machine(input);
+      native.native_traps = true;
+      native.load_bytes(&image, 0).unwrap();
+      assert!(matches!(native.run(0), Err(VmError::BadInput(_))));
+
+      // the OS has no error to fail with, so it halts instead
+      let (os, output) = run(source, input, false);
+      assert_eq!(output, b"input: bad input\n");
+      assert!(!os.running());
+    }
+
+    for native_traps in [true, false] {
+      let (vm, _) = run(source, b"65535\n", native_traps);
+      assert_eq!(vm.registers.get(Register::R0), 65535);
+    }
+  }
+
+  #[test]
+  fn test_putsp() {
+    let source = r#"
+      .orig x3000
+        lea r0, odd
+        trap tputsp
+        lea r0, even
+        trap tputsp
+        halt
+      odd: .stringp "hey"
+      even: .stringp " you"
+      .end
+    "#;
+
+    for native_traps in [true, false] {
+      let (_, output) = run(source, b"", native_traps);
+      assert_eq!(output, b"hey you");
+    }
+  }
+
+  #[test]
+  fn test_os_bad_trap() {
+    let (vm, output) = run(".orig x3000\n trap x10\n .end\n", b"", false);
+
+    assert_eq!(output, b"bad trap\n");
+    assert!(!vm.running());
+  }
+
+  #[test]
+  fn test_trap_handler() {
+    let mut vm = machine(b"");
+
+    // TRAP x40 adds r1 to r0, TRAP x41 fails
+    vm.set_trap_handler(0x40, |registers: &mut Registers, _: &mut Memory| {
+      *registers.reg_r(Register::R0) += registers.get(Register::R1);
+      Ok(TrapAction::Continue)
+    });
+    vm.set_trap_handler(0x41, |_: &mut Registers, _: &mut Memory| {
+      Err("assertion failed".into())
+    });
+
+    *vm.registers.reg_r(Register::R0) = 1;
+    *vm.registers.reg_r(Register::R1) = 2;
+    assert!(vm.trap(0xF040).unwrap());
+    assert_eq!(vm.registers.get(Register::R0), 3);
+
+    let err = vm.trap(0xF041).unwrap_err();
+    assert_eq!(err.to_string(), "trap x41 failed: assertion failed");
+
+    assert!(vm.remove_trap_handler(0x40).is_some());
+    assert!(!vm.trap(0xF040).unwrap());
+  }
+
+  #[test]
+  fn test_trap_handler_overrides_os() {
+    let source = r#"
+      .orig x3000
+        trap x30
+        add r2, r2, #1
+        trap x31
+        add r2, r2, #1
+        halt
+      .end
+    "#;
+
+    let image = serialize(&parse(&lowercase(source)).unwrap());
+
+    let mut vm = Machine::new();
+    vm.load_bytes(&image, 0).unwrap();
+
+    let mut calls = 0;
+    vm.set_trap_handler(0x30, move |registers: &mut Registers, _: &mut Memory| {
+      calls += 1;
+      *registers.reg_r(Register::R1) = calls;
+      Ok(TrapAction::Continue)
+    });
+    vm.set_trap_handler(0x31, |_: &mut Registers, _: &mut Memory| {
+      Ok(TrapAction::Halt)
+    });
+
+    vm.run(0).unwrap();
+
+    assert!(!vm.running());
+    assert_eq!(vm.registers.get(Register::R1), 1);
+    assert_eq!(vm.registers.get(Register::R2), 1);
+    assert_eq!(vm.registers.get(Register::Pc), 0x3003);
+  }
+
+  #[test]
+  fn test_trap_handler_error_stops_run() {
+    let mut vm = machine(b"");
+    vm.memory.write(0x3000, 0xF050);
+    vm.pc_start = 0x3000;
+
+    vm.set_trap_handler(0x50, |_: &mut Registers, _: &mut Memory| Err("bad".into()));
+
+    assert!(matches!(vm.run(0), Err(VmError::Trap { vector: 0x50, .. })));
+    assert_eq!(vm.registers.get(Register::Pc), 0x3000);
+  }
+
+  #[test]
+  fn test_native_bad_input() {
+    let mut vm = machine(b"12a\n");
+    assert!(matches!(vm.trap(0xF026), Err(VmError::BadInput(input)) if input == "12a"));
+
+    assert!(matches!(vm.trap(0xF020), Err(VmError::Io(_))));
+  }
 }
@@ -0,0 +1,275 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use crate::instructions::Instruction;
+use crate::{Assembly, Word};
+
+/// Executions and branch outcomes by address, as written by `rvm --coverage`.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Executed {
+  pub counts: BTreeMap<u16, u64>,
+  /// Times each BR was taken and not taken
+  pub branches: BTreeMap<u16, (u64, u64)>,
+}
+
+impl Executed {
+  /// Adds a coverage file to what was executed, so several runs can be
+  /// combined.
+  pub fn parse(&mut self, text: &str) -> Result<(), String> {
+    for (line_number, line) in text.lines().enumerate() {
+      if line.trim().is_empty() {
+        continue;
+      }
+
+      let bad = || format!("bad coverage on line {}: `{line}`", line_number + 1);
+
+      let fields: Vec<&str> = line.split_whitespace().collect();
+
+      let address = fields[0]
+        .strip_prefix('x')
+        .and_then(|hex| u16::from_str_radix(hex, 16).ok())
+        .ok_or_else(bad)?;
+
+      let numbers = fields[1..]
+        .iter()
+        .map(|field| field.parse::<u64>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| bad())?;
+
+      let (count, branch) = match numbers.as_slice() {
+        [count] => (*count, None),
+        [count, taken, not_taken] => (*count, Some((*taken, *not_taken))),
+        _ => return Err(bad()),
+      };
+
+      *self.counts.entry(address).or_default() += count;
+
+      if let Some((taken, not_taken)) = branch {
+        let outcomes = self.branches.entry(address).or_default();
+        outcomes.0 += taken;
+        outcomes.1 += not_taken;
+      }
+    }
+
+    Ok(())
+  }
+}
+
+/// Coverage of a source line holding an instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Line {
+  /// Counted from 1
+  pub line: usize,
+  pub count: u64,
+  /// Times taken and not taken, for a conditional BR
+  pub branch: Option<(u64, u64)>,
+}
+
+/// Every line of `assembly` that holds an instruction, with what was executed
+/// there.
+///
+/// Data isn't counted, and neither are unconditional branches as only one way
+/// can be taken.
+pub fn lines(assembly: &Assembly, executed: &Executed) -> Vec<Line> {
+  let words = &assembly.program.words;
+
+  assembly
+    .lines
+    .iter()
+    .filter_map(|(&address, &line)| {
+      let index = address.wrapping_sub(assembly.program.origin);
+
+      let Some(Word::Instruction(instruction)) = words.get(usize::from(index)) else {
+        return None;
+      };
+
+      let conditional = match *instruction {
+        Instruction::Br(n, z, p, _) => (n || z || p) && !(n && z && p),
+        _ => false,
+      };
+
+      let branch = executed.branches.get(&address).copied().unwrap_or((0, 0));
+
+      Some(Line {
+        line,
+        count: executed.counts.get(&address).copied().unwrap_or(0),
+        branch: conditional.then_some(branch),
+      })
+    })
+    .collect()
+}
+
+/// Lines executed and lines, branch outcomes seen and branch outcomes.
+fn totals(lines: &[Line]) -> (usize, usize, usize, usize) {
+  let executed = lines.iter().filter(|line| line.count > 0).count();
+  let branches: Vec<_> = lines.iter().filter_map(|line| line.branch).collect();
+  let taken = branches
+    .iter()
+    .map(|&(taken, not_taken)| usize::from(taken > 0) + usize::from(not_taken > 0))
+    .sum();
+
+  (executed, lines.len(), taken, branches.len() * 2)
+}
+
+/// An LCOV tracefile for the source file `name`, with both outcomes of every
+/// conditional BR as branches.
+pub fn lcov(name: &str, assembly: &Assembly, executed: &Executed) -> String {
+  let lines = lines(assembly, executed);
+  let (lines_hit, lines_found, branches_hit, branches_found) = totals(&lines);
+
+  let mut out = String::new();
+
+  writeln!(out, "TN:").unwrap();
+  writeln!(out, "SF:{name}").unwrap();
+
+  for line in &lines {
+    let Some((taken, not_taken)) = line.branch else {
+      continue;
+    };
+
+    for (branch, outcome) in [taken, not_taken].into_iter().enumerate() {
+      // never reached is told apart from reached but never going this way
+      let outcome = if line.count == 0 {
+        "-".to_string()
+      } else {
+        outcome.to_string()
+      };
+
+      writeln!(out, "BRDA:{},0,{branch},{outcome}", line.line).unwrap();
+    }
+  }
+
+  writeln!(out, "BRF:{branches_found}").unwrap();
+  writeln!(out, "BRH:{branches_hit}").unwrap();
+
+  for line in &lines {
+    writeln!(out, "DA:{},{}", line.line, line.count).unwrap();
+  }
+
+  writeln!(out, "LF:{lines_found}").unwrap();
+  writeln!(out, "LH:{lines_hit}").unwrap();
+  writeln!(out, "end_of_record").unwrap();
+
+  out
+}
+
+/// `source` with the executions of every instruction in front of its line,
+/// `#####` for one never executed and `-` for a line without one, and the
+/// outcomes of each conditional BR below it.
+pub fn annotate(source: &str, assembly: &Assembly, executed: &Executed) -> String {
+  let lines = lines(assembly, executed);
+  let (lines_hit, lines_found, branches_hit, branches_found) = totals(&lines);
+
+  let by_line: BTreeMap<usize, &Line> = lines.iter().map(|line| (line.line, line)).collect();
+
+  let mut out = String::new();
+
+  writeln!(
+    out,
+    "lines {lines_hit}/{lines_found}, branches {branches_hit}/{branches_found}\n"
+  )
+  .unwrap();
+
+  for (i, text) in source.lines().enumerate() {
+    let line = by_line.get(&(i + 1));
+
+    let count = match line {
+      Some(line) if line.count == 0 => "#####".to_string(),
+      Some(line) => line.count.to_string(),
+      None => "-".to_string(),
+    };
+
+    writeln!(out, "{count:>9}:{:>5}:{text}", i + 1).unwrap();
+
+    let Some(line) = line else {
+      continue;
+    };
+
+    match line.branch {
+      Some(_) if line.count == 0 => writeln!(out, "{:16}branch never executed", "").unwrap(),
+      Some((taken, not_taken)) => {
+        writeln!(out, "{:16}branch taken {taken}, not taken {not_taken}", "").unwrap()
+      }
+      None => {}
+    }
+  }
+
+  out
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::parsing::{assemble, lowercase};
+
+  const SOURCE: &str = ".orig x3000
+  and r0, r0, #0
+  add r0, r0, #2
+loop:
+  add r0, r0, #-1
+  brp loop
+  brn never
+  brnzp done
+never:
+  .fill x1234
+  not r0, r0
+done:
+  halt
+.end
+";
+
+  fn executed() -> Executed {
+    let mut executed = Executed::default();
+
+    executed
+      .parse("x3000 1\nx3001 1\nx3002 2\nx3003 2 1 1\nx3004 1 0 1\n")
+      .unwrap();
+    executed.parse("x3005 1 1 0\nx3008 1\n").unwrap();
+
+    executed
+  }
+
+  #[test]
+  fn test_parse() {
+    let mut executed = executed();
+    assert_eq!(executed.counts[&0x3002], 2);
+    assert_eq!(executed.branches[&0x3003], (1, 1));
+
+    executed.parse("x3003 4 3 1\n").unwrap();
+    assert_eq!(executed.counts[&0x3003], 6);
+    assert_eq!(executed.branches[&0x3003], (4, 2));
+
+    assert!(executed.parse("3000 1").is_err());
+    assert!(executed.parse("x3000 1 2").is_err());
+    assert!(executed.parse("x3000 one").is_err());
+  }
+
+  #[test]
+  fn test_lcov() {
+    let assembly = assemble(&lowercase(SOURCE)).unwrap();
+
+    assert_eq!(
+      lcov("loop.asm", &assembly, &executed()),
+      "TN:\nSF:loop.asm\n\
+       BRDA:6,0,0,1\nBRDA:6,0,1,1\nBRDA:7,0,0,0\nBRDA:7,0,1,1\n\
+       BRF:4\nBRH:3\n\
+       DA:2,1\nDA:3,1\nDA:5,2\nDA:6,2\nDA:7,1\nDA:8,1\nDA:11,0\nDA:13,1\n\
+       LF:8\nLH:7\nend_of_record\n"
+    );
+  }
+
+  #[test]
+  fn test_annotate() {
+    let assembly = assemble(&lowercase(SOURCE)).unwrap();
+    let listing = annotate(SOURCE, &assembly, &executed());
+    let lines: Vec<&str> = listing.lines().collect();
+
+    assert_eq!(lines[0], "lines 7/8, branches 3/4");
+    assert_eq!(lines[2], "        -:    1:.orig x3000");
+    assert_eq!(lines[7], "        2:    6:  brp loop");
+    assert_eq!(lines[8], "                branch taken 1, not taken 1");
+    assert_eq!(lines[11], "        1:    8:  brnzp done");
+    assert_eq!(lines[13], "        -:   10:  .fill x1234");
+    assert_eq!(lines[14], "    #####:   11:  not r0, r0");
+  }
+}
@@ -0,0 +1,263 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use crate::instructions::Instruction;
+
+/// Shortest run of characters that is shown as a `.stringz`.
+const MIN_STRING: usize = 2;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Kind {
+  Instruction(Instruction),
+  Data,
+  /// First character of a zero terminated string running up to and including
+  /// `end`
+  String {
+    end: usize,
+  },
+  /// Rest of a string, emitted along with its start
+  InString,
+}
+
+/// Turns an image back into assembly that assembles to the same words.
+///
+/// Words that can't be written as an instruction become `.fill`, runs of zeros
+/// become `.blkw`, and runs of printable characters ending in a zero become
+/// `.stringz`. Words loaded or stored by `ld`, `st`, `ldi` and `sti` are
+/// assumed to be data. Every branch, call or load target inside the image gets
+/// a synthesized label.
+pub fn disassemble(origin: u16, words: &[u16]) -> String {
+  let mut kinds = classify(words);
+
+  let index = |address: u16| -> Option<usize> {
+    let index = usize::from(address.wrapping_sub(origin));
+
+    (index < words.len()).then_some(index)
+  };
+
+  let target = |i: usize, instruction: &Instruction| -> Option<usize> {
+    index(instruction.target(origin.wrapping_add(i as u16))?)
+  };
+
+  // words that are loaded from or stored to are data, not code
+  for i in 0..words.len() {
+    if let Kind::Instruction(
+      instruction @ (Instruction::Ld(..)
+      | Instruction::St(..)
+      | Instruction::Ldi(..)
+      | Instruction::Sti(..)),
+    ) = kinds[i]
+    {
+      if let Some(t) = target(i, &instruction) {
+        if matches!(kinds[t], Kind::Instruction(_)) {
+          kinds[t] = Kind::Data;
+        }
+      }
+    }
+  }
+
+  // index of each label, and whether it is called as a subroutine
+  let mut labels = BTreeMap::new();
+
+  for (i, kind) in kinds.iter().enumerate() {
+    if let Kind::Instruction(instruction) = kind {
+      if let Some(t) = target(i, instruction) {
+        *labels.entry(t).or_insert(false) |= matches!(instruction, Instruction::Jsr(_));
+      }
+    }
+  }
+
+  // a label can't point into the middle of a string, so break those up
+  for i in 0..words.len() {
+    if let Kind::String { end } = kinds[i] {
+      if labels.range(i + 1..=end).next().is_some() {
+        kinds[i..=end].fill(Kind::Data);
+      }
+    }
+  }
+
+  let names: BTreeMap<usize, String> = labels
+    .iter()
+    .map(|(&i, &is_call)| {
+      let prefix = match kinds[i] {
+        _ if is_call => "sub",
+        Kind::Instruction(_) => "l",
+        Kind::String { .. } => "str",
+        Kind::Data | Kind::InString => "data",
+      };
+
+      (i, format!("{prefix}_{:04x}", origin.wrapping_add(i as u16)))
+    })
+    .collect();
+
+  let mut out = String::new();
+  writeln!(out, ".orig x{origin:04x}").unwrap();
+
+  let mut i = 0;
+
+  while i < words.len() {
+    if let Some(name) = names.get(&i) {
+      writeln!(out, "{name}:").unwrap();
+    }
+
+    let address = origin.wrapping_add(i as u16);
+
+    let (statement, len) = match kinds[i] {
+      Kind::Instruction(instruction) => {
+        let label = target(i, &instruction).and_then(|t| names.get(&t));
+        let asm = instruction.to_asm(label.map(String::as_str)).unwrap();
+
+        (asm, 1)
+      }
+      Kind::String { end } => {
+        let string: String = words[i..end].iter().map(|&c| escape(c)).collect();
+
+        (format!(".stringz \"{string}\""), end + 1 - i)
+      }
+      Kind::Data if words[i] == 0 => {
+        let zeros = words[i..]
+          .iter()
+          .enumerate()
+          .take_while(|&(j, &word)| {
+            word == 0 && kinds[i + j] == Kind::Data && (j == 0 || !names.contains_key(&(i + j)))
+          })
+          .count();
+
+        if zeros == 1 {
+          (".fill x0000".to_string(), 1)
+        } else {
+          (format!(".blkw #{zeros}"), zeros)
+        }
+      }
+      Kind::Data | Kind::InString => (format!(".fill x{:04x}", words[i]), 1),
+    };
+
+    writeln!(out, "  {statement:<24} ; x{address:04x}").unwrap();
+
+    i += len;
+  }
+
+  writeln!(out, ".end").unwrap();
+
+  out
+}
+
+fn classify(words: &[u16]) -> Vec<Kind> {
+  let mut kinds: Vec<Kind> = words
+    .iter()
+    .map(|&word| {
+      let instruction = Instruction::decode(word);
+
+      if instruction.bytecode() == word && instruction.to_asm(None).is_some() {
+        Kind::Instruction(instruction)
+      } else {
+        Kind::Data
+      }
+    })
+    .collect();
+
+  let mut i = 0;
+
+  while i < words.len() {
+    let len = words[i..]
+      .iter()
+      .take_while(|&&c| is_string_char(c))
+      .count();
+    let end = i + len;
+
+    if len >= MIN_STRING && words.get(end) == Some(&0) {
+      kinds[i] = Kind::String { end };
+      kinds[i + 1..=end].fill(Kind::InString);
+      i = end + 1;
+    } else {
+      i += len.max(1);
+    }
+  }
+
+  kinds
+}
+
+fn is_string_char(c: u16) -> bool {
+  matches!(c, 0x20..=0x7E | 0x09 | 0x0A | 0x0D | 0x1B)
+}
+
+fn escape(c: u16) -> String {
+  match c as u8 {
+    b'\n' => "\\n".to_string(),
+    b'\t' => "\\t".to_string(),
+    b'\r' => "\\r".to_string(),
+    0x1B => "\\e".to_string(),
+    b'"' => "\\\"".to_string(),
+    b'\\' => "\\\\".to_string(),
+    c => (c as char).to_string(),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::parsing::{lowercase, parse};
+  use crate::{deserialize, serialize};
+
+  fn round_trip(origin: u16, words: &[u16]) -> String {
+    let asm = disassemble(origin, words);
+    let program = parse(&lowercase(&asm)).unwrap_or_else(|_| panic!("failed to parse:\n{asm}"));
+
+    assert_eq!(
+      deserialize(&serialize(&program)),
+      Ok((origin, words.to_vec())),
+      "{asm}"
+    );
+
+    asm
+  }
+
+  #[test]
+  fn test_disassemble_program() {
+    let source = r#".orig x3000
+      lea r0, message
+      jsr print
+      ld r1, count
+      loop: add r1, r1, #-1
+      brp loop
+      halt
+      print: trap tputs
+      ret: jmp r7
+      count: .fill #3
+      buffer: .blkw #3
+      message: .stringz "Hello, \"World\"!\n"
+      .end
+    "#;
+
+    let program = parse(source).unwrap_or_else(|_| panic!("failed to parse"));
+    let (origin, words) = deserialize(&serialize(&program)).unwrap();
+
+    let asm = round_trip(origin, &words);
+
+    assert!(asm.contains("lea r0, str_300c"), "{asm}");
+    assert!(asm.contains("jsr sub_3006"), "{asm}");
+    assert!(asm.contains("ld r1, data_3008"), "{asm}");
+    assert!(asm.contains("brp l_3003"), "{asm}");
+    assert!(asm.contains(".fill x0003"), "{asm}");
+    assert!(asm.contains(".blkw #3"), "{asm}");
+    assert!(asm.contains(r#".stringz "Hello, \"World\"!\n""#), "{asm}");
+  }
+
+  #[test]
+  fn test_disassemble_every_word() {
+    let words: Vec<u16> = (0..=u16::MAX).collect();
+
+    round_trip(0x0000, &words[..0xFFFF]);
+  }
+
+  #[test]
+  fn test_disassemble_label_inside_string() {
+    // `lea r0, #2` points at the `i` of "hi"
+    round_trip(0x3000, &[0xE002, 0xF025, 'h' as u16, 'i' as u16, 0]);
+  }
+
+  #[test]
+  fn test_disassemble_wrapping_origin() {
+    round_trip(0xFFFE, &[0x0FFF, 0x1021, 0xF025]);
+  }
+}
@@ -1,3 +1,5 @@
+use std::fmt::{self, Display, Formatter};
+
 use crate::registers::Register;
 
 #[derive(Debug, PartialEq, Clone, Copy)]
@@ -6,7 +8,7 @@ pub enum Instruction {
   /// 0000 X X X XXXXXXXXX
   /// op   N Z P offset9
   /// ```
-  Br(bool, bool, bool, u8),
+  Br(bool, bool, bool, i16),
   /// ```
   /// 0001 XXX XXX 000 XXX
   /// op   dr  sr1     sr2
@@ -16,22 +18,22 @@ pub enum Instruction {
   /// 0001 XXX XXX 1 XXXXX
   /// op   dr  sr1   imm5
   /// ```
-  Add2(Register, Register, u8),
+  Add2(Register, Register, i8),
   /// ```
   /// 0010 XXX XXXXXXXXX
   /// op   dr  offset9
   /// ```
-  Ld(Register, u8),
+  Ld(Register, i16),
   /// ```
   /// 0011 XXX XXXXXXXXX
   /// op   sr  offset9
   /// ```
-  St(Register, u8),
+  St(Register, i16),
   /// ```
   /// 0100 1 XXXXXXXXXXX
   /// op     offset11
   /// ```
-  Jsr(u8),
+  Jsr(i16),
   /// ```
   /// 0100 000 XXX    000000
   /// op       base_r
@@ -46,17 +48,17 @@ pub enum Instruction {
   /// 0101 XXX XXX 1 XXXXX
   /// op   dr  sr1   imm5
   /// ```
-  And2(Register, Register, u8),
+  And2(Register, Register, i8),
   /// ```
   /// 0110 XXX XXX    XXXXXX
   /// op   dr  base_r offset6
   /// ```
-  Ldr(Register, Register, u8),
+  Ldr(Register, Register, i8),
   /// ```
   /// 0111 XXX XXX    XXXXXX
   /// op   dr  base_r offset6
   /// ```
-  Str(Register, Register, u8),
+  Str(Register, Register, i8),
   /// ```
   /// 1000 XXXXXXXXXXXX
   /// op
@@ -71,12 +73,12 @@ pub enum Instruction {
   /// 1010 XXX XXXXXXXXX
   /// op   dr  offset9
   /// ```
-  Ldi(Register, u8),
+  Ldi(Register, i16),
   /// ```
   /// 1011 XXX XXXXXXXXX
   /// op   sr  offset9
   /// ```
-  Sti(Register, u8),
+  Sti(Register, i16),
   /// ```
   /// 1100 000 XXX    000000
   /// op       base_r
@@ -91,7 +93,7 @@ pub enum Instruction {
   /// 1110 XXX XXXXXXXXX
   /// op   dr  offset9
   /// ```
-  Lea(Register, u8),
+  Lea(Register, i16),
   /// ```
   /// 1111 0000 XXXXXXXX
   /// op        trap
@@ -99,6 +101,114 @@ pub enum Instruction {
   Trap(TrapVect),
 }
 impl Instruction {
+  /// Inverse of [`Instruction::bytecode`], accepting any word.
+  ///
+  /// Bits the encoding requires to be zero or one are ignored, and the reserved
+  /// opcode decodes to [`Instruction::Res`], so words that aren't in
+  /// canonical form don't survive a round trip through `bytecode`.
+  pub fn decode(i: u16) -> Instruction {
+    let dr = Register::decode(i >> 9);
+    let sr1 = Register::decode(i >> 6);
+    let sr2 = Register::decode(i);
+    let bit_5 = (i >> 5) & 0x1 == 1;
+
+    let imm5 = sext(i, 5) as i8;
+    let offset6 = sext(i, 6) as i8;
+    let offset9 = sext(i, 9);
+    let offset11 = sext(i, 11);
+
+    match i >> 12 {
+      0b0000 => {
+        let n = (i >> 11) & 0x1 == 1;
+        let z = (i >> 10) & 0x1 == 1;
+        let p = (i >> 9) & 0x1 == 1;
+
+        Instruction::Br(n, z, p, offset9)
+      }
+      0b0001 if bit_5 => Instruction::Add2(dr, sr1, imm5),
+      0b0001 => Instruction::Add1(dr, sr1, sr2),
+      0b0010 => Instruction::Ld(dr, offset9),
+      0b0011 => Instruction::St(dr, offset9),
+      0b0100 if (i >> 11) & 0x1 == 1 => Instruction::Jsr(offset11),
+      0b0100 => Instruction::Jsrr(sr1),
+      0b0101 if bit_5 => Instruction::And2(dr, sr1, imm5),
+      0b0101 => Instruction::And1(dr, sr1, sr2),
+      0b0110 => Instruction::Ldr(dr, sr1, offset6),
+      0b0111 => Instruction::Str(dr, sr1, offset6),
+      0b1000 => Instruction::Rti,
+      0b1001 => Instruction::Not(dr, sr1),
+      0b1010 => Instruction::Ldi(dr, offset9),
+      0b1011 => Instruction::Sti(dr, offset9),
+      0b1100 => Instruction::Jmp(sr1),
+      0b1101 => Instruction::Res,
+      0b1110 => Instruction::Lea(dr, offset9),
+      0b1111 => Instruction::Trap(TrapVect::decode(i as u8)),
+      _ => unreachable!(),
+    }
+  }
+
+  /// The pc-relative offset of instructions that address memory relative to the
+  /// next instruction.
+  pub fn pc_offset(&self) -> Option<i16> {
+    match self {
+      Instruction::Br(_, _, _, offset)
+      | Instruction::Ld(_, offset)
+      | Instruction::St(_, offset)
+      | Instruction::Jsr(offset)
+      | Instruction::Ldi(_, offset)
+      | Instruction::Sti(_, offset)
+      | Instruction::Lea(_, offset) => Some(*offset),
+      _ => None,
+    }
+  }
+
+  /// The address a pc-relative instruction at `address` refers to.
+  pub fn target(&self, address: u16) -> Option<u16> {
+    let offset = self.pc_offset()?;
+
+    Some(address.wrapping_add(1).wrapping_add(offset as u16))
+  }
+
+  /// Formats the instruction as assembly the parser accepts, with `label` in
+  /// place of the pc-relative offset if given.
+  ///
+  /// Returns `None` for a branch without any condition, which has no mnemonic.
+  pub fn to_asm(&self, label: Option<&str>) -> Option<String> {
+    let offset = |offset: &i16| match label {
+      Some(label) => label.to_string(),
+      None => format!("#{offset}"),
+    };
+
+    Some(match self {
+      Instruction::Br(false, false, false, _) => return None,
+      Instruction::Br(n, z, p, offset9) => format!(
+        "br{}{}{} {}",
+        if *n { "n" } else { "" },
+        if *z { "z" } else { "" },
+        if *p { "p" } else { "" },
+        offset(offset9)
+      ),
+      Instruction::Add1(dr, sr1, sr2) => format!("add {dr}, {sr1}, {sr2}"),
+      Instruction::Add2(dr, sr1, imm5) => format!("add {dr}, {sr1}, #{imm5}"),
+      Instruction::Ld(dr, offset9) => format!("ld {dr}, {}", offset(offset9)),
+      Instruction::St(sr, offset9) => format!("st {sr}, {}", offset(offset9)),
+      Instruction::Jsr(offset11) => format!("jsr {}", offset(offset11)),
+      Instruction::Jsrr(base_r) => format!("jsrr {base_r}"),
+      Instruction::And1(dr, sr1, sr2) => format!("and {dr}, {sr1}, {sr2}"),
+      Instruction::And2(dr, sr1, imm5) => format!("and {dr}, {sr1}, #{imm5}"),
+      Instruction::Ldr(dr, base_r, offset6) => format!("ldr {dr}, {base_r}, #{offset6}"),
+      Instruction::Str(sr, base_r, offset6) => format!("str {sr}, {base_r}, #{offset6}"),
+      Instruction::Rti => "rti".to_string(),
+      Instruction::Not(dr, sr) => format!("not {dr}, {sr}"),
+      Instruction::Ldi(dr, offset9) => format!("ldi {dr}, {}", offset(offset9)),
+      Instruction::Sti(sr, offset9) => format!("sti {sr}, {}", offset(offset9)),
+      Instruction::Jmp(base_r) => format!("jmp {base_r}"),
+      Instruction::Res => "res".to_string(),
+      Instruction::Lea(dr, offset9) => format!("lea {dr}, {}", offset(offset9)),
+      Instruction::Trap(trap) => format!("trap {trap}"),
+    })
+  }
+
   pub fn bytecode(&self) -> u16 {
     match self {
       Instruction::Br(n, z, p, offset9) => {
@@ -112,7 +222,7 @@ impl Instruction {
         if *p {
           out |= 0b0000_0010_0000_0000;
         }
-        out |= u16::from(*offset9);
+        out |= *offset9 as u16 & 0x1FF;
         out
       }
       Instruction::Add1(dr, sr1, sr2) => {
@@ -126,24 +236,25 @@ impl Instruction {
         let mut out = 0b0001_0000_0000_0000;
         out |= dr.bytecode() << 9;
         out |= sr1.bytecode() << 6;
-        out |= u16::from(*imm5);
+        out |= 0b0000_0000_0010_0000;
+        out |= *imm5 as u16 & 0x1F;
         out
       }
       Instruction::Ld(dr, offset9) => {
         let mut out = 0b0010_0000_0000_0000;
         out |= dr.bytecode() << 9;
-        out |= u16::from(*offset9);
+        out |= *offset9 as u16 & 0x1FF;
         out
       }
       Instruction::St(sr, offset9) => {
         let mut out = 0b0011_0000_0000_0000;
         out |= sr.bytecode() << 9;
-        out |= u16::from(*offset9);
+        out |= *offset9 as u16 & 0x1FF;
         out
       }
       Instruction::Jsr(offset11) => {
         let mut out = 0b0100_1000_0000_0000;
-        out |= u16::from(*offset11);
+        out |= *offset11 as u16 & 0x7FF;
         out
       }
       Instruction::Jsrr(base_r) => {
@@ -162,26 +273,27 @@ impl Instruction {
         let mut out = 0b0101_0000_0000_0000;
         out |= dr.bytecode() << 9;
         out |= sr1.bytecode() << 6;
-        out |= u16::from(*imm5);
+        out |= 0b0000_0000_0010_0000;
+        out |= *imm5 as u16 & 0x1F;
         out
       }
       Instruction::Ldr(dr, base_r, offset6) => {
         let mut out = 0b0110_0000_0000_0000;
         out |= dr.bytecode() << 9;
         out |= base_r.bytecode() << 6;
-        out |= u16::from(*offset6);
+        out |= *offset6 as u16 & 0x3F;
         out
       }
       Instruction::Str(sr, base_r, offset6) => {
         let mut out = 0b0111_0000_0000_0000;
         out |= sr.bytecode() << 9;
         out |= base_r.bytecode() << 6;
-        out |= u16::from(*offset6);
+        out |= *offset6 as u16 & 0x3F;
         out
       }
       Instruction::Rti => 0b1000_0000_0000_0000,
       Instruction::Not(dr, sr) => {
-        let mut out = 0b1001_0000_0000_0000;
+        let mut out = 0b1001_0000_0011_1111;
         out |= dr.bytecode() << 9;
         out |= sr.bytecode() << 6;
         out
@@ -189,13 +301,13 @@ impl Instruction {
       Instruction::Ldi(dr, offset9) => {
         let mut out = 0b1010_0000_0000_0000;
         out |= dr.bytecode() << 9;
-        out |= u16::from(*offset9);
+        out |= *offset9 as u16 & 0x1FF;
         out
       }
       Instruction::Sti(sr, offset9) => {
         let mut out = 0b1011_0000_0000_0000;
         out |= sr.bytecode() << 9;
-        out |= u16::from(*offset9);
+        out |= *offset9 as u16 & 0x1FF;
         out
       }
       Instruction::Jmp(base_r) => {
@@ -207,7 +319,7 @@ impl Instruction {
       Instruction::Lea(dr, offset9) => {
         let mut out = 0b1110_0000_0000_0000;
         out |= dr.bytecode() << 9;
-        out |= u16::from(*offset9);
+        out |= *offset9 as u16 & 0x1FF;
         out
       }
       Instruction::Trap(trap) => {
@@ -221,18 +333,206 @@ impl Instruction {
 
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum TrapVect {
-  GetC = 0x20,
-  OutC = 0x21,
-  PutS = 0x22,
-  In = 0x23,
-  PutSp = 0x24,
-  Halt = 0x25,
-  InU16 = 0x26,
-  OutU16 = 0x27,
+  GetC,
+  OutC,
+  PutS,
+  In,
+  PutSp,
+  Halt,
+  InU16,
+  OutU16,
+  /// Any vector without a name of its own
+  Other(u8),
 }
 
 impl TrapVect {
   pub fn bytecode(&self) -> u16 {
-    *self as u16
+    match self {
+      TrapVect::GetC => 0x20,
+      TrapVect::OutC => 0x21,
+      TrapVect::PutS => 0x22,
+      TrapVect::In => 0x23,
+      TrapVect::PutSp => 0x24,
+      TrapVect::Halt => 0x25,
+      TrapVect::InU16 => 0x26,
+      TrapVect::OutU16 => 0x27,
+      TrapVect::Other(vect) => u16::from(*vect),
+    }
+  }
+
+  pub fn decode(vect: u8) -> TrapVect {
+    match vect {
+      0x20 => TrapVect::GetC,
+      0x21 => TrapVect::OutC,
+      0x22 => TrapVect::PutS,
+      0x23 => TrapVect::In,
+      0x24 => TrapVect::PutSp,
+      0x25 => TrapVect::Halt,
+      0x26 => TrapVect::InU16,
+      0x27 => TrapVect::OutU16,
+      _ => TrapVect::Other(vect),
+    }
+  }
+}
+
+impl Display for TrapVect {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    match self {
+      TrapVect::GetC => write!(f, "tgetc"),
+      TrapVect::OutC => write!(f, "toutc"),
+      TrapVect::PutS => write!(f, "tputs"),
+      TrapVect::In => write!(f, "tin"),
+      TrapVect::PutSp => write!(f, "tputsp"),
+      TrapVect::Halt => write!(f, "thalt"),
+      TrapVect::InU16 => write!(f, "tinu16"),
+      TrapVect::OutU16 => write!(f, "toutu16"),
+      TrapVect::Other(vect) => write!(f, "x{vect:02x}"),
+    }
+  }
+}
+
+/// Sign extends the low `bit_count` bits of `x`.
+fn sext(x: u16, bit_count: u16) -> i16 {
+  let shift = 16 - bit_count;
+
+  ((x << shift) as i16) >> shift
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_bytecode_negative_offsets() {
+    assert_eq!(Instruction::Br(true, true, true, -1).bytecode(), 0x0FFF);
+    assert_eq!(Instruction::Br(false, true, false, -256).bytecode(), 0x0500);
+    assert_eq!(Instruction::Jsr(-1).bytecode(), 0x4FFF);
+    assert_eq!(Instruction::Ld(Register::R1, -2).bytecode(), 0x23FE);
+    assert_eq!(
+      Instruction::Ldr(Register::R0, Register::R6, -1).bytecode(),
+      0x61BF
+    );
+    assert_eq!(
+      Instruction::Str(Register::R0, Register::R6, -32).bytecode(),
+      0x71A0
+    );
+  }
+
+  #[test]
+  fn test_bytecode_immediate() {
+    assert_eq!(
+      Instruction::Add2(Register::R1, Register::R1, -1).bytecode(),
+      0x127F
+    );
+    assert_eq!(
+      Instruction::And2(Register::R0, Register::R0, 0).bytecode(),
+      0x5020
+    );
+    assert_eq!(
+      Instruction::Add1(Register::R0, Register::R1, Register::R2).bytecode(),
+      0x1042
+    );
+  }
+
+  #[test]
+  fn test_decode() {
+    assert_eq!(
+      Instruction::decode(0x127F),
+      Instruction::Add2(Register::R1, Register::R1, -1)
+    );
+    assert_eq!(
+      Instruction::decode(0x1042),
+      Instruction::Add1(Register::R0, Register::R1, Register::R2)
+    );
+    assert_eq!(
+      Instruction::decode(0x0FFF),
+      Instruction::Br(true, true, true, -1)
+    );
+    assert_eq!(Instruction::decode(0x4FFF), Instruction::Jsr(-1));
+    assert_eq!(Instruction::decode(0x41C0), Instruction::Jsrr(Register::R7));
+    assert_eq!(Instruction::decode(0xC1C0), Instruction::Jmp(Register::R7));
+    assert_eq!(Instruction::decode(0x8000), Instruction::Rti);
+    assert_eq!(Instruction::decode(0xD123), Instruction::Res);
+    assert_eq!(
+      Instruction::decode(0xF025),
+      Instruction::Trap(TrapVect::Halt)
+    );
+    assert_eq!(
+      Instruction::decode(0xF0FF),
+      Instruction::Trap(TrapVect::Other(0xFF))
+    );
+  }
+
+  #[test]
+  fn test_decode_non_canonical() {
+    // bits 4:3 of a register add have to be zero
+    assert_eq!(
+      Instruction::decode(0x105A),
+      Instruction::Add1(Register::R0, Register::R1, Register::R2)
+    );
+    // NOT has to end in ones
+    assert_eq!(
+      Instruction::decode(0x9040),
+      Instruction::Not(Register::R0, Register::R1)
+    );
+  }
+
+  /// Encoding what any word decodes to and decoding it again gives the same
+  /// instruction, so `decode` is a left inverse of `bytecode` on everything
+  /// `decode` returns.
+  #[test]
+  fn test_decode_bytecode_round_trip() {
+    for word in 0..=u16::MAX {
+      let instruction = Instruction::decode(word);
+
+      assert_eq!(
+        Instruction::decode(instruction.bytecode()),
+        instruction,
+        "{word:#06X}"
+      );
+    }
+  }
+
+  #[test]
+  fn test_canonical_words() {
+    let canonical = (0..=u16::MAX).filter(|&word| Instruction::decode(word).bytecode() == word);
+
+    let offsets = 8 * 4096; // BR, LD, ST, LDR, STR, LDI, STI, LEA
+    let operates = 2 * (512 + 2048); // ADD, AND
+    let subroutines = 2048 + 8; // JSR, JSRR
+    let rest = 1 + 64 + 8 + 1 + 256; // RTI, NOT, JMP, RES, TRAP
+
+    assert_eq!(canonical.count(), offsets + operates + subroutines + rest);
+  }
+
+  #[test]
+  fn test_target() {
+    assert_eq!(Instruction::Jsr(-1).target(0x3000), Some(0x3000));
+    assert_eq!(
+      Instruction::Br(true, true, true, 1).target(0xFFFF),
+      Some(0x0001)
+    );
+    assert_eq!(Instruction::Jmp(Register::R7).target(0x3000), None);
+  }
+
+  #[test]
+  fn test_to_asm() {
+    assert_eq!(
+      Instruction::Add2(Register::R1, Register::R1, -1).to_asm(None),
+      Some("add r1, r1, #-1".to_string())
+    );
+    assert_eq!(
+      Instruction::Br(true, false, true, -3).to_asm(None),
+      Some("brnp #-3".to_string())
+    );
+    assert_eq!(
+      Instruction::Lea(Register::R0, 4).to_asm(Some("message")),
+      Some("lea r0, message".to_string())
+    );
+    assert_eq!(
+      Instruction::Trap(TrapVect::Other(0x30)).to_asm(None),
+      Some("trap x30".to_string())
+    );
+    assert_eq!(Instruction::Br(false, false, false, 1).to_asm(None), None);
   }
 }
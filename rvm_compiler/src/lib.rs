@@ -1,23 +1,142 @@
+use std::collections::BTreeMap;
+use std::fmt::{self, Display, Formatter, Write};
+
 use crate::instructions::Instruction;
 
+pub mod coverage;
+pub mod disassembler;
 pub mod instructions;
 pub mod parsing;
 pub mod registers;
 
+/// Address programs are loaded at when they have no `.orig`.
+pub const ORIGIN: u16 = 0x3000;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Word {
+  Instruction(Instruction),
+  /// Raw data from `.fill`, `.blkw` or `.stringz`
+  Data(u16),
+}
+
+impl Word {
+  pub fn bytecode(&self) -> u16 {
+    match self {
+      Word::Instruction(instruction) => instruction.bytecode(),
+      Word::Data(data) => *data,
+    }
+  }
+}
+
 #[derive(Debug, PartialEq)]
 pub struct Program {
-  pub instructions: Vec<Instruction>,
+  pub origin: u16,
+  pub words: Vec<Word>,
+}
+
+/// A program along with the address of every label in it and the source line
+/// of every statement, for debuggers.
+#[derive(Debug, PartialEq)]
+pub struct Assembly {
+  pub program: Program,
+  pub symbols: BTreeMap<String, u16>,
+  /// Line, counted from 1, of the statement laid out at each address. Only
+  /// the first address of a directive spanning several words is included.
+  pub lines: BTreeMap<u16, usize>,
+}
+
+/// Writes a symbol file, one `xADDR label` line per label in address order.
+pub fn write_symbols(symbols: &BTreeMap<String, u16>) -> String {
+  let mut symbols: Vec<_> = symbols.iter().collect();
+  symbols.sort_by_key(|&(label, address)| (*address, label));
+
+  let mut out = String::new();
+
+  for (label, address) in symbols {
+    writeln!(out, "x{address:04x} {label}").unwrap();
+  }
+
+  out
 }
 
 pub fn serialize(program: &Program) -> Vec<u8> {
-  let mut out: Vec<u16> = Vec::with_capacity(program.instructions.len() + 1);
+  let mut out: Vec<u16> = Vec::with_capacity(program.words.len() + 1);
 
-  let header = 0b0011_0000_0000_0000;
-  out.push(header);
+  out.push(program.origin);
 
-  for instruction in &program.instructions {
-    out.push(instruction.bytecode());
+  for word in &program.words {
+    out.push(word.bytecode());
   }
 
   out.iter().flat_map(|&x| x.to_be_bytes()).collect()
 }
+
+#[derive(Debug, PartialEq)]
+pub enum ImageError {
+  MissingHeader,
+  OddLength(usize),
+}
+
+impl Display for ImageError {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    match self {
+      ImageError::MissingHeader => write!(f, "image is missing its origin header"),
+      ImageError::OddLength(len) => write!(f, "image length {len} is not a whole number of words"),
+    }
+  }
+}
+
+/// Inverse of [`serialize`], splitting an image into its origin and words.
+pub fn deserialize(image: &[u8]) -> Result<(u16, Vec<u16>), ImageError> {
+  if image.len() < 2 {
+    return Err(ImageError::MissingHeader);
+  }
+
+  if !image.len().is_multiple_of(2) {
+    return Err(ImageError::OddLength(image.len()));
+  }
+
+  let mut words = image
+    .chunks_exact(2)
+    .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]));
+
+  let origin = words.next().unwrap();
+
+  Ok((origin, words.collect()))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::instructions::TrapVect;
+
+  #[test]
+  fn test_serialize() {
+    let program = Program {
+      origin: 0x4000,
+      words: vec![
+        Word::Instruction(Instruction::Trap(TrapVect::Halt)),
+        Word::Data(0xBEEF),
+      ],
+    };
+
+    assert_eq!(
+      serialize(&program),
+      vec![0x40, 0x00, 0xF0, 0x25, 0xBE, 0xEF]
+    );
+  }
+
+  #[test]
+  fn test_write_symbols() {
+    let symbols = BTreeMap::from([
+      ("loop".to_string(), 0x3001),
+      ("start".to_string(), 0x3000),
+      ("again".to_string(), 0x3001),
+    ]);
+
+    assert_eq!(
+      write_symbols(&symbols),
+      "x3000 start\nx3001 again\nx3001 loop\n"
+    );
+  }
+}
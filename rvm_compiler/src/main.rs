@@ -1,23 +1,37 @@
 use std::env::args;
-use std::fs::{read_to_string, File};
+use std::fs::{read, read_to_string, File};
 use std::io::Write;
+use std::path::Path;
 
-use rvm_compiler::parsing::print_errors;
+use rvm_compiler::coverage::{annotate, lcov, Executed};
+use rvm_compiler::disassembler::disassemble;
+use rvm_compiler::parsing::{assemble, lowercase, print_errors};
+use rvm_compiler::write_symbols;
 
 fn main() {
-  let (in_file, out_file) = (args().nth(1), args().nth(2));
-  if in_file.is_none() || out_file.is_none() {
-    println!("Usage: rvm_compiler <in_file> <out_file>");
-    return;
-  }
+  let args: Vec<String> = args().skip(1).collect();
 
-  let in_file = in_file.unwrap();
-  let out_file = out_file.unwrap();
+  match args.as_slice() {
+    [flag, in_file, out_file] if flag == "-d" || flag == "--disassemble" => {
+      disassemble_file(in_file, out_file)
+    }
+    [flag, in_file, out_file, coverage @ ..] if flag == "--coverage" && !coverage.is_empty() => {
+      coverage_file(in_file, out_file, coverage)
+    }
+    [in_file, out_file] => assemble_file(in_file, out_file),
+    _ => {
+      println!("Usage: rvm_compiler <in_file> <out_file>");
+      println!("       rvm_compiler --disassemble <in_file> <out_file>");
+      println!("       rvm_compiler --coverage <in_file> <lcov_file> <coverage>...");
+    }
+  }
+}
 
-  let contents = read_to_string(in_file).unwrap().to_ascii_lowercase();
+fn assemble_file(in_file: &str, out_file: &str) {
+  let contents = lowercase(&read_to_string(in_file).unwrap());
 
-  let program = match rvm_compiler::parsing::parse(&contents) {
-    Ok(program) => program,
+  let assembly = match assemble(&contents) {
+    Ok(assembly) => assembly,
     Err(errs) => {
       print_errors(&contents, errs);
 
@@ -25,11 +39,81 @@ fn main() {
     }
   };
 
-  let bytecode = rvm_compiler::serialize(&program);
+  let bytecode = rvm_compiler::serialize(&assembly.program);
 
-  let mut file = File::create(&out_file).unwrap();
+  let mut file = File::create(out_file).unwrap();
 
   file.write_all(&bytecode).unwrap();
 
   println!("written to `{out_file}`");
+
+  // labels for the debugger, next to the image
+  let symbol_file = Path::new(out_file).with_extension("sym");
+
+  let mut file = File::create(&symbol_file).unwrap();
+
+  file
+    .write_all(write_symbols(&assembly.symbols).as_bytes())
+    .unwrap();
+
+  println!("written to `{}`", symbol_file.display());
+}
+
+fn disassemble_file(in_file: &str, out_file: &str) {
+  let image = read(in_file).unwrap();
+
+  let (origin, words) = match rvm_compiler::deserialize(&image) {
+    Ok(image) => image,
+    Err(err) => panic!("Failed to read image: {err}"),
+  };
+
+  let mut file = File::create(out_file).unwrap();
+
+  file
+    .write_all(disassemble(origin, &words).as_bytes())
+    .unwrap();
+
+  println!("written to `{out_file}`");
+}
+
+/// Maps coverage written by `rvm --coverage` back to the source, as an LCOV
+/// file and an annotated listing next to it.
+fn coverage_file(in_file: &str, out_file: &str, coverage: &[String]) {
+  let source = read_to_string(in_file).unwrap();
+  let contents = lowercase(&source);
+
+  let assembly = match assemble(&contents) {
+    Ok(assembly) => assembly,
+    Err(errs) => {
+      print_errors(&contents, errs);
+
+      panic!("Failed to parse program");
+    }
+  };
+
+  let mut executed = Executed::default();
+
+  for file in coverage {
+    if let Err(err) = executed.parse(&read_to_string(file).unwrap()) {
+      panic!("Failed to read `{file}`: {err}");
+    }
+  }
+
+  let mut file = File::create(out_file).unwrap();
+
+  file
+    .write_all(lcov(in_file, &assembly, &executed).as_bytes())
+    .unwrap();
+
+  println!("written to `{out_file}`");
+
+  let listing_file = Path::new(out_file).with_extension("txt");
+
+  let mut file = File::create(&listing_file).unwrap();
+
+  file
+    .write_all(annotate(&source, &assembly, &executed).as_bytes())
+    .unwrap();
+
+  println!("written to `{}`", listing_file.display());
 }
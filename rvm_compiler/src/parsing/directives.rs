@@ -0,0 +1,197 @@
+use chumsky::error::Simple;
+use chumsky::primitive::{choice, filter, just, none_of};
+use chumsky::text::TextParser;
+use chumsky::Parser;
+
+use crate::parsing::utils::{parse_operand, parse_unsigned, word_range};
+use crate::parsing::Directive;
+
+/// Every directive but `.end`, which is handled by the program parser as it
+/// ends the input.
+pub fn parse_directive() -> impl Parser<char, Directive, Error = Simple<char>> {
+  choice((
+    parse_orig(),
+    parse_fill(),
+    parse_blkw(),
+    parse_stringz(),
+    parse_stringp(),
+  ))
+}
+
+/// `.orig x3000`
+///
+/// Sets the address the program is loaded at.
+pub fn parse_orig() -> impl Parser<char, Directive, Error = Simple<char>> {
+  just(".orig")
+    .padded()
+    .ignore_then(parse_unsigned(16))
+    .map(|address| Directive::Orig(address as u16))
+}
+
+/// `.end`
+///
+/// Marks the end of the program, anything after it is ignored.
+pub fn parse_end() -> impl Parser<char, Directive, Error = Simple<char>> {
+  just(".end").padded().to(Directive::End)
+}
+
+/// `.fill x1234` or `.fill label`
+///
+/// A single word holding a number or the address of a label.
+pub fn parse_fill() -> impl Parser<char, Directive, Error = Simple<char>> {
+  just(".fill")
+    .padded()
+    .ignore_then(parse_operand(word_range()))
+    .map(Directive::Fill)
+}
+
+/// `.blkw #10`
+///
+/// Reserves a block of zeroed words.
+pub fn parse_blkw() -> impl Parser<char, Directive, Error = Simple<char>> {
+  just(".blkw")
+    .padded()
+    .ignore_then(parse_unsigned(16))
+    .map(|count| Directive::Blkw(count as u16))
+}
+
+/// `.stringz "hello"`
+///
+/// One word per character, followed by a terminating zero word.
+pub fn parse_stringz() -> impl Parser<char, Directive, Error = Simple<char>> {
+  just(".stringz")
+    .padded()
+    .ignore_then(parse_string())
+    .map(Directive::Stringz)
+}
+
+/// `.stringp "hello"`
+///
+/// Two bytes per word, low byte first, terminated by a zero byte as read by
+/// the PUTSP trap.
+pub fn parse_stringp() -> impl Parser<char, Directive, Error = Simple<char>> {
+  just(".stringp")
+    .padded()
+    .ignore_then(parse_string())
+    .map(Directive::Stringp)
+}
+
+/// Packs the bytes of `string` two per word, low byte first, with a zero byte
+/// at the end.
+pub fn pack_string(string: &str) -> Vec<u16> {
+  let mut words: Vec<u16> = string
+    .as_bytes()
+    .chunks(2)
+    .map(|pair| u16::from(pair[0]) | u16::from(*pair.get(1).unwrap_or(&0)) << 8)
+    .collect();
+
+  // an odd length already ends on a zero high byte
+  if string.len().is_multiple_of(2) {
+    words.push(0);
+  }
+
+  words
+}
+
+/// `"hello\n"`
+pub fn parse_string() -> impl Parser<char, String, Error = Simple<char>> {
+  let escape = just('\\').ignore_then(filter(|_| true).try_map(|c, span| match c {
+    'n' => Ok('\n'),
+    't' => Ok('\t'),
+    'r' => Ok('\r'),
+    'e' => Ok('\x1B'),
+    '0' => Ok('\0'),
+    '\\' | '"' => Ok(c),
+    _ => Err(Simple::custom(
+      span,
+      format!("invalid escape sequence `\\{c}`"),
+    )),
+  }));
+
+  just('"')
+    .ignore_then(none_of("\\\"\n").or(escape).repeated())
+    .then_ignore(just('"'))
+    .collect::<String>()
+    .padded()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::parsing::Operand;
+
+  #[test]
+  fn test_parse_orig() {
+    assert_eq!(
+      parse_orig().parse(".orig x3000"),
+      Ok(Directive::Orig(0x3000))
+    );
+  }
+
+  #[test]
+  fn test_parse_end() {
+    assert_eq!(parse_end().parse(".end"), Ok(Directive::End));
+  }
+
+  #[test]
+  fn test_parse_fill() {
+    assert_eq!(
+      parse_fill().parse(".fill xffff"),
+      Ok(Directive::Fill(Operand::Number(0xFFFF)))
+    );
+    assert_eq!(
+      parse_fill().parse(".fill #12"),
+      Ok(Directive::Fill(Operand::Number(12)))
+    );
+    assert_eq!(
+      parse_fill().parse(".fill table"),
+      Ok(Directive::Fill(Operand::Label("table".to_string())))
+    );
+  }
+
+  #[test]
+  fn test_parse_fill_negative() {
+    assert_eq!(
+      parse_fill().parse(".fill #-1"),
+      Ok(Directive::Fill(Operand::Number(-1)))
+    );
+    assert!(parse_fill().parse(".fill x10000").is_err());
+  }
+
+  #[test]
+  fn test_parse_blkw() {
+    assert_eq!(parse_blkw().parse(".blkw #10"), Ok(Directive::Blkw(10)));
+  }
+
+  #[test]
+  fn test_parse_stringz() {
+    assert_eq!(
+      parse_stringz().parse(r#".stringz "Hello, World!""#),
+      Ok(Directive::Stringz("Hello, World!".to_string()))
+    );
+    assert_eq!(
+      parse_stringz().parse(r#".stringz "a\n\"b\"\\""#),
+      Ok(Directive::Stringz("a\n\"b\"\\".to_string()))
+    );
+    assert!(parse_stringz().parse(r#".stringz "\q""#).is_err());
+  }
+
+  #[test]
+  fn test_parse_stringp() {
+    assert_eq!(
+      parse_directive().parse(r#".stringp "hey""#),
+      Ok(Directive::Stringp("hey".to_string()))
+    );
+    assert_eq!(
+      parse_directive().parse(r#".stringz "hey""#),
+      Ok(Directive::Stringz("hey".to_string()))
+    );
+  }
+
+  #[test]
+  fn test_pack_string() {
+    assert_eq!(pack_string("hey"), vec![0x6568, 0x0079]);
+    assert_eq!(pack_string("hi"), vec![0x6968, 0x0000]);
+    assert_eq!(pack_string(""), vec![0x0000]);
+  }
+}
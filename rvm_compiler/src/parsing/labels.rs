@@ -0,0 +1,248 @@
+use std::collections::{BTreeMap, HashMap};
+
+use ariadne::{Color, Fmt, Label, Report, ReportKind};
+
+use crate::instructions::Instruction;
+use crate::parsing::directives::pack_string;
+use crate::parsing::utils::signed_range;
+use crate::parsing::{Directive, Item, Operand, Span, Statement};
+use crate::{Assembly, Program, Word, ORIGIN};
+
+type Symbols = HashMap<String, (u16, Span)>;
+
+/// Resolves label operands into pc-relative offsets and lays out directives.
+///
+/// The first pass assigns an address to every label, the second pass patches
+/// each [`Statement::Labeled`] with the offset from the instruction after it to
+/// the label. `source` is only used to find the line of each statement.
+pub fn resolve(items: Vec<(Item, Span)>, source: &str) -> Result<Assembly, Vec<Report<'static>>> {
+  let (origin, mut errors) = origin(&items);
+  let (symbols, symbol_errors) = symbol_table(&items, origin);
+  errors.extend(symbol_errors);
+
+  let line_starts = line_starts(source);
+
+  let mut words = Vec::new();
+  let mut lines = BTreeMap::new();
+  let mut address = origin;
+
+  for (item, span) in items {
+    let Item::Statement(statement) = item else {
+      continue;
+    };
+
+    let line = line_starts.partition_point(|&start| start <= span.start);
+
+    match statement {
+      Statement::Instruction(instruction) => words.push(Word::Instruction(instruction)),
+      Statement::Labeled(instruction, label) => {
+        let instruction = match lookup(&symbols, &label) {
+          Some(target) => {
+            let offset = i32::from(target) - (i32::from(address) + 1);
+
+            let bits = match instruction {
+              Instruction::Jsr(_) => 11,
+              _ => 9,
+            };
+
+            if signed_range(bits).contains(&offset) {
+              patch(instruction, offset as i16)
+            } else {
+              errors.push(
+                error(
+                  span,
+                  format!("Label {} is out of range", label.fg(Color::Red)),
+                  format!("offset {offset} does not fit in {bits} bits"),
+                )
+                .finish(),
+              );
+              instruction
+            }
+          }
+          None => {
+            errors.push(undefined(&label, span));
+            instruction
+          }
+        };
+
+        words.push(Word::Instruction(instruction));
+      }
+      Statement::Directive(Directive::Orig(_)) => {}
+      Statement::Directive(Directive::End) => break,
+      Statement::Directive(Directive::Fill(Operand::Number(value))) => {
+        words.push(Word::Data(value as u16))
+      }
+      Statement::Directive(Directive::Fill(Operand::Label(label))) => {
+        match lookup(&symbols, &label) {
+          Some(target) => words.push(Word::Data(target)),
+          None => {
+            errors.push(undefined(&label, span));
+            words.push(Word::Data(0));
+          }
+        }
+      }
+      Statement::Directive(Directive::Blkw(count)) => {
+        words.extend((0..count).map(|_| Word::Data(0)));
+      }
+      Statement::Directive(Directive::Stringz(string)) => {
+        words.extend(string.chars().map(|c| Word::Data(c as u16)));
+        words.push(Word::Data(0));
+      }
+      Statement::Directive(Directive::Stringp(string)) => {
+        words.extend(pack_string(&string).into_iter().map(Word::Data));
+      }
+    }
+
+    let next = origin.wrapping_add(words.len() as u16);
+
+    if next != address {
+      lines.insert(address, line);
+    }
+
+    address = next;
+  }
+
+  let symbols: BTreeMap<String, u16> = symbols
+    .into_iter()
+    .map(|(label, (address, _))| (label, address))
+    .collect();
+
+  if errors.is_empty() {
+    Ok(Assembly {
+      program: Program { origin, words },
+      symbols,
+      lines,
+    })
+  } else {
+    Err(errors)
+  }
+}
+
+/// Where each line starts, counted in characters like spans are.
+fn line_starts(source: &str) -> Vec<usize> {
+  let newlines = source
+    .chars()
+    .enumerate()
+    .filter(|&(_, c)| c == '\n')
+    .map(|(i, _)| i + 1);
+
+  std::iter::once(0).chain(newlines).collect()
+}
+
+/// The load address from `.orig`, which has to come before any other statement.
+fn origin(items: &[(Item, Span)]) -> (u16, Vec<Report<'static>>) {
+  let mut origin = None;
+  let mut errors = Vec::new();
+
+  for (index, (item, span)) in items.iter().enumerate() {
+    let Item::Statement(statement) = item else {
+      continue;
+    };
+
+    match statement {
+      Statement::Directive(Directive::Orig(address)) => {
+        let is_first = items[..index]
+          .iter()
+          .all(|(item, _)| matches!(item, Item::Label(_)));
+
+        if is_first {
+          origin = Some(*address);
+        } else {
+          errors.push(
+            error(
+              span.clone(),
+              format!("Misplaced {}", ".orig".fg(Color::Red)),
+              "`.orig` has to come before any other statement".to_string(),
+            )
+            .finish(),
+          );
+        }
+      }
+      Statement::Directive(Directive::End) => break,
+      _ => {}
+    }
+  }
+
+  (origin.unwrap_or(ORIGIN), errors)
+}
+
+/// First pass: the address of every label, along with the span it was defined
+/// at.
+fn symbol_table(items: &[(Item, Span)], origin: u16) -> (Symbols, Vec<Report<'static>>) {
+  let mut symbols = Symbols::new();
+  let mut errors = Vec::new();
+  let mut address = origin;
+
+  for (item, span) in items {
+    match item {
+      Item::Label(label) => {
+        if let Some((_, previous)) = symbols.get(label) {
+          errors.push(
+            error(
+              span.clone(),
+              format!("Duplicate label {}", label.fg(Color::Red)),
+              "label is already defined".to_string(),
+            )
+            .with_label(
+              Label::new(previous.clone())
+                .with_message("first defined here")
+                .with_color(Color::Yellow),
+            )
+            .finish(),
+          );
+        } else {
+          symbols.insert(label.clone(), (address, span.clone()));
+        }
+      }
+      Item::Statement(Statement::Directive(Directive::End)) => break,
+      Item::Statement(statement) => address = address.wrapping_add(size(statement)),
+    }
+  }
+
+  (symbols, errors)
+}
+
+/// Number of words `statement` takes up in the image.
+fn size(statement: &Statement) -> u16 {
+  match statement {
+    Statement::Instruction(_) | Statement::Labeled(..) => 1,
+    Statement::Directive(Directive::Orig(_) | Directive::End) => 0,
+    Statement::Directive(Directive::Fill(_)) => 1,
+    Statement::Directive(Directive::Blkw(count)) => *count,
+    Statement::Directive(Directive::Stringz(string)) => string.chars().count() as u16 + 1,
+    Statement::Directive(Directive::Stringp(string)) => string.len() as u16 / 2 + 1,
+  }
+}
+
+fn lookup(symbols: &Symbols, label: &str) -> Option<u16> {
+  symbols.get(label).map(|&(address, _)| address)
+}
+
+fn undefined(label: &str, span: Span) -> Report<'static> {
+  error(
+    span,
+    format!("Undefined label {}", label.fg(Color::Red)),
+    "label is never defined".to_string(),
+  )
+  .finish()
+}
+
+fn patch(instruction: Instruction, offset: i16) -> Instruction {
+  match instruction {
+    Instruction::Br(n, z, p, _) => Instruction::Br(n, z, p, offset),
+    Instruction::Ld(dr, _) => Instruction::Ld(dr, offset),
+    Instruction::St(sr, _) => Instruction::St(sr, offset),
+    Instruction::Jsr(_) => Instruction::Jsr(offset),
+    Instruction::Ldi(dr, _) => Instruction::Ldi(dr, offset),
+    Instruction::Sti(sr, _) => Instruction::Sti(sr, offset),
+    Instruction::Lea(dr, _) => Instruction::Lea(dr, offset),
+    _ => unreachable!("{instruction:?} has no pc-relative offset"),
+  }
+}
+
+fn error(span: Span, msg: String, label: String) -> ariadne::ReportBuilder<'static, Span> {
+  Report::build(ReportKind::Error, (), span.start)
+    .with_code(4)
+    .with_message(msg)
+    .with_label(Label::new(span).with_message(label).with_color(Color::Red))
+}
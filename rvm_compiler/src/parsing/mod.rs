@@ -1,24 +1,75 @@
 use ariadne::{Color, Fmt, Label, Report, ReportKind, Source};
 use chumsky::error::Simple;
-use chumsky::primitive::{choice, end};
-use chumsky::text::{newline, TextParser};
+use chumsky::primitive::{any, choice, end};
+use chumsky::text::TextParser;
 use chumsky::Parser;
+use directives::{parse_directive, parse_end};
+use labels::resolve;
 use ops::{
-  parse_add, parse_and, parse_br, parse_halt, parse_jmp, parse_jsr, parse_ld, parse_ldi, parse_ldr,
-  parse_lea, parse_not, parse_res, parse_rti, parse_st, parse_sti, parse_str, parse_trap,
+  parse_add, parse_and, parse_br, parse_halt, parse_jmp, parse_jsr, parse_jsrr, parse_ld,
+  parse_ldi, parse_ldr, parse_lea, parse_not, parse_res, parse_rti, parse_st, parse_sti, parse_str,
+  parse_trap,
 };
-use utils::comment;
+use utils::{comment, parse_label_definition};
 
 use crate::instructions::Instruction;
-use crate::Program;
+use crate::{Assembly, Program};
 
+pub mod directives;
+pub mod labels;
 pub mod ops;
 pub mod registers;
 pub mod utils;
 
-pub fn parse(input: &str) -> Result<Program, Vec<Report>> {
+pub type Span = std::ops::Range<usize>;
+
+/// Operand of a pc-relative instruction.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Operand {
+  Number(i32),
+  Label(String),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Statement {
+  Instruction(Instruction),
+  /// Instruction whose pc-relative offset points at a label, resolved by
+  /// [`labels::resolve`].
+  Labeled(Instruction, String),
+  Directive(Directive),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Directive {
+  /// `.orig`, the address the program is loaded at
+  Orig(u16),
+  /// `.end`, anything after it is ignored
+  End,
+  /// `.fill`, a single word
+  Fill(Operand),
+  /// `.blkw`, a block of zeroed words
+  Blkw(u16),
+  /// `.stringz`, a zero terminated string with one character per word
+  Stringz(String),
+  /// `.stringp`, a zero terminated string with two bytes per word
+  Stringp(String),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Item {
+  /// `label:`, naming the address of the next statement
+  Label(String),
+  Statement(Statement),
+}
+
+pub fn parse(input: &str) -> Result<Program, Vec<Report<'_>>> {
+  assemble(input).map(|assembly| assembly.program)
+}
+
+/// Like [`parse`], keeping the address of every label.
+pub fn assemble(input: &str) -> Result<Assembly, Vec<Report<'_>>> {
   match parse_program().parse(input) {
-    Ok(program) => Ok(program),
+    Ok(items) => resolve(items, input),
     Err(errs) => Err(
       errs
         .into_iter()
@@ -89,39 +140,96 @@ pub fn parse(input: &str) -> Result<Program, Vec<Report>> {
   }
 }
 
-pub fn print_errors(input: &str, errs: Vec<Report>) {
+/// Lowercases `input` for parsing, leaving the contents of string literals
+/// untouched.
+pub fn lowercase(input: &str) -> String {
+  let mut out = String::with_capacity(input.len());
+  let mut in_string = false;
+  let mut in_comment = false;
+  let mut escaped = false;
+
+  for c in input.chars() {
+    if in_string {
+      out.push(c);
+
+      if escaped {
+        escaped = false;
+      } else if c == '\\' {
+        escaped = true;
+      } else if c == '"' || c == '\n' {
+        in_string = false;
+      }
+
+      continue;
+    }
+
+    match c {
+      ';' => in_comment = true,
+      '\n' => in_comment = false,
+      '"' if !in_comment => in_string = true,
+      _ => {}
+    }
+
+    out.push(c.to_ascii_lowercase());
+  }
+
+  out
+}
+
+pub fn print_errors(input: &str, errs: Vec<Report<'_>>) {
   for err in errs {
     err.eprint(Source::from(input)).unwrap();
   }
 }
 
-fn parse_program() -> impl Parser<char, Program, Error = Simple<char>> {
-  parse_instruction()
+fn parse_program() -> impl Parser<char, Vec<(Item, Span)>, Error = Simple<char>> {
+  let end_of_program = parse_end()
+    .map_with_span(|end, span| (Item::Statement(Statement::Directive(end)), span))
+    .then_ignore(any().repeated());
+
+  comment()
+    .to(None)
+    .or(parse_item().map_with_span(|item, span| Some((item, span))))
     .padded()
-    .then_ignore(comment().or_not())
-    .separated_by(newline().or_not())
-    .allow_trailing()
+    .repeated()
+    .then(end_of_program.or_not())
     .then_ignore(end())
-    .map(|instructions| Program { instructions })
+    .map(|(items, end)| items.into_iter().flatten().chain(end).collect())
 }
 
-fn parse_instruction() -> impl Parser<char, Instruction, Error = Simple<char>> {
+fn parse_item() -> impl Parser<char, Item, Error = Simple<char>> {
+  parse_label_definition()
+    .map(Item::Label)
+    .or(parse_statement().map(Item::Statement))
+}
+
+fn parse_statement() -> impl Parser<char, Statement, Error = Simple<char>> {
   choice((
     parse_br(),
-    parse_add(),
     parse_ld(),
     parse_st(),
+    // `jsrr r0` would otherwise parse as `jsr` to a label named `r`
+    parse_jsrr().map(Statement::Instruction),
     parse_jsr(),
+    parse_ldi(),
+    parse_sti(),
+    parse_lea(),
+    parse_instruction().map(Statement::Instruction),
+    parse_directive().map(Statement::Directive),
+  ))
+}
+
+fn parse_instruction() -> impl Parser<char, Instruction, Error = Simple<char>> {
+  choice((
+    parse_add(),
+    parse_jsrr(),
     parse_and(),
     parse_ldr(),
     parse_str(),
     parse_rti(),
     parse_not(),
-    parse_ldi(),
-    parse_sti(),
     parse_jmp(),
     parse_res(),
-    parse_lea(),
     parse_trap(),
     parse_halt(),
   ))
@@ -132,6 +240,11 @@ mod tests {
   use super::*;
   use crate::instructions::TrapVect;
   use crate::registers::Register;
+  use crate::{Word, ORIGIN};
+
+  fn assemble(input: &str) -> Program {
+    parse(input).unwrap_or_else(|errs| panic!("failed to parse: {} errors", errs.len()))
+  }
 
   #[test]
   fn test_parse_instruction() {
@@ -140,50 +253,244 @@ mod tests {
       Instruction::Add1(Register::R0, Register::R1, Register::R2)
     );
     assert_eq!(
-      parse_instruction().parse("lea r0, x2").unwrap(),
-      Instruction::Lea(Register::R0, 0x2)
+      parse_statement().parse("lea r0, x2").unwrap(),
+      Statement::Instruction(Instruction::Lea(Register::R0, 0x2))
     );
     assert_eq!(
       parse_instruction().parse("trap tgetc").unwrap(),
       Instruction::Trap(TrapVect::GetC)
     );
+    assert_eq!(
+      parse_statement().parse("jsrr r3").unwrap(),
+      Statement::Instruction(Instruction::Jsrr(Register::R3))
+    );
+  }
+
+  #[test]
+  fn test_parse_item() {
+    assert_eq!(
+      parse_item().parse("loop:").unwrap(),
+      Item::Label("loop".to_string())
+    );
+    assert_eq!(
+      parse_item().parse("brp loop").unwrap(),
+      Item::Statement(Statement::Labeled(
+        Instruction::Br(false, false, true, 0),
+        "loop".to_string()
+      ))
+    );
   }
 
   #[test]
   fn test_parse() {
     assert_eq!(
-      parse_program().parse(
+      assemble(
         "add r0, r1, r2
         lea r0, x2
         trap tgetc
         "
       ),
-      Ok(Program {
-        instructions: vec![
-          Instruction::Add1(Register::R0, Register::R1, Register::R2),
-          Instruction::Lea(Register::R0, 0x2),
-          Instruction::Trap(TrapVect::GetC),
+      Program {
+        origin: ORIGIN,
+        words: vec![
+          Word::Instruction(Instruction::Add1(Register::R0, Register::R1, Register::R2)),
+          Word::Instruction(Instruction::Lea(Register::R0, 0x2)),
+          Word::Instruction(Instruction::Trap(TrapVect::GetC)),
         ]
-      })
+      }
     );
   }
 
   #[test]
   fn test_parse_with_comments() {
     assert_eq!(
-      parse_program().parse(
-        "add r0, r1, r2 ; comment
+      assemble(
+        "; leading comment
+        add r0, r1, r2 ; comment
         lea r0, x2
+        ; comment on its own line
         trap tgetc ; awa
         "
       ),
-      Ok(Program {
-        instructions: vec![
-          Instruction::Add1(Register::R0, Register::R1, Register::R2),
-          Instruction::Lea(Register::R0, 0x2),
-          Instruction::Trap(TrapVect::GetC),
+      Program {
+        origin: ORIGIN,
+        words: vec![
+          Word::Instruction(Instruction::Add1(Register::R0, Register::R1, Register::R2)),
+          Word::Instruction(Instruction::Lea(Register::R0, 0x2)),
+          Word::Instruction(Instruction::Trap(TrapVect::GetC)),
+        ]
+      }
+    );
+  }
+
+  #[test]
+  fn test_parse_labels() {
+    assert_eq!(
+      assemble(
+        "start: lea r0, message
+        jsr print
+        brnzp end
+        print:
+        trap tputs
+        jmp r7
+        message: add r0, r0, r0
+        end: halt
+        "
+      ),
+      Program {
+        origin: ORIGIN,
+        words: vec![
+          Word::Instruction(Instruction::Lea(Register::R0, 4)),
+          Word::Instruction(Instruction::Jsr(1)),
+          Word::Instruction(Instruction::Br(true, true, true, 3)),
+          Word::Instruction(Instruction::Trap(TrapVect::PutS)),
+          Word::Instruction(Instruction::Jmp(Register::R7)),
+          Word::Instruction(Instruction::Add1(Register::R0, Register::R0, Register::R0)),
+          Word::Instruction(Instruction::Trap(TrapVect::Halt)),
         ]
-      })
+      }
     );
   }
+
+  #[test]
+  fn test_assemble_symbols_and_lines() {
+    let assembly = super::assemble(
+      ".orig x4000\nstart: add r0, r0, #1\nbrp start\n\nend: halt\n.stringz \"hi\"\n",
+    )
+    .unwrap_or_else(|errs| panic!("failed to parse: {} errors", errs.len()));
+
+    assert_eq!(assembly.program.origin, 0x4000);
+    assert_eq!(
+      assembly.symbols.into_iter().collect::<Vec<_>>(),
+      [("end".to_string(), 0x4002), ("start".to_string(), 0x4000)]
+    );
+    assert_eq!(
+      assembly.lines.into_iter().collect::<Vec<_>>(),
+      [(0x4000, 2), (0x4001, 3), (0x4002, 5), (0x4003, 6)]
+    );
+  }
+
+  #[test]
+  fn test_parse_undefined_label() {
+    assert_eq!(parse("brz nowhere").map_err(|errs| errs.len()), Err(1));
+  }
+
+  #[test]
+  fn test_parse_duplicate_label() {
+    assert_eq!(
+      parse("brz a\na: halt\na: halt").map_err(|errs| errs.len()),
+      Err(1)
+    );
+  }
+
+  #[test]
+  fn test_parse_directives() {
+    assert_eq!(
+      assemble(
+        r#".orig x4000
+        lea r0, message
+        trap tputs
+        ld r1, table
+        halt
+        table: .fill message
+        .fill #7
+        buffer: .blkw #2
+        message: .stringz "Hi\n"
+        .end
+        this is ignored
+        "#
+      ),
+      Program {
+        origin: 0x4000,
+        words: vec![
+          Word::Instruction(Instruction::Lea(Register::R0, 7)),
+          Word::Instruction(Instruction::Trap(TrapVect::PutS)),
+          Word::Instruction(Instruction::Ld(Register::R1, 1)),
+          Word::Instruction(Instruction::Trap(TrapVect::Halt)),
+          Word::Data(0x4008),
+          Word::Data(7),
+          Word::Data(0),
+          Word::Data(0),
+          Word::Data('H' as u16),
+          Word::Data('i' as u16),
+          Word::Data('\n' as u16),
+          Word::Data(0),
+        ]
+      }
+    );
+  }
+
+  #[test]
+  fn test_parse_stringp() {
+    assert_eq!(
+      assemble(
+        r#"lea r0, odd
+        lea r0, even
+        odd: .stringp "abc"
+        even: .stringp "ab"
+        "#
+      ),
+      Program {
+        origin: 0x3000,
+        words: vec![
+          Word::Instruction(Instruction::Lea(Register::R0, 1)),
+          Word::Instruction(Instruction::Lea(Register::R0, 2)),
+          Word::Data(0x6261),
+          Word::Data(0x0063),
+          Word::Data(0x6261),
+          Word::Data(0x0000),
+        ]
+      }
+    );
+  }
+
+  #[test]
+  fn test_parse_misplaced_orig() {
+    assert_eq!(
+      parse("halt\n.orig x4000").map_err(|errs| errs.len()),
+      Err(1)
+    );
+  }
+
+  #[test]
+  fn test_lowercase() {
+    assert_eq!(
+      lowercase("LEA R0, MSG ; \"Quoted\" Comment\nMSG: .STRINGZ \"Hello \\\"World\\\"\" ; X"),
+      "lea r0, msg ; \"quoted\" comment\nmsg: .stringz \"Hello \\\"World\\\"\" ; x"
+    );
+  }
+
+  #[test]
+  fn test_parse_backward_label() {
+    assert_eq!(
+      assemble(
+        "loop: add r1, r1, #-1
+        brp loop
+        halt
+        "
+      ),
+      Program {
+        origin: ORIGIN,
+        words: vec![
+          Word::Instruction(Instruction::Add2(Register::R1, Register::R1, -1)),
+          Word::Instruction(Instruction::Br(false, false, true, -2)),
+          Word::Instruction(Instruction::Trap(TrapVect::Halt)),
+        ]
+      }
+    );
+  }
+
+  #[test]
+  fn test_parse_label_out_of_range() {
+    assert_eq!(
+      parse("brz far\n.blkw #256\nfar: halt").map_err(|errs| errs.len()),
+      Err(1)
+    );
+    assert!(parse("brz far\n.blkw #255\nfar: halt").is_ok());
+  }
+
+  #[test]
+  fn test_parse_immediate_out_of_range() {
+    assert_eq!(parse("add r0, r0, #16").map_err(|errs| errs.len()), Err(1));
+  }
 }
@@ -5,16 +5,19 @@ use chumsky::Parser;
 
 use crate::instructions::{Instruction, TrapVect};
 use crate::parsing::registers::parse_register;
-use crate::parsing::utils::{comma, parse_number};
+use crate::parsing::utils::{comma, parse_operand, parse_signed, parse_unsigned, signed_range};
+use crate::parsing::{Operand, Statement};
 
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum Op {
+  BrNZP,
   BrNZ,
   BrNP,
   BrZP,
   BrN,
   BrZ,
   BrP,
+  Br,
   Add,
   Ld,
   St,
@@ -32,6 +35,23 @@ pub enum Op {
   Trap,
 }
 
+/// Builds the statement for an instruction with a pc-relative `operand`.
+///
+/// Label operands are left as a [`Statement::Labeled`] with a zero offset, to
+/// be filled in once every label's address is known.
+fn with_operand(operand: Operand, f: impl Fn(i16) -> Instruction) -> Statement {
+  match operand {
+    Operand::Number(offset) => Statement::Instruction(f(offset as i16)),
+    Operand::Label(label) => Statement::Labeled(f(0), label),
+  }
+}
+
+/// BRNZP, BR
+/// ```
+/// 0000 1 1 1 XXXXXXXXX
+/// op   N Z P offset9
+/// ```
+///
 /// BRNZ
 /// ```
 /// 0000 1 1 0 XXXXXXXXX
@@ -67,25 +87,32 @@ pub enum Op {
 /// 0000 0 0 1 XXXXXXXXX
 /// op   N Z P offset9
 /// ```
-pub fn parse_br() -> impl Parser<char, Instruction, Error = Simple<char>> {
+pub fn parse_br() -> impl Parser<char, Statement, Error = Simple<char>> {
   choice((
+    just("brnzp").map(|_| Op::BrNZP),
     just("brnz").map(|_| Op::BrNZ),
     just("brnp").map(|_| Op::BrNP),
     just("brzp").map(|_| Op::BrZP),
     just("brn").map(|_| Op::BrN),
     just("brz").map(|_| Op::BrZ),
     just("brp").map(|_| Op::BrP),
+    just("br").map(|_| Op::Br),
   ))
   .padded()
-  .then(parse_number())
-  .map(|(br, offset)| match br {
-    Op::BrNZ => Instruction::Br(true, true, false, offset as u8),
-    Op::BrNP => Instruction::Br(true, false, true, offset as u8),
-    Op::BrZP => Instruction::Br(false, true, true, offset as u8),
-    Op::BrN => Instruction::Br(true, false, false, offset as u8),
-    Op::BrZ => Instruction::Br(false, true, false, offset as u8),
-    Op::BrP => Instruction::Br(false, false, true, offset as u8),
-    _ => unreachable!(),
+  .then(parse_operand(signed_range(9)))
+  .map(|(br, operand)| {
+    let (n, z, p) = match br {
+      Op::BrNZP | Op::Br => (true, true, true),
+      Op::BrNZ => (true, true, false),
+      Op::BrNP => (true, false, true),
+      Op::BrZP => (false, true, true),
+      Op::BrN => (true, false, false),
+      Op::BrZ => (false, true, false),
+      Op::BrP => (false, false, true),
+      _ => unreachable!(),
+    };
+
+    with_operand(operand, |offset| Instruction::Br(n, z, p, offset))
   })
 }
 
@@ -138,8 +165,8 @@ pub fn parse_add2() -> impl Parser<char, Instruction, Error = Simple<char>> {
     .padded()
     .ignore_then(parse_register().separated_by(comma()).exactly(2))
     .then_ignore(comma())
-    .then(parse_number())
-    .try_map(|(registers, address), span| {
+    .then(parse_signed(5))
+    .try_map(|(registers, imm5), span| {
       if registers.len() != 2 {
         return Err(Simple::custom(span, "invalid add op"));
       }
@@ -147,7 +174,7 @@ pub fn parse_add2() -> impl Parser<char, Instruction, Error = Simple<char>> {
       let dr = registers[0];
       let sr1 = registers[1];
 
-      Ok(Instruction::Add2(dr, sr1, address as u8))
+      Ok(Instruction::Add2(dr, sr1, imm5 as i8))
     })
 }
 
@@ -156,13 +183,13 @@ pub fn parse_add2() -> impl Parser<char, Instruction, Error = Simple<char>> {
 /// 0010 XXX XXXXXXXXX
 /// op   dr  offset9
 /// ```
-pub fn parse_ld() -> impl Parser<char, Instruction, Error = Simple<char>> {
+pub fn parse_ld() -> impl Parser<char, Statement, Error = Simple<char>> {
   just("ld")
     .padded()
     .ignore_then(parse_register())
     .then_ignore(comma())
-    .then(parse_number())
-    .map(|(dr, address)| Instruction::Ld(dr, address as u8))
+    .then(parse_operand(signed_range(9)))
+    .map(|(dr, operand)| with_operand(operand, |offset| Instruction::Ld(dr, offset)))
 }
 
 /// ST
@@ -170,13 +197,13 @@ pub fn parse_ld() -> impl Parser<char, Instruction, Error = Simple<char>> {
 /// 0011 XXX XXXXXXXXX
 /// op   sr  offset9
 /// ```
-pub fn parse_st() -> impl Parser<char, Instruction, Error = Simple<char>> {
+pub fn parse_st() -> impl Parser<char, Statement, Error = Simple<char>> {
   just("st")
     .padded()
     .ignore_then(parse_register())
     .then_ignore(comma())
-    .then(parse_number())
-    .map(|(sr, address)| Instruction::St(sr, address as u8))
+    .then(parse_operand(signed_range(9)))
+    .map(|(sr, operand)| with_operand(operand, |offset| Instruction::St(sr, offset)))
 }
 
 /// JSR
@@ -184,11 +211,11 @@ pub fn parse_st() -> impl Parser<char, Instruction, Error = Simple<char>> {
 /// 0100 1 XXXXXXXXXXX
 /// op     offset11
 /// ```
-pub fn parse_jsr() -> impl Parser<char, Instruction, Error = Simple<char>> {
+pub fn parse_jsr() -> impl Parser<char, Statement, Error = Simple<char>> {
   just("jsr")
     .padded()
-    .ignore_then(parse_number())
-    .map(|address| Instruction::Jsr(address as u8))
+    .ignore_then(parse_operand(signed_range(11)))
+    .map(|operand| with_operand(operand, Instruction::Jsr))
 }
 
 /// JSRR
@@ -250,8 +277,8 @@ pub fn parse_and2() -> impl Parser<char, Instruction, Error = Simple<char>> {
     .padded()
     .ignore_then(parse_register().separated_by(comma()).exactly(2))
     .then_ignore(comma())
-    .then(parse_number())
-    .try_map(|(registers, address), span| {
+    .then(parse_signed(5))
+    .try_map(|(registers, imm5), span| {
       if registers.len() != 2 {
         return Err(Simple::custom(span, "invalid and op"));
       }
@@ -259,7 +286,7 @@ pub fn parse_and2() -> impl Parser<char, Instruction, Error = Simple<char>> {
       let dr = registers[0];
       let sr1 = registers[1];
 
-      Ok(Instruction::And2(dr, sr1, address as u8))
+      Ok(Instruction::And2(dr, sr1, imm5 as i8))
     })
 }
 
@@ -273,8 +300,8 @@ pub fn parse_ldr() -> impl Parser<char, Instruction, Error = Simple<char>> {
     .padded()
     .ignore_then(parse_register().separated_by(comma()).exactly(2))
     .then_ignore(comma())
-    .then(parse_number())
-    .try_map(|(args, address), span| {
+    .then(parse_signed(6))
+    .try_map(|(args, offset6), span| {
       if args.len() != 2 {
         return Err(Simple::custom(span, "invalid ldr op"));
       }
@@ -282,7 +309,7 @@ pub fn parse_ldr() -> impl Parser<char, Instruction, Error = Simple<char>> {
       let dr = args[0];
       let base_r = args[1];
 
-      Ok(Instruction::Ldr(dr, base_r, address as u8))
+      Ok(Instruction::Ldr(dr, base_r, offset6 as i8))
     })
 }
 
@@ -296,8 +323,8 @@ pub fn parse_str() -> impl Parser<char, Instruction, Error = Simple<char>> {
     .padded()
     .ignore_then(parse_register().separated_by(comma()).exactly(2))
     .then_ignore(comma())
-    .then(parse_number())
-    .try_map(|(args, address), span| {
+    .then(parse_signed(6))
+    .try_map(|(args, offset6), span| {
       if args.len() != 2 {
         return Err(Simple::custom(span, "invalid str op"));
       }
@@ -305,7 +332,7 @@ pub fn parse_str() -> impl Parser<char, Instruction, Error = Simple<char>> {
       let dr = args[0];
       let base_r = args[1];
 
-      Ok(Instruction::Str(dr, base_r, address as u8))
+      Ok(Instruction::Str(dr, base_r, offset6 as i8))
     })
 }
 
@@ -344,13 +371,13 @@ pub fn parse_not() -> impl Parser<char, Instruction, Error = Simple<char>> {
 /// 1010 XXX XXXXXXXXX
 /// op   dr  offset9
 /// ```
-pub fn parse_ldi() -> impl Parser<char, Instruction, Error = Simple<char>> {
+pub fn parse_ldi() -> impl Parser<char, Statement, Error = Simple<char>> {
   just("ldi")
     .padded()
     .ignore_then(parse_register())
     .then_ignore(comma())
-    .then(parse_number())
-    .map(|(dr, address)| Instruction::Ldi(dr, address as u8))
+    .then(parse_operand(signed_range(9)))
+    .map(|(dr, operand)| with_operand(operand, |offset| Instruction::Ldi(dr, offset)))
 }
 
 /// STI
@@ -358,13 +385,13 @@ pub fn parse_ldi() -> impl Parser<char, Instruction, Error = Simple<char>> {
 /// 1011 XXX XXXXXXXXX
 /// op   sr  offset9
 /// ```
-pub fn parse_sti() -> impl Parser<char, Instruction, Error = Simple<char>> {
+pub fn parse_sti() -> impl Parser<char, Statement, Error = Simple<char>> {
   just("sti")
     .padded()
     .ignore_then(parse_register())
     .then_ignore(comma())
-    .then(parse_number())
-    .map(|(sr, address)| Instruction::Sti(sr, address as u8))
+    .then(parse_operand(signed_range(9)))
+    .map(|(sr, operand)| with_operand(operand, |offset| Instruction::Sti(sr, offset)))
 }
 
 /// JMP
@@ -393,13 +420,13 @@ pub fn parse_res() -> impl Parser<char, Instruction, Error = Simple<char>> {
 /// 1110 XXX XXXXXXXXX
 /// op   dr  offset9
 /// ```
-pub fn parse_lea() -> impl Parser<char, Instruction, Error = Simple<char>> {
+pub fn parse_lea() -> impl Parser<char, Statement, Error = Simple<char>> {
   just("lea")
     .padded()
     .ignore_then(parse_register())
     .then_ignore(comma())
-    .then(parse_number())
-    .map(|(dr, address)| Instruction::Lea(dr, address as u8))
+    .then(parse_operand(signed_range(9)))
+    .map(|(dr, operand)| with_operand(operand, |offset| Instruction::Lea(dr, offset)))
 }
 
 /// TRAP
@@ -417,9 +444,11 @@ pub fn parse_trap() -> impl Parser<char, Instruction, Error = Simple<char>> {
       just("toutu16").map(|_| TrapVect::OutU16),
       just("tgetc").map(|_| TrapVect::GetC),
       just("toutc").map(|_| TrapVect::OutC),
+      just("tputsp").map(|_| TrapVect::PutSp),
       just("tputs").map(|_| TrapVect::PutS),
       just("thalt").map(|_| TrapVect::Halt),
       just("tin").map(|_| TrapVect::In),
+      parse_unsigned(8).map(|vect| TrapVect::decode(vect as u8)),
     )))
     .map(Instruction::Trap)
 }
@@ -441,62 +470,125 @@ mod tests {
   fn test_parse_br() {
     assert_eq!(
       parse_br().parse("brnz x12"),
-      Ok(Instruction::Br(true, true, false, 0x12))
+      Ok(Statement::Instruction(Instruction::Br(
+        true, true, false, 0x12
+      )))
     );
 
     assert_eq!(
       parse_br().parse("brnp x12"),
-      Ok(Instruction::Br(true, false, true, 0x12))
+      Ok(Statement::Instruction(Instruction::Br(
+        true, false, true, 0x12
+      )))
     );
 
     assert_eq!(
       parse_br().parse("brzp x12"),
-      Ok(Instruction::Br(false, true, true, 0x12))
+      Ok(Statement::Instruction(Instruction::Br(
+        false, true, true, 0x12
+      )))
     );
 
     assert_eq!(
       parse_br().parse("brn x12"),
-      Ok(Instruction::Br(true, false, false, 0x12))
+      Ok(Statement::Instruction(Instruction::Br(
+        true, false, false, 0x12
+      )))
     );
 
     assert_eq!(
       parse_br().parse("brz x12"),
-      Ok(Instruction::Br(false, true, false, 0x12))
+      Ok(Statement::Instruction(Instruction::Br(
+        false, true, false, 0x12
+      )))
     );
 
     assert_eq!(
       parse_br().parse("brp x12"),
-      Ok(Instruction::Br(false, false, true, 0x12))
+      Ok(Statement::Instruction(Instruction::Br(
+        false, false, true, 0x12
+      )))
     );
 
     assert_eq!(
       parse_br().parse("brnz #12"),
-      Ok(Instruction::Br(true, true, false, 12))
+      Ok(Statement::Instruction(Instruction::Br(
+        true, true, false, 12
+      )))
     );
 
     assert_eq!(
       parse_br().parse("brnp #12"),
-      Ok(Instruction::Br(true, false, true, 12))
+      Ok(Statement::Instruction(Instruction::Br(
+        true, false, true, 12
+      )))
     );
 
     assert_eq!(
       parse_br().parse("brzp #12"),
-      Ok(Instruction::Br(false, true, true, 12))
+      Ok(Statement::Instruction(Instruction::Br(
+        false, true, true, 12
+      )))
     );
 
     assert_eq!(
       parse_br().parse("brn #12"),
-      Ok(Instruction::Br(true, false, false, 12))
+      Ok(Statement::Instruction(Instruction::Br(
+        true, false, false, 12
+      )))
     );
 
     assert_eq!(
       parse_br().parse("brz #12"),
-      Ok(Instruction::Br(false, true, false, 12))
+      Ok(Statement::Instruction(Instruction::Br(
+        false, true, false, 12
+      )))
     );
 
     assert_eq!(
       parse_br().parse("brp #12"),
-      Ok(Instruction::Br(false, false, true, 12))
+      Ok(Statement::Instruction(Instruction::Br(
+        false, false, true, 12
+      )))
+    );
+
+    assert_eq!(
+      parse_br().parse("brnzp #12"),
+      Ok(Statement::Instruction(Instruction::Br(
+        true, true, true, 12
+      )))
+    );
+
+    assert_eq!(
+      parse_br().parse("br #12"),
+      Ok(Statement::Instruction(Instruction::Br(
+        true, true, true, 12
+      )))
+    );
+
+    assert_eq!(
+      parse_br().parse("brnzp #-1"),
+      Ok(Statement::Instruction(Instruction::Br(
+        true, true, true, -1
+      )))
+    );
+
+    assert_eq!(
+      parse_br().parse("brnzp x-100"),
+      Ok(Statement::Instruction(Instruction::Br(
+        true, true, true, -256
+      )))
+    );
+
+    assert!(parse_br().parse("brnzp #256").is_err());
+    assert!(parse_br().parse("brnzp #-257").is_err());
+
+    assert_eq!(
+      parse_br().parse("brz loop"),
+      Ok(Statement::Labeled(
+        Instruction::Br(false, true, false, 0),
+        "loop".to_string()
+      ))
     );
   }
 
@@ -517,6 +609,20 @@ mod tests {
     );
   }
 
+  #[test]
+  fn test_parse_add_negative() {
+    assert_eq!(
+      parse_add().parse("add r1, r1, #-1"),
+      Ok(Instruction::Add2(Register::R1, Register::R1, -1))
+    );
+    assert_eq!(
+      parse_add().parse("add r1, r1, #-16"),
+      Ok(Instruction::Add2(Register::R1, Register::R1, -16))
+    );
+    assert!(parse_add().parse("add r1, r1, #16").is_err());
+    assert!(parse_add().parse("add r1, r1, #-17").is_err());
+  }
+
   #[test]
   fn test_parse_add1() {
     assert_eq!(
@@ -541,11 +647,11 @@ mod tests {
   fn test_parse_ld() {
     assert_eq!(
       parse_ld().parse("ld r0, #2"),
-      Ok(Instruction::Ld(Register::R0, 2))
+      Ok(Statement::Instruction(Instruction::Ld(Register::R0, 2)))
     );
     assert_eq!(
       parse_ld().parse("ld r0, x2"),
-      Ok(Instruction::Ld(Register::R0, 0x2))
+      Ok(Statement::Instruction(Instruction::Ld(Register::R0, 0x2)))
     );
   }
 
@@ -553,18 +659,34 @@ mod tests {
   fn test_parse_st() {
     assert_eq!(
       parse_st().parse("st r0, #2"),
-      Ok(Instruction::St(Register::R0, 2))
+      Ok(Statement::Instruction(Instruction::St(Register::R0, 2)))
     );
     assert_eq!(
       parse_st().parse("st r0, x2"),
-      Ok(Instruction::St(Register::R0, 0x2))
+      Ok(Statement::Instruction(Instruction::St(Register::R0, 0x2)))
     );
   }
 
   #[test]
   fn test_parse_jsr() {
-    assert_eq!(parse_jsr().parse("jsr #2"), Ok(Instruction::Jsr(2)));
-    assert_eq!(parse_jsr().parse("jsr x2"), Ok(Instruction::Jsr(0x2)));
+    assert_eq!(
+      parse_jsr().parse("jsr #2"),
+      Ok(Statement::Instruction(Instruction::Jsr(2)))
+    );
+    assert_eq!(
+      parse_jsr().parse("jsr x2"),
+      Ok(Statement::Instruction(Instruction::Jsr(0x2)))
+    );
+    assert_eq!(
+      parse_jsr().parse("jsr #-1024"),
+      Ok(Statement::Instruction(Instruction::Jsr(-1024)))
+    );
+    assert!(parse_jsr().parse("jsr #1024").is_err());
+    assert!(parse_jsr().parse("jsr #300").is_ok());
+    assert_eq!(
+      parse_jsr().parse("jsr print"),
+      Ok(Statement::Labeled(Instruction::Jsr(0), "print".to_string()))
+    );
   }
 
   #[test]
@@ -624,6 +746,15 @@ mod tests {
     );
   }
 
+  #[test]
+  fn test_parse_ldr_negative() {
+    assert_eq!(
+      parse_ldr().parse("ldr r0, r6, #-32"),
+      Ok(Instruction::Ldr(Register::R0, Register::R6, -32))
+    );
+    assert!(parse_ldr().parse("ldr r0, r6, #32").is_err());
+  }
+
   #[test]
   fn test_parse_str() {
     assert_eq!(
@@ -653,11 +784,11 @@ mod tests {
   fn test_parse_ldi() {
     assert_eq!(
       parse_ldi().parse("ldi r0, #2"),
-      Ok(Instruction::Ldi(Register::R0, 2))
+      Ok(Statement::Instruction(Instruction::Ldi(Register::R0, 2)))
     );
     assert_eq!(
       parse_ldi().parse("ldi r0, x2"),
-      Ok(Instruction::Ldi(Register::R0, 0x2))
+      Ok(Statement::Instruction(Instruction::Ldi(Register::R0, 0x2)))
     );
   }
 
@@ -665,11 +796,11 @@ mod tests {
   fn test_parse_sti() {
     assert_eq!(
       parse_sti().parse("sti r0, #2"),
-      Ok(Instruction::Sti(Register::R0, 2))
+      Ok(Statement::Instruction(Instruction::Sti(Register::R0, 2)))
     );
     assert_eq!(
       parse_sti().parse("sti r0, x2"),
-      Ok(Instruction::Sti(Register::R0, 0x2))
+      Ok(Statement::Instruction(Instruction::Sti(Register::R0, 0x2)))
     );
   }
 
@@ -686,15 +817,43 @@ mod tests {
     assert_eq!(parse_res().parse("res"), Ok(Instruction::Res));
   }
 
+  #[test]
+  fn test_parse_trap() {
+    assert_eq!(
+      parse_trap().parse("trap tputsp"),
+      Ok(Instruction::Trap(TrapVect::PutSp))
+    );
+    assert_eq!(
+      parse_trap().parse("trap tputs"),
+      Ok(Instruction::Trap(TrapVect::PutS))
+    );
+    assert_eq!(
+      parse_trap().parse("trap x25"),
+      Ok(Instruction::Trap(TrapVect::Halt))
+    );
+    assert_eq!(
+      parse_trap().parse("trap x30"),
+      Ok(Instruction::Trap(TrapVect::Other(0x30)))
+    );
+    assert!(parse_trap().parse("trap x100").is_err());
+  }
+
   #[test]
   fn test_parse_lea() {
     assert_eq!(
       parse_lea().parse("lea r0, #2"),
-      Ok(Instruction::Lea(Register::R0, 2))
+      Ok(Statement::Instruction(Instruction::Lea(Register::R0, 2)))
     );
     assert_eq!(
       parse_lea().parse("lea r0, x2"),
-      Ok(Instruction::Lea(Register::R0, 0x2))
+      Ok(Statement::Instruction(Instruction::Lea(Register::R0, 0x2)))
+    );
+    assert_eq!(
+      parse_lea().parse("lea r0, message"),
+      Ok(Statement::Labeled(
+        Instruction::Lea(Register::R0, 0),
+        "message".to_string()
+      ))
     );
   }
 }
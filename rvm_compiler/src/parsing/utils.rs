@@ -1,25 +1,108 @@
+use std::ops::RangeInclusive;
+
 use chumsky::error::Simple;
-use chumsky::primitive::{just, take_until};
-use chumsky::text::{int, newline, TextParser};
+use chumsky::primitive::{filter, just, take_until};
+use chumsky::text::{ident, newline, TextParser};
 use chumsky::Parser;
 
-pub fn parse_number() -> impl Parser<char, u16, Error = Simple<char>> {
+use crate::parsing::{Operand, Span};
+
+pub fn parse_number() -> impl Parser<char, i32, Error = Simple<char>> {
   parse_hex().or(parse_decimal())
 }
 
-/// `x123a`
-pub fn parse_hex() -> impl Parser<char, u16, Error = Simple<char>> {
-  just("x").ignore_then(int(16)).try_map(|x: String, span| {
-    u16::from_str_radix(&x, 16).map_err(|_| Simple::custom(span, "invalid hex number"))
-  })
+/// A number that fits in a `bits` wide two's complement field.
+pub fn parse_signed(bits: u32) -> impl Parser<char, i32, Error = Simple<char>> {
+  parse_number().try_map(move |x, span| check_range(x, signed_range(bits), span))
+}
+
+/// A number that fits in a `bits` wide unsigned field.
+pub fn parse_unsigned(bits: u32) -> impl Parser<char, i32, Error = Simple<char>> {
+  parse_number().try_map(move |x, span| check_range(x, 0..=(1 << bits) - 1, span))
+}
+
+/// Values a `bits` wide two's complement field can hold.
+pub fn signed_range(bits: u32) -> RangeInclusive<i32> {
+  -(1 << (bits - 1))..=(1 << (bits - 1)) - 1
+}
+
+/// Values a word can hold, either signed or unsigned.
+pub fn word_range() -> RangeInclusive<i32> {
+  i32::from(i16::MIN)..=i32::from(u16::MAX)
+}
+
+fn check_range(x: i32, range: RangeInclusive<i32>, span: Span) -> Result<i32, Simple<char>> {
+  if range.contains(&x) {
+    Ok(x)
+  } else {
+    Err(Simple::custom(
+      span,
+      format!(
+        "{x} is out of range, expected {} to {}",
+        range.start(),
+        range.end()
+      ),
+    ))
+  }
+}
+
+/// `x123a`, `#1234` or `label`, with numbers restricted to `range`
+pub fn parse_operand(
+  range: RangeInclusive<i32>,
+) -> impl Parser<char, Operand, Error = Simple<char>> {
+  parse_number()
+    .map(Operand::Number)
+    .or(parse_label().map(Operand::Label))
+    .try_map(move |operand, span| match operand {
+      Operand::Number(x) => check_range(x, range.clone(), span).map(Operand::Number),
+      label => Ok(label),
+    })
+}
+
+/// `loop`
+pub fn parse_label() -> impl Parser<char, String, Error = Simple<char>> {
+  ident()
+}
+
+/// `loop:`
+pub fn parse_label_definition() -> impl Parser<char, String, Error = Simple<char>> {
+  parse_label().then_ignore(just(':'))
+}
+
+/// `x123a` or `x-1f`
+pub fn parse_hex() -> impl Parser<char, i32, Error = Simple<char>> {
+  just("x")
+    .ignore_then(just('-').or_not())
+    .then(digits(16))
+    .try_map(|(sign, x): (_, String), span| {
+      let x =
+        i32::from_str_radix(&x, 16).map_err(|_| Simple::custom(span, "invalid hex number"))?;
+
+      Ok(if sign.is_some() { -x } else { x })
+    })
 }
 
-/// `#1234`
-pub fn parse_decimal() -> impl Parser<char, u16, Error = Simple<char>> {
-  just("#").ignore_then(int(10)).try_map(|x: String, span| {
-    x.parse::<u16>()
-      .map_err(|_| Simple::custom(span, "invalid decimal number"))
-  })
+/// `#1234` or `#-12`
+pub fn parse_decimal() -> impl Parser<char, i32, Error = Simple<char>> {
+  just("#")
+    .ignore_then(just('-').or_not())
+    .then(digits(10))
+    .try_map(|(sign, x): (_, String), span| {
+      let x = x
+        .parse::<i32>()
+        .map_err(|_| Simple::custom(span, "invalid decimal number"))?;
+
+      Ok(if sign.is_some() { -x } else { x })
+    })
+}
+
+/// Digits in `radix`, unlike [`chumsky::text::int`] allowing leading zeros like
+/// `x0020`
+fn digits(radix: u32) -> impl Parser<char, String, Error = Simple<char>> {
+  filter(move |c: &char| c.is_digit(radix))
+    .repeated()
+    .at_least(1)
+    .collect()
 }
 
 /// ` , `
@@ -42,13 +125,70 @@ mod test {
     assert_eq!(parse_number().parse("#1234"), Ok(1234));
   }
 
+  #[test]
+  fn test_parse_operand() {
+    assert_eq!(
+      parse_operand(word_range()).parse("x12"),
+      Ok(Operand::Number(0x12))
+    );
+    assert_eq!(
+      parse_operand(word_range()).parse("#12"),
+      Ok(Operand::Number(12))
+    );
+    assert_eq!(
+      parse_operand(word_range()).parse("loop"),
+      Ok(Operand::Label("loop".to_string()))
+    );
+    assert_eq!(
+      parse_operand(word_range()).parse("xyz"),
+      Ok(Operand::Label("xyz".to_string()))
+    );
+  }
+
+  #[test]
+  fn test_parse_operand_range() {
+    assert!(parse_operand(signed_range(5)).parse("x10").is_err());
+    assert!(parse_operand(word_range()).parse("x10000").is_err());
+  }
+
+  #[test]
+  fn test_parse_label_definition() {
+    assert_eq!(
+      parse_label_definition().parse("loop:"),
+      Ok("loop".to_string())
+    );
+    assert!(parse_label_definition().parse("loop").is_err());
+  }
+
+  #[test]
+  fn test_parse_signed() {
+    assert_eq!(parse_signed(5).parse("#15"), Ok(15));
+    assert_eq!(parse_signed(5).parse("#-16"), Ok(-16));
+    assert!(parse_signed(5).parse("#16").is_err());
+    assert!(parse_signed(5).parse("#-17").is_err());
+    assert_eq!(parse_signed(9).parse("x-100"), Ok(-256));
+    assert!(parse_signed(9).parse("x100").is_err());
+    assert!(parse_signed(11).parse("#1024").is_err());
+  }
+
+  #[test]
+  fn test_parse_unsigned() {
+    assert_eq!(parse_unsigned(16).parse("xffff"), Ok(0xFFFF));
+    assert!(parse_unsigned(16).parse("x10000").is_err());
+    assert!(parse_unsigned(16).parse("#-1").is_err());
+  }
+
   #[test]
   fn test_parse_hex() {
     assert_eq!(parse_hex().parse("x1234"), Ok(0x1234));
+    assert_eq!(parse_hex().parse("x-1f"), Ok(-0x1F));
+    assert_eq!(parse_hex().parse("x0020"), Ok(0x20));
   }
 
   #[test]
   fn test_parse_decimal() {
     assert_eq!(parse_decimal().parse("#1234"), Ok(1234));
+    assert_eq!(parse_decimal().parse("#-3"), Ok(-3));
+    assert_eq!(parse_decimal().parse("#007"), Ok(7));
   }
 }
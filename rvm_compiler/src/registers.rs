@@ -1,3 +1,5 @@
+use std::fmt::{self, Display, Formatter};
+
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum Register {
   R0 = 0x0,
@@ -16,4 +18,28 @@ impl Register {
   pub fn bytecode(&self) -> u16 {
     *self as u16
   }
+
+  /// The general purpose register named by the low 3 bits of `bits`.
+  pub fn decode(bits: u16) -> Register {
+    match bits & 0x7 {
+      0 => Register::R0,
+      1 => Register::R1,
+      2 => Register::R2,
+      3 => Register::R3,
+      4 => Register::R4,
+      5 => Register::R5,
+      6 => Register::R6,
+      _ => Register::R7,
+    }
+  }
+}
+
+impl Display for Register {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    match self {
+      Register::Pc => write!(f, "pc"),
+      Register::Cond => write!(f, "cond"),
+      _ => write!(f, "r{}", self.bytecode()),
+    }
+  }
 }
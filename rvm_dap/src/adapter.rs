@@ -0,0 +1,756 @@
+use std::collections::BTreeMap;
+use std::fs::{canonicalize, read_to_string};
+use std::path::Path;
+
+use ariadne::Source;
+use rvm::debugger::{disassemble, labels, parse_number, parse_register};
+use rvm::device::{Display, Output};
+use rvm::register::{cc_letter, Register};
+use rvm::step::StopReason;
+use rvm::Machine;
+use rvm_compiler::parsing::{assemble, lowercase};
+use rvm_compiler::serialize;
+use serde_json::{json, Value};
+
+/// Steps taken between checks for requests while the program runs.
+const SLICE: u64 = 10_000;
+
+/// The only thread, as the machine has one.
+const THREAD: u64 = 1;
+
+/// Variable references of the scopes.
+const REGISTERS: u64 = 1;
+const MEMORY: u64 = 2;
+
+/// How far a resumed program runs before it stops by itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+  Continue,
+  /// Until the next source line, entering subroutines
+  StepIn,
+  /// Until the next source line in the same subroutine or its caller
+  Next,
+  /// Until the current subroutine returns
+  StepOut,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+  /// Before `configurationDone`, or stopped in the debugger
+  Stopped,
+  Running(Mode),
+  Terminated,
+}
+
+/// A debug adapter for one program, turning requests into messages to send
+/// back to the editor.
+pub struct Adapter {
+  vm: Machine,
+  state: State,
+  path: String,
+  stop_on_entry: bool,
+  /// Source line of each address laid out by the assembler
+  lines: BTreeMap<u16, usize>,
+  /// First address of each line
+  addresses: BTreeMap<usize, u16>,
+  symbols: BTreeMap<String, u16>,
+  /// The first label of every address with one
+  labels: BTreeMap<u16, String>,
+  /// Where the program was loaded and how many words it has
+  program: (u16, u16),
+  /// Calls entered minus calls returned from since the program was resumed
+  depth: i32,
+  disconnected: bool,
+  seq: u64,
+  messages: Vec<Value>,
+  events: Vec<Value>,
+}
+
+impl Adapter {
+  pub fn new() -> Self {
+    Self {
+      vm: Machine::new(),
+      state: State::Stopped,
+      path: String::new(),
+      stop_on_entry: false,
+      lines: BTreeMap::new(),
+      addresses: BTreeMap::new(),
+      symbols: BTreeMap::new(),
+      labels: BTreeMap::new(),
+      program: (0, 0),
+      depth: 0,
+      disconnected: false,
+      seq: 0,
+      messages: Vec::new(),
+      events: Vec::new(),
+    }
+  }
+
+  pub fn running(&self) -> bool {
+    matches!(self.state, State::Running(_))
+  }
+
+  /// Whether the editor is done with the adapter.
+  pub fn disconnected(&self) -> bool {
+    self.disconnected
+  }
+
+  /// Messages to send, in order.
+  pub fn take_messages(&mut self) -> Vec<Value> {
+    std::mem::take(&mut self.messages)
+  }
+
+  /// Responds to `request`, followed by any events it caused.
+  pub fn handle(&mut self, request: &Value) {
+    let command = request["command"].as_str().unwrap_or_default();
+    let arguments = &request["arguments"];
+
+    let result = match command {
+      "initialize" => Ok(json!({
+        "supportsConfigurationDoneRequest": true,
+        "supportsSetVariable": true,
+        "supportsTerminateRequest": true,
+      })),
+      "launch" => self.launch(arguments),
+      "setBreakpoints" => Ok(self.set_breakpoints(arguments)),
+      "configurationDone" => {
+        if self.stop_on_entry {
+          self.stopped("entry", None);
+        } else {
+          self.resume(Mode::Continue);
+        }
+
+        Ok(Value::Null)
+      }
+      "threads" => Ok(json!({ "threads": [{ "id": THREAD, "name": "main" }] })),
+      "stackTrace" => Ok(self.stack_trace()),
+      "scopes" => Ok(json!({
+        "scopes": [
+          { "name": "Registers", "variablesReference": REGISTERS, "expensive": false },
+          {
+            "name": "Memory",
+            "variablesReference": MEMORY,
+            "indexedVariables": self.program.1,
+            "expensive": false,
+          },
+        ]
+      })),
+      "variables" => self.variables(arguments),
+      "setVariable" => self.set_variable(arguments),
+      "continue" => {
+        self.resume(Mode::Continue);
+        Ok(json!({ "allThreadsContinued": true }))
+      }
+      "next" => {
+        self.resume(Mode::Next);
+        Ok(Value::Null)
+      }
+      "stepIn" => {
+        self.resume(Mode::StepIn);
+        Ok(Value::Null)
+      }
+      "stepOut" => {
+        self.resume(Mode::StepOut);
+        Ok(Value::Null)
+      }
+      "pause" => {
+        if self.running() {
+          self.stopped("pause", None);
+        }
+
+        Ok(Value::Null)
+      }
+      "evaluate" => self.evaluate(arguments),
+      "terminate" => {
+        self.terminate();
+        Ok(Value::Null)
+      }
+      "disconnect" => {
+        self.state = State::Terminated;
+        self.disconnected = true;
+        Ok(Value::Null)
+      }
+      _ => Err(format!("unsupported request `{command}`")),
+    };
+
+    let mut response = json!({
+      "type": "response",
+      "request_seq": request["seq"],
+      "command": command,
+      "success": result.is_ok(),
+    });
+
+    match result {
+      Ok(Value::Null) => {}
+      Ok(body) => response["body"] = body,
+      Err(message) => response["message"] = json!(message),
+    }
+
+    self.send(response);
+
+    for event in std::mem::take(&mut self.events) {
+      self.send(event);
+    }
+  }
+
+  /// Runs the program for a while, if it is running.
+  pub fn run_slice(&mut self) {
+    let State::Running(mode) = self.state else {
+      return;
+    };
+
+    let Self {
+      vm, lines, depth, ..
+    } = self;
+
+    let reason = vm.run_with(Some(SLICE), |vm, step| {
+      let pc = vm.registers.get(Register::Pc);
+      *depth += step.call_depth(pc);
+
+      let at_line = vm.running() && lines.contains_key(&pc);
+
+      match mode {
+        Mode::Continue => false,
+        Mode::StepIn => at_line,
+        Mode::Next => at_line && *depth <= 0,
+        Mode::StepOut => at_line && *depth < 0,
+      }
+    });
+
+    self.flush_output();
+
+    self.stop(reason);
+
+    for event in std::mem::take(&mut self.events) {
+      self.send(event);
+    }
+  }
+
+  fn send(&mut self, mut message: Value) {
+    self.seq += 1;
+    message["seq"] = json!(self.seq);
+
+    self.messages.push(message);
+  }
+
+  fn event(&mut self, event: &str, body: Value) {
+    self
+      .events
+      .push(json!({ "type": "event", "event": event, "body": body }));
+  }
+
+  fn stopped(&mut self, reason: &str, text: Option<String>) {
+    self.state = State::Stopped;
+    self.event(
+      "stopped",
+      json!({
+        "reason": reason,
+        "threadId": THREAD,
+        "allThreadsStopped": true,
+        "text": text,
+      }),
+    );
+  }
+
+  fn terminate(&mut self) {
+    self.state = State::Terminated;
+    self.event("terminated", json!({}));
+  }
+
+  fn launch(&mut self, arguments: &Value) -> Result<Value, String> {
+    let Some(path) = arguments["program"].as_str() else {
+      return Err("no `program` to launch".to_string());
+    };
+
+    let contents = read_to_string(path).map_err(|err| format!("failed to read `{path}`: {err}"))?;
+    let contents = lowercase(&contents);
+
+    let assembly = match assemble(&contents) {
+      Ok(assembly) => assembly,
+      Err(errs) => {
+        let count = errs.len();
+
+        for err in errs {
+          let mut report = Vec::new();
+          err.write(Source::from(&contents), &mut report).unwrap();
+
+          self.output("stderr", &String::from_utf8_lossy(&report));
+        }
+
+        return Err(format!("failed to assemble `{path}`: {count} errors"));
+      }
+    };
+
+    let mut vm = Machine::new();
+    vm.memory.display = Display::new(Output::Buffer(Vec::new()));
+    vm.load_bytes(&serialize(&assembly.program), 0)
+      .map_err(|err| format!("failed to load `{path}`: {err}"))?;
+    vm.reset_pc(0);
+    vm.enter_user_mode();
+
+    self.vm = vm;
+    self.path = path.to_string();
+    self.stop_on_entry = arguments["stopOnEntry"].as_bool().unwrap_or(false);
+    self.program = (assembly.program.origin, assembly.program.words.len() as u16);
+
+    self.addresses.clear();
+
+    for (&address, &line) in &assembly.lines {
+      self.addresses.entry(line).or_insert(address);
+    }
+
+    self.lines = assembly.lines;
+
+    self.labels = labels(&assembly.symbols);
+    self.symbols = assembly.symbols;
+
+    self.event("initialized", json!({}));
+
+    Ok(Value::Null)
+  }
+
+  /// Replaces the breakpoints of the launched program, moving each to the next
+  /// line with code. Breakpoints in other sources can't be hit.
+  fn set_breakpoints(&mut self, arguments: &Value) -> Value {
+    let requested = arguments["breakpoints"]
+      .as_array()
+      .cloned()
+      .unwrap_or_default();
+
+    let source = arguments["source"]["path"].as_str().unwrap_or_default();
+
+    if !same_file(source, &self.path) {
+      let breakpoints: Vec<Value> = requested
+        .iter()
+        .map(|_| json!({ "verified": false, "message": "not the launched program" }))
+        .collect();
+
+      return json!({ "breakpoints": breakpoints });
+    }
+
+    self.vm.breakpoints.clear();
+
+    let breakpoints: Vec<Value> = requested
+      .iter()
+      .map(|breakpoint| {
+        let line = breakpoint["line"].as_u64().unwrap_or_default() as usize;
+
+        match self.addresses.range(line..).next() {
+          Some((&line, &address)) => {
+            self.vm.breakpoints.insert(address);
+            json!({ "verified": true, "line": line })
+          }
+          None => json!({ "verified": false, "message": "no code at or after this line" }),
+        }
+      })
+      .collect();
+
+    json!({ "breakpoints": breakpoints })
+  }
+
+  fn stack_trace(&mut self) -> Value {
+    let pc = self.vm.registers.get(Register::Pc);
+
+    let name = match self.labels.range(..=pc).next_back() {
+      Some((&start, label)) if start == pc => label.clone(),
+      Some((&start, label)) => format!("{label}+{}", pc - start),
+      None => format!("x{pc:04X}"),
+    };
+
+    let mut frame = json!({
+      "id": 0,
+      "name": name,
+      "line": 0,
+      "column": 0,
+      "instructionPointerReference": format!("x{pc:04X}"),
+    });
+
+    // the operating system has no source
+    if let Some(&line) = self.lines.get(&pc) {
+      let name = Path::new(&self.path)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned());
+
+      frame["line"] = json!(line);
+      frame["column"] = json!(1);
+      frame["source"] = json!({ "name": name, "path": self.path });
+    }
+
+    json!({ "stackFrames": [frame], "totalFrames": 1 })
+  }
+
+  fn variables(&mut self, arguments: &Value) -> Result<Value, String> {
+    let variables: Vec<Value> = match arguments["variablesReference"].as_u64() {
+      Some(REGISTERS) => {
+        let registers = &self.vm.registers;
+
+        let mut variables: Vec<Value> = (0..8)
+          .map(|r| variable(&format!("r{r}"), registers.get(Register::general(r))))
+          .collect();
+
+        variables.push(variable("pc", registers.get(Register::Pc)));
+        variables.push(variable("psr", registers.psr()));
+
+        let cc = cc_letter(registers.get(Register::Cond));
+        variables.push(json!({ "name": "cc", "value": cc.to_string(), "variablesReference": 0 }));
+
+        variables
+      }
+      Some(MEMORY) => {
+        let (origin, len) = self.program;
+        let start = arguments["start"].as_u64().unwrap_or(0).min(u64::from(len)) as u16;
+        let count = arguments["count"]
+          .as_u64()
+          .unwrap_or(u64::from(len))
+          .min(u64::from(len - start)) as u16;
+
+        (start..start + count)
+          .map(|i| {
+            let address = origin.wrapping_add(i);
+            let word = self.vm.memory.read(address);
+
+            json!({
+              "name": format!("x{address:04X}"),
+              "value": format!("x{word:04X}  {}", disassemble(address, word)),
+              "variablesReference": 0,
+            })
+          })
+          .collect()
+      }
+      _ => return Err("unknown variables reference".to_string()),
+    };
+
+    Ok(json!({ "variables": variables }))
+  }
+
+  fn set_variable(&mut self, arguments: &Value) -> Result<Value, String> {
+    let name = arguments["name"].as_str().unwrap_or_default();
+    let text = arguments["value"].as_str().unwrap_or_default();
+    let value = parse_number(text.trim()).ok_or_else(|| format!("bad value `{text}`"))?;
+
+    match arguments["variablesReference"].as_u64() {
+      Some(REGISTERS) => match parse_register(name) {
+        Some(Register::Psr) => self.vm.registers.set_psr(value),
+        Some(r) => *self.vm.registers.reg_r(r) = value,
+        None => return Err(format!("`{name}` can't be changed")),
+      },
+      Some(MEMORY) => {
+        let address = parse_number(name).ok_or_else(|| format!("bad address `{name}`"))?;
+        self.vm.memory.write(address, value);
+      }
+      _ => return Err("unknown variables reference".to_string()),
+    }
+
+    Ok(json!({ "value": format!("x{value:04X}") }))
+  }
+
+  /// Lines typed in the debug console are the program's input, anything else
+  /// is looked up as a register, label or address.
+  fn evaluate(&mut self, arguments: &Value) -> Result<Value, String> {
+    let expression = arguments["expression"].as_str().unwrap_or_default();
+
+    if arguments["context"] == "repl" {
+      self.vm.memory.keyboard.push(expression.as_bytes());
+      self.vm.memory.keyboard.push(b"\n");
+
+      return Ok(json!({ "result": "", "variablesReference": 0 }));
+    }
+
+    let value = match parse_register(expression) {
+      Some(Register::Psr) => self.vm.registers.psr(),
+      Some(r) => self.vm.registers.get(r),
+      None => {
+        let address = self
+          .symbols
+          .get(expression)
+          .copied()
+          .or_else(|| parse_number(expression))
+          .ok_or_else(|| format!("unknown expression `{expression}`"))?;
+
+        self.vm.memory.read(address)
+      }
+    };
+
+    Ok(json!({ "result": format!("x{value:04X} ({})", value as i16), "variablesReference": 0 }))
+  }
+
+  fn output(&mut self, category: &str, output: &str) {
+    self.event("output", json!({ "category": category, "output": output }));
+  }
+
+  fn flush_output(&mut self) {
+    let output = self.vm.memory.display.take_output();
+
+    if !output.is_empty() {
+      self.output("stdout", &String::from_utf8_lossy(&output));
+    }
+  }
+
+  fn resume(&mut self, mode: Mode) {
+    if self.state == State::Terminated {
+      return;
+    }
+
+    self.state = State::Running(mode);
+    self.depth = 0;
+  }
+
+  /// Tells the editor why the program stopped, unless it is still running.
+  fn stop(&mut self, reason: StopReason) {
+    match reason {
+      StopReason::BudgetExhausted => {}
+      StopReason::Breakpoint(_) => self.stopped("breakpoint", None),
+      StopReason::Condition => self.stopped("step", None),
+      StopReason::Error(err) => self.stopped("exception", Some(err.to_string())),
+      StopReason::Halted => {
+        self.event("exited", json!({ "exitCode": 0 }));
+        self.terminate();
+      }
+    }
+  }
+}
+
+/// Whether `a` and `b` name the same file, however they're written.
+fn same_file(a: &str, b: &str) -> bool {
+  a == b || matches!((canonicalize(a), canonicalize(b)), (Ok(a), Ok(b)) if a == b)
+}
+
+fn variable(name: &str, value: u16) -> Value {
+  json!({
+    "name": name,
+    "value": format!("x{value:04X} ({})", value as i16),
+    "variablesReference": 0,
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use std::env::temp_dir;
+  use std::fs::write;
+
+  use super::*;
+
+  const SOURCE: &str = "\
+.orig x3000
+start: and r0, r0, #0
+  trap tin
+  jsr double
+  trap toutc
+  halt
+double: add r0, r0, r0
+  jmp r7
+.end
+";
+
+  /// An adapter that has launched `SOURCE`, with its messages so far.
+  fn launch(name: &str, stop_on_entry: bool) -> (Adapter, Vec<Value>) {
+    let path = temp_dir().join(format!("rvm_dap_{name}.asm"));
+    write(&path, SOURCE).unwrap();
+
+    let mut adapter = Adapter::new();
+    request(&mut adapter, "initialize", json!({}));
+    request(
+      &mut adapter,
+      "launch",
+      json!({ "program": path, "stopOnEntry": stop_on_entry }),
+    );
+
+    let messages = adapter.take_messages();
+    (adapter, messages)
+  }
+
+  fn request(adapter: &mut Adapter, command: &str, arguments: Value) -> Value {
+    adapter
+      .handle(&json!({ "seq": 1, "type": "request", "command": command, "arguments": arguments }));
+
+    adapter
+      .messages
+      .iter()
+      .rev()
+      .find(|message| message["type"] == "response")
+      .cloned()
+      .unwrap()
+  }
+
+  /// Runs until the program stops, returning the events sent on the way.
+  fn run(adapter: &mut Adapter) -> Vec<Value> {
+    while adapter.running() {
+      adapter.run_slice();
+    }
+
+    adapter
+      .take_messages()
+      .into_iter()
+      .filter(|message| message["type"] == "event")
+      .collect()
+  }
+
+  fn line(adapter: &mut Adapter) -> Value {
+    let trace = request(adapter, "stackTrace", json!({ "threadId": THREAD }));
+    adapter.take_messages();
+
+    trace["body"]["stackFrames"][0]["line"].clone()
+  }
+
+  #[test]
+  fn test_launch() {
+    let (_, messages) = launch("launch", false);
+
+    assert_eq!(messages[1]["success"], true);
+    assert_eq!(messages[2]["event"], "initialized");
+
+    let mut adapter = Adapter::new();
+    let response = request(&mut adapter, "launch", json!({ "program": "missing.asm" }));
+    assert_eq!(response["success"], false);
+  }
+
+  #[test]
+  fn test_breakpoints_and_io() {
+    let (mut adapter, _) = launch("breakpoints", false);
+    let source = json!({ "path": adapter.path });
+
+    let response = request(
+      &mut adapter,
+      "setBreakpoints",
+      json!({ "source": source, "breakpoints": [{ "line": 7 }, { "line": 8 }, { "line": 20 }] }),
+    );
+    assert_eq!(
+      response["body"]["breakpoints"],
+      json!([
+        { "verified": true, "line": 7 },
+        { "verified": true, "line": 8 },
+        { "verified": false, "message": "no code at or after this line" },
+      ])
+    );
+    request(
+      &mut adapter,
+      "setBreakpoints",
+      json!({ "source": source, "breakpoints": [{ "line": 7 }] }),
+    );
+
+    // other files keep none, and leave the program's alone
+    let response = request(
+      &mut adapter,
+      "setBreakpoints",
+      json!({ "source": { "path": "other.asm" }, "breakpoints": [{ "line": 3 }] }),
+    );
+    assert_eq!(
+      response["body"]["breakpoints"],
+      json!([{ "verified": false, "message": "not the launched program" }])
+    );
+    assert_eq!(adapter.vm.breakpoints.len(), 1);
+
+    // the program waits in the OS for input typed in the debug console
+    request(&mut adapter, "configurationDone", json!({}));
+    adapter.run_slice();
+    assert!(adapter.running());
+
+    request(
+      &mut adapter,
+      "evaluate",
+      json!({ "expression": "a", "context": "repl" }),
+    );
+    let events = run(&mut adapter);
+
+    let output: String = events
+      .iter()
+      .filter(|event| event["event"] == "output")
+      .filter_map(|event| event["body"]["output"].as_str())
+      .collect();
+    assert_eq!(output, "input: a");
+
+    let stopped = events
+      .iter()
+      .find(|event| event["event"] == "stopped")
+      .unwrap();
+    assert_eq!(stopped["body"]["reason"], "breakpoint");
+    assert_eq!(line(&mut adapter), 7);
+
+    let response = request(
+      &mut adapter,
+      "evaluate",
+      json!({ "expression": "r0", "context": "watch" }),
+    );
+    assert_eq!(response["body"]["result"], "x0061 (97)");
+
+    request(&mut adapter, "continue", json!({}));
+    let events = run(&mut adapter);
+    let names: Vec<&str> = events
+      .iter()
+      .filter_map(|event| event["event"].as_str())
+      .collect();
+    assert_eq!(names, ["output", "exited", "terminated"]);
+    assert_eq!(adapter.state, State::Terminated);
+  }
+
+  #[test]
+  fn test_stepping() {
+    let (mut adapter, _) = launch("stepping", true);
+    adapter.vm.memory.keyboard.push(b"\x01");
+
+    request(&mut adapter, "configurationDone", json!({}));
+    assert_eq!(adapter.take_messages()[1]["body"]["reason"], "entry");
+    assert_eq!(line(&mut adapter), 2);
+
+    // stepping into a trap runs through the OS, which has no source
+    request(&mut adapter, "stepIn", json!({}));
+    run(&mut adapter);
+    assert_eq!(line(&mut adapter), 3);
+
+    request(&mut adapter, "stepIn", json!({}));
+    run(&mut adapter);
+    assert_eq!(line(&mut adapter), 4);
+
+    request(&mut adapter, "stepIn", json!({}));
+    run(&mut adapter);
+    assert_eq!(line(&mut adapter), 7);
+
+    request(&mut adapter, "stepOut", json!({}));
+    run(&mut adapter);
+    assert_eq!(line(&mut adapter), 5);
+    assert_eq!(adapter.vm.registers.get(Register::R0), 2);
+
+    request(&mut adapter, "next", json!({}));
+    run(&mut adapter);
+    assert_eq!(line(&mut adapter), 6);
+  }
+
+  #[test]
+  fn test_variables() {
+    let (mut adapter, _) = launch("variables", true);
+
+    let response = request(
+      &mut adapter,
+      "setVariable",
+      json!({ "variablesReference": REGISTERS, "name": "r1", "value": "#-2" }),
+    );
+    assert_eq!(response["body"]["value"], "xFFFE");
+
+    let response = request(
+      &mut adapter,
+      "variables",
+      json!({ "variablesReference": REGISTERS }),
+    );
+    let registers = &response["body"]["variables"];
+    assert_eq!(registers[1]["value"], "xFFFE (-2)");
+    assert_eq!(registers[8]["value"], "x3000 (12288)");
+    assert_eq!(registers[9]["value"], "x8002 (-32766)");
+    assert_eq!(registers[10]["value"], "z");
+
+    request(
+      &mut adapter,
+      "setVariable",
+      json!({ "variablesReference": MEMORY, "name": "x3001", "value": "xF025" }),
+    );
+
+    let response = request(
+      &mut adapter,
+      "variables",
+      json!({ "variablesReference": MEMORY, "start": 1, "count": 1 }),
+    );
+    assert_eq!(
+      response["body"]["variables"],
+      json!([{ "name": "x3001", "value": "xF025  trap thalt", "variablesReference": 0 }])
+    );
+  }
+}
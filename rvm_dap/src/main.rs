@@ -0,0 +1,48 @@
+use std::io::{stdin, stdout, BufReader};
+use std::sync::mpsc::{channel, TryRecvError};
+use std::thread;
+
+use adapter::Adapter;
+use protocol::{read_message, write_message};
+
+mod adapter;
+mod protocol;
+
+/// A Debug Adapter Protocol server over stdin and stdout, for debugging
+/// assembly from an editor.
+fn main() {
+  let (sender, receiver) = channel();
+
+  // requests keep coming in while the program runs
+  thread::spawn(move || {
+    let mut reader = BufReader::new(stdin().lock());
+
+    while let Ok(Some(message)) = read_message(&mut reader) {
+      if sender.send(message).is_err() {
+        break;
+      }
+    }
+  });
+
+  let mut adapter = Adapter::new();
+  let mut stdout = stdout().lock();
+
+  while !adapter.disconnected() {
+    if adapter.running() {
+      match receiver.try_recv() {
+        Ok(request) => adapter.handle(&request),
+        Err(TryRecvError::Empty) => adapter.run_slice(),
+        Err(TryRecvError::Disconnected) => break,
+      }
+    } else {
+      match receiver.recv() {
+        Ok(request) => adapter.handle(&request),
+        Err(_) => break,
+      }
+    }
+
+    for message in adapter.take_messages() {
+      write_message(&mut stdout, &message).unwrap();
+    }
+  }
+}
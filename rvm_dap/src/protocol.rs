@@ -0,0 +1,84 @@
+use std::io::{self, BufRead, ErrorKind, Write};
+
+use serde_json::Value;
+
+/// Reads the next message, framed by a `Content-Length` header. Returns `None`
+/// once the input has ended.
+pub fn read_message(reader: &mut impl BufRead) -> io::Result<Option<Value>> {
+  let mut length = None;
+
+  loop {
+    let mut header = String::new();
+
+    if reader.read_line(&mut header)? == 0 {
+      return Ok(None);
+    }
+
+    let header = header.trim_end();
+
+    if header.is_empty() {
+      break;
+    }
+
+    if let Some(value) = header.strip_prefix("Content-Length:") {
+      length = value.trim().parse::<usize>().ok();
+    }
+  }
+
+  let Some(length) = length else {
+    return Err(io::Error::new(
+      ErrorKind::InvalidData,
+      "message without a content length",
+    ));
+  };
+
+  let mut body = vec![0; length];
+  reader.read_exact(&mut body)?;
+
+  Ok(Some(serde_json::from_slice(&body)?))
+}
+
+pub fn write_message(writer: &mut impl Write, message: &Value) -> io::Result<()> {
+  let body = message.to_string();
+
+  write!(writer, "Content-Length: {}\r\n\r\n{body}", body.len())?;
+  writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+  use std::io::Cursor;
+
+  use serde_json::json;
+
+  use super::*;
+
+  #[test]
+  fn test_round_trip() {
+    let messages = [
+      json!({ "seq": 1, "type": "request", "command": "initialize" }),
+      json!({ "seq": 2, "type": "request", "command": "launch", "arguments": { "program": "é.asm" } }),
+    ];
+
+    let mut buffer = Vec::new();
+
+    for message in &messages {
+      write_message(&mut buffer, message).unwrap();
+    }
+
+    let mut reader = Cursor::new(buffer);
+
+    for message in &messages {
+      assert_eq!(read_message(&mut reader).unwrap().as_ref(), Some(message));
+    }
+
+    assert!(read_message(&mut reader).unwrap().is_none());
+  }
+
+  #[test]
+  fn test_missing_length() {
+    let mut reader = Cursor::new(b"Content-Type: json\r\n\r\n{}".to_vec());
+
+    assert!(read_message(&mut reader).is_err());
+  }
+}